@@ -0,0 +1,86 @@
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum_server::accept::Accept;
+use hyper::{service::Service, Request, Response};
+
+/// Response extension asking for the connection to be closed without sending
+/// the response, like nginx's 444.
+#[derive(Clone, Copy)]
+pub struct DropConnection;
+
+#[derive(Debug)]
+struct Dropped;
+
+impl fmt::Display for Dropped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection dropped without a response")
+    }
+}
+
+impl Error for Dropped {}
+
+/// Turns responses marked with `DropConnection` into a service error, on
+/// which hyper closes the connection (http/1) or resets the stream (http/2)
+/// without writing anything.
+#[derive(Clone)]
+pub struct AbortService<S> {
+    inner: S,
+}
+
+impl<S, B, R> Service<Request<R>> for AbortService<S>
+where
+    S: Service<Request<R>, Response = Response<B>>,
+    S::Error: Into<Box<dyn Error + Send + Sync>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<R>) -> Self::Future {
+        let res = self.inner.call(req);
+        Box::pin(async move {
+            let res = res.await.map_err(Into::into)?;
+            if res.extensions().get::<DropConnection>().is_some() {
+                return Err(Dropped.into());
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// Wraps the service of every connection accepted by `inner` in an
+/// `AbortService`.
+#[derive(Clone)]
+pub struct AbortAcceptor<A> {
+    inner: A,
+}
+
+impl<A> AbortAcceptor<A> {
+    pub fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+impl<I, S, A> Accept<I, S> for AbortAcceptor<A>
+where
+    A: Accept<I, AbortService<S>>,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = A::Future;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        self.inner.accept(stream, AbortService { inner: service })
+    }
+}
@@ -0,0 +1,118 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use axum::{routing::get, Json, Router};
+
+use crate::{
+    config::BalanceStrategy,
+    log::log_error,
+    metrics,
+    reload::{snapshot, SharedConfig},
+};
+
+/// The balancing strategy each host is using right now.
+fn balance_strategies(shared_config: &SharedConfig) -> HashMap<String, BalanceStrategy> {
+    let config = snapshot(shared_config);
+    config
+        .hosts
+        .iter()
+        .map(|(domain, host)| (domain.clone(), config.effective_settings(host).balance))
+        .collect()
+}
+
+fn admin_app(shared_config: SharedConfig) -> Router {
+    Router::new()
+        .route("/metrics", get(|| async { metrics::render() }))
+        .route(
+            "/balance",
+            get(move || async move { Json(balance_strategies(&shared_config)) }),
+        )
+}
+
+/// Serves operational endpoints on a separate, loopback-only listener so
+/// they are never reachable through a proxied host.
+pub async fn admin_server(port: u16, shared_config: SharedConfig) {
+    let app = admin_app(shared_config);
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let server = match axum::Server::try_bind(&addr) {
+        Ok(server) => server,
+        Err(e) => {
+            log_error(&format!("failed to bind admin endpoint {}: {}", addr, e));
+            return;
+        }
+    };
+    println!("admin endpoint listening on {}", addr);
+    if let Err(e) = server.serve(app.into_make_service()).await {
+        log_error(&format!("admin endpoint stopped: {}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        balance::next_target,
+        config::ConfigSource,
+        reload::{new_shared_config, try_reload},
+    };
+
+    fn write_config(name: &str, balance: &str) -> ConfigSource {
+        let path = std::env::temp_dir().join(format!(
+            "reverse-proxy-admin-{}-{}.yml",
+            name,
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            format!(
+                "hosts:\n  lb.test:\n    protocol: http\n    balance: {}\n    upstreams:\n      - url: http://10.0.0.1:80\n        weight: 3\n      - http://10.0.0.2:80\n",
+                balance
+            ),
+        )
+        .unwrap();
+        ConfigSource::File(path.to_string_lossy().into_owned())
+    }
+
+    /// The last octet of the next `count` picks for `lb.test` under the
+    /// live config.
+    fn picks(shared: &SharedConfig, count: usize) -> Vec<String> {
+        let config = snapshot(shared);
+        let host = &config.hosts["lb.test"];
+        let strategy = config.effective_settings(host).balance;
+        (0..count)
+            .map(|_| {
+                let target = next_target("lb.test", strategy, &host.targets(), |_| true).unwrap();
+                target.ip.rsplit('.').next().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    async fn live_balance(addr: SocketAddr) -> HashMap<String, String> {
+        let url = format!("http://{}/balance", addr).parse().unwrap();
+        let res = hyper::Client::new().get(url).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reloaded_balance_reaches_picks_and_the_endpoint() {
+        let source = write_config("round-robin", "round_robin");
+        let shared = new_shared_config(source.load().unwrap());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(admin_app(shared.clone()).into_make_service());
+        tokio::spawn(server);
+
+        assert_eq!(live_balance(addr).await["lb.test"], "round_robin");
+        assert_eq!(picks(&shared, 4), ["1", "2", "1", "2"]);
+
+        try_reload(&write_config("weighted", "weighted"), &shared)
+            .await
+            .unwrap();
+        assert_eq!(live_balance(addr).await["lb.test"], "weighted");
+        assert_eq!(picks(&shared, 8), ["1", "1", "1", "2", "1", "1", "1", "2"]);
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{LazyLock, Mutex},
+    time::Instant,
+};
+
+use hyper::StatusCode;
+
+use crate::{
+    config::{Config, ErrorAlert},
+    log::{log_error, log_info},
+    metrics::{decr, incr, METRICS},
+};
+
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+struct ErrorWindow {
+    /// Requests and 5xx answers per second that had traffic, oldest first.
+    seconds: VecDeque<(u64, u32, u32)>,
+    alerting: bool,
+}
+
+impl ErrorWindow {
+    fn expire(&mut self, now: u64, window: u64) {
+        while let Some(&(second, _, _)) = self.seconds.front() {
+            if second + window > now {
+                break;
+            }
+            self.seconds.pop_front();
+        }
+    }
+
+    fn totals(&self) -> (u32, u32) {
+        self.seconds
+            .iter()
+            .fold((0, 0), |(requests, errors), &(_, r, e)| {
+                (requests + r, errors + e)
+            })
+    }
+
+    fn clear_alert(&mut self, host: &str, why: &str) {
+        if self.alerting {
+            self.alerting = false;
+            decr(&METRICS.hosts_over_error_threshold);
+            log_info(&format!("host {} error rate back to normal: {}", host, why));
+        }
+    }
+}
+
+/// Sliding 5xx windows of hosts with `error_alert`, keyed like `Config::hosts`.
+static WINDOWS: LazyLock<Mutex<HashMap<String, ErrorWindow>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Counts an answer to a request for `host`. Crossing the threshold is
+/// logged once and counted in the metrics, as is going back under it.
+pub fn record_status(host: &str, alert: &ErrorAlert, status: StatusCode) {
+    let now = START.elapsed().as_secs();
+    let window_secs = alert.window_secs.unwrap_or(60);
+    let error = u32::from(status.is_server_error());
+    let mut windows = WINDOWS.lock().unwrap();
+    let window = windows
+        .entry(host.to_string())
+        .or_insert_with(|| ErrorWindow {
+            seconds: VecDeque::new(),
+            alerting: false,
+        });
+    window.expire(now, window_secs);
+    match window.seconds.back_mut() {
+        Some((second, requests, errors)) if *second == now => {
+            *requests += 1;
+            *errors += error;
+        }
+        _ => window.seconds.push_back((now, 1, error)),
+    }
+    let (requests, errors) = window.totals();
+    // Too few answers to tell either way, the alert stays as it is.
+    if requests < alert.min_requests.unwrap_or(20) {
+        return;
+    }
+    let ratio = errors as f64 / requests as f64;
+    let over = ratio > alert.threshold;
+    if over && !window.alerting {
+        window.alerting = true;
+        incr(&METRICS.error_rate_alerts);
+        incr(&METRICS.hosts_over_error_threshold);
+        log_error(&format!(
+            "host {} error rate alert: {} of {} answers in the last {}s were 5xx ({:.1}%), above {:.1}%",
+            host,
+            errors,
+            requests,
+            window_secs,
+            ratio * 100.0,
+            alert.threshold * 100.0
+        ));
+    } else if !over && window.alerting {
+        window.clear_alert(
+            host,
+            &format!("{} of {} answers were 5xx", errors, requests),
+        );
+    }
+}
+
+/// Drops the windows of hosts that no longer have `error_alert` and of
+/// those without traffic for a whole window, which also ends their alert.
+pub fn prune_error_windows(config: &Config) {
+    let now = START.elapsed().as_secs();
+    let mut windows = WINDOWS.lock().unwrap();
+    windows.retain(|host, window| {
+        let alert = match config.hosts.get(host).and_then(|h| h.error_alert.as_ref()) {
+            Some(alert) => alert,
+            None => {
+                window.clear_alert(host, "error_alert removed");
+                return false;
+            }
+        };
+        window.expire(now, alert.window_secs.unwrap_or(60));
+        if window.seconds.is_empty() {
+            window.clear_alert(host, "no requests in the window");
+            return false;
+        }
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alerting(host: &str) -> Option<bool> {
+        WINDOWS.lock().unwrap().get(host).map(|w| w.alerting)
+    }
+
+    fn record(host: &str, alert: &ErrorAlert, status: u16, times: usize) {
+        for _ in 0..times {
+            record_status(host, alert, StatusCode::from_u16(status).unwrap());
+        }
+    }
+
+    #[test]
+    fn alerts_over_the_threshold_and_recovers() {
+        let host = "alert.example.com";
+        let alert: ErrorAlert = serde_yaml::from_str("threshold: 0.5\nmin_requests: 4").unwrap();
+        record(host, &alert, 500, 3);
+        assert_eq!(alerting(host), Some(false), "too few requests to tell");
+        record(host, &alert, 502, 1);
+        assert_eq!(alerting(host), Some(true));
+        record(host, &alert, 404, 3);
+        assert_eq!(alerting(host), Some(true), "4 of 7 still over half");
+        record(host, &alert, 200, 1);
+        assert_eq!(alerting(host), Some(false));
+    }
+
+    #[test]
+    fn pruning_drops_hosts_without_error_alert() {
+        let host = "pruned-alert.example.com";
+        let alert: ErrorAlert = serde_yaml::from_str("threshold: 0.0\nmin_requests: 1").unwrap();
+        record(host, &alert, 503, 1);
+        assert_eq!(alerting(host), Some(true));
+        // Keeps the host of the test above, which may run alongside.
+        let config = "hosts:\n  alert.example.com:\n    ip: 127.0.0.1\n    port: 9000\n    \
+                      protocol: http\n    error_alert:\n      threshold: 0.5\n";
+        prune_error_windows(&serde_yaml::from_str(config).unwrap());
+        assert_eq!(alerting(host), None);
+    }
+}
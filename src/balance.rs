@@ -0,0 +1,310 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use rand::Rng;
+
+use crate::config::{BalanceStrategy, Target};
+
+/// Rotation position per configured host.
+static NEXT: LazyLock<Mutex<HashMap<String, usize>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Requests in flight per upstream authority, for `least_conn`.
+static IN_FLIGHT: LazyLock<Mutex<HashMap<String, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Counts a request against its upstream until dropped.
+pub struct InFlight(String);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.0);
+            }
+        }
+    }
+}
+
+pub fn track_in_flight(authority: &str) -> InFlight {
+    *IN_FLIGHT
+        .lock()
+        .unwrap()
+        .entry(authority.to_string())
+        .or_insert(0) += 1;
+    InFlight(authority.to_string())
+}
+
+/// Requests in flight per upstream authority, sorted by authority.
+pub fn in_flight_counts() -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = IN_FLIGHT
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(authority, count)| (authority.clone(), *count))
+        .collect();
+    counts.sort();
+    counts
+}
+
+fn advance(domain: &str) -> usize {
+    let mut next = NEXT.lock().unwrap();
+    let position = next.entry(domain.to_string()).or_insert(0);
+    let current = *position;
+    *position = current.wrapping_add(1);
+    current
+}
+
+/// Index of the target owning `slot` when each target takes `weight` slots.
+fn weighted_index(targets: &[Target], slot: usize) -> usize {
+    let mut end = 0;
+    for (index, target) in targets.iter().enumerate() {
+        end += target.weight as usize;
+        if slot < end {
+            return index;
+        }
+    }
+    0
+}
+
+/// A random target among those passing `usable`, by weight.
+fn weighted_random(targets: &[Target], usable: impl Fn(&Target) -> bool) -> Option<Target> {
+    let candidates: Vec<Target> = targets.iter().filter(|t| usable(t)).cloned().collect();
+    let total: usize = candidates.iter().map(|t| t.weight as usize).sum();
+    if total == 0 {
+        return None;
+    }
+    let slot = rand::thread_rng().gen_range(0..total);
+    Some(candidates[weighted_index(&candidates, slot)].clone())
+}
+
+/// The next target of `domain` under `strategy` that passes `usable`, `None`
+/// when none does. An unusable pick falls through to the next target in
+/// list order, except under `weighted_random` which only draws from the
+/// usable ones.
+pub fn next_target(
+    domain: &str,
+    strategy: BalanceStrategy,
+    targets: &[Target],
+    usable: impl Fn(&Target) -> bool,
+) -> Option<Target> {
+    if targets.is_empty() {
+        return None;
+    }
+    if strategy == BalanceStrategy::WeightedRandom {
+        return weighted_random(targets, usable);
+    }
+    let position = advance(domain);
+    let start = match strategy {
+        BalanceStrategy::RoundRobin
+        | BalanceStrategy::LeastConn
+        | BalanceStrategy::WeightedRandom => position % targets.len(),
+        BalanceStrategy::Weighted => {
+            let total: usize = targets.iter().map(|t| t.weight as usize).sum();
+            weighted_index(targets, position % total.max(1))
+        }
+    };
+    let mut candidates = (0..targets.len())
+        .map(|offset| &targets[(start + offset) % targets.len()])
+        .filter(|target| usable(target));
+    let target = match strategy {
+        // Ties go to the first in rotation order, so equally loaded
+        // upstreams still take turns.
+        BalanceStrategy::LeastConn => {
+            let in_flight = IN_FLIGHT.lock().unwrap();
+            candidates.min_by_key(|target| in_flight.get(&target.authority()).copied().unwrap_or(0))
+        }
+        _ => candidates.next(),
+    };
+    target.cloned()
+}
+
+/// FNV-1a, stable across builds and processes so every proxy instance pins
+/// a value to the same upstream.
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for byte in *part {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        // Keeps ("ab", "c") apart from ("a", "bc").
+        hash ^= 0xff;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    // The splitmix64 finalizer spreads similar inputs over the whole range.
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// The target for `key` among those passing `usable`, by weighted
+/// rendezvous hashing: each target scores the key and the highest score
+/// wins. The same key keeps its target while the set is stable, and a
+/// target leaving or joining only moves the keys it wins or won.
+pub fn hashed_target(
+    key: &[u8],
+    targets: &[Target],
+    usable: impl Fn(&Target) -> bool,
+) -> Option<Target> {
+    targets
+        .iter()
+        .filter(|target| target.weight > 0 && usable(target))
+        .map(|target| {
+            let hash = fnv1a(&[key, target.authority().as_bytes()]);
+            // Uniform in (0, 1), never 0 so the logarithm stays finite.
+            let uniform = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            (f64::from(target.weight) / -uniform.ln(), target)
+        })
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, target)| target.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(weights: &[u32]) -> Vec<Target> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| Target {
+                weight: *weight,
+                ..Target::parse(&format!("http://10.0.0.{}:80", i + 1)).unwrap()
+            })
+            .collect()
+    }
+
+    /// The last octet of each pick, `domain` keeps the rotation of each test
+    /// apart.
+    fn picks(
+        domain: &str,
+        strategy: BalanceStrategy,
+        targets: &[Target],
+        usable: impl Fn(&Target) -> bool + Copy,
+        count: usize,
+    ) -> Vec<String> {
+        (0..count)
+            .map(|_| {
+                let target = next_target(domain, strategy, targets, usable).unwrap();
+                target.ip.rsplit('.').next().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_skips_unusable_targets() {
+        let targets = targets(&[1, 1, 1]);
+        let all = picks(
+            "rr.test",
+            BalanceStrategy::RoundRobin,
+            &targets,
+            |_| true,
+            6,
+        );
+        assert_eq!(all, ["1", "2", "3", "1", "2", "3"]);
+        let some = picks(
+            "rr2.test",
+            BalanceStrategy::RoundRobin,
+            &targets,
+            |t| t.ip != "10.0.0.2",
+            4,
+        );
+        assert_eq!(some, ["1", "3", "3", "1"]);
+        assert!(
+            next_target("rr3.test", BalanceStrategy::RoundRobin, &targets, |_| false).is_none()
+        );
+    }
+
+    #[test]
+    fn weighted_gives_each_target_its_turns() {
+        let targets = targets(&[2, 1]);
+        let all = picks(
+            "weighted.test",
+            BalanceStrategy::Weighted,
+            &targets,
+            |_| true,
+            6,
+        );
+        assert_eq!(all, ["1", "1", "2", "1", "1", "2"]);
+    }
+
+    #[test]
+    fn least_conn_prefers_idle_targets() {
+        let targets = targets(&[1, 1]);
+        let busy = track_in_flight("10.0.0.1:80");
+        let all = picks(
+            "least.test",
+            BalanceStrategy::LeastConn,
+            &targets,
+            |_| true,
+            3,
+        );
+        assert_eq!(all, ["2", "2", "2"]);
+        drop(busy);
+        let all = picks(
+            "least.test",
+            BalanceStrategy::LeastConn,
+            &targets,
+            |_| true,
+            2,
+        );
+        assert_eq!(all.len(), 2);
+        assert_ne!(all[0], all[1]);
+    }
+
+    #[test]
+    fn weighted_random_follows_the_weights() {
+        let targets = targets(&[3, 1, 0]);
+        let all = picks(
+            "random.test",
+            BalanceStrategy::WeightedRandom,
+            &targets,
+            |_| true,
+            4000,
+        );
+        let first = all.iter().filter(|pick| *pick == "1").count();
+        assert!(!all.iter().any(|pick| pick == "3"));
+        assert!((2700..3300).contains(&first), "{}", first);
+        let usable = picks(
+            "random.test",
+            BalanceStrategy::WeightedRandom,
+            &targets,
+            |t| t.ip != "10.0.0.1",
+            20,
+        );
+        assert!(usable.iter().all(|pick| pick == "2"));
+    }
+
+    #[test]
+    fn hashed_keys_stay_on_their_target() {
+        let targets = targets(&[1, 1, 1, 1]);
+        let keys: Vec<String> = (0..200).map(|i| format!("user-{}", i)).collect();
+        let pick = |key: &String, usable: &dyn Fn(&Target) -> bool| {
+            hashed_target(key.as_bytes(), &targets, usable).unwrap().ip
+        };
+        let before: Vec<String> = keys.iter().map(|key| pick(key, &|_| true)).collect();
+        let again: Vec<String> = keys.iter().map(|key| pick(key, &|_| true)).collect();
+        assert_eq!(before, again);
+        for target in &targets {
+            assert!(before.contains(&target.ip), "{} got no keys", target.ip);
+        }
+
+        // Taking one target out only moves the keys it had.
+        let without: Vec<String> = keys
+            .iter()
+            .map(|key| pick(key, &|t| t.ip != "10.0.0.1"))
+            .collect();
+        for (before, after) in before.iter().zip(&without) {
+            if before != "10.0.0.1" {
+                assert_eq!(before, after);
+            } else {
+                assert_ne!(after, "10.0.0.1");
+            }
+        }
+        assert!(hashed_target(b"key", &targets, |_| false).is_none());
+    }
+}
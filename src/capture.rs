@@ -0,0 +1,257 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    sync::{
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        LazyLock,
+    },
+    thread,
+};
+
+use futures_util::StreamExt;
+use hyper::{
+    body::Bytes,
+    header::{HeaderName, SET_COOKIE},
+    Body, HeaderMap,
+};
+
+use crate::{
+    config::Capture,
+    debug::is_redacted,
+    log::{log_error, log_info},
+};
+
+/// Captures waiting for the writer, past this they are dropped rather than
+/// holding up requests.
+const WRITE_QUEUE: usize = 1024;
+
+/// Appends `(path, line)` captures on a thread of its own, so a slow disk
+/// never blocks a runtime worker.
+static WRITER: LazyLock<SyncSender<(String, String)>> = LazyLock::new(|| {
+    let (tx, rx) = sync_channel::<(String, String)>(WRITE_QUEUE);
+    thread::spawn(move || {
+        for (path, line) in rx {
+            let written = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut file| file.write_all(format!("{}\n", line).as_bytes()));
+            if let Err(e) = written {
+                log_error(&format!("failed to write capture to {}: {}", path, e));
+            }
+        }
+    });
+    tx
+});
+
+/// Header secrets shorter than this are left alone: redaction replaces
+/// every occurrence in the body, and a value like `en` or `1` would mangle
+/// unrelated text.
+const MIN_SECRET_LEN: usize = 8;
+
+/// The secrets in the value of a masked header: the whole value, each
+/// cookie's value and the credentials after an auth scheme, when at least
+/// `MIN_SECRET_LEN` long. Only the first part of a `Set-Cookie` is the
+/// cookie, the rest are its attributes.
+fn header_secrets(name: &HeaderName, value: &str) -> Vec<String> {
+    let parts: Vec<&str> = if *name == SET_COOKIE {
+        value.split(';').take(1).collect()
+    } else {
+        value.split(';').collect()
+    };
+    let mut secrets = vec![value.trim().to_string()];
+    for part in parts {
+        let part = part.trim();
+        if let Some((_, secret)) = part.split_once('=').or_else(|| part.split_once(' ')) {
+            secrets.push(secret.trim().to_string());
+        }
+    }
+    secrets.retain(|secret| secret.len() >= MIN_SECRET_LEN);
+    secrets
+}
+
+/// `capture` with the values of the masked headers in `headers` added to
+/// `redact`, so a body echoing a token or cookie does not leak it.
+pub fn redacting_headers(capture: &Capture, headers: &HeaderMap, extra: &[String]) -> Capture {
+    let mut capture = capture.clone();
+    let secrets = headers
+        .iter()
+        .filter(|(name, _)| is_redacted(name, extra))
+        .filter_map(|(name, value)| Some(header_secrets(name, value.to_str().ok()?)))
+        .flatten();
+    capture.redact.get_or_insert_with(Vec::new).extend(secrets);
+    capture
+}
+
+/// Collects the first `max_bytes` of a body as it streams past and writes
+/// them out once the body is finished or dropped.
+struct Tee {
+    label: String,
+    config: Capture,
+    captured: Vec<u8>,
+    total: u64,
+}
+
+impl Tee {
+    fn push(&mut self, chunk: &Bytes) {
+        let max = self.config.max_bytes.unwrap_or(4096);
+        let room = max.saturating_sub(self.captured.len());
+        self.captured
+            .extend_from_slice(&chunk[..room.min(chunk.len())]);
+        self.total += chunk.len() as u64;
+    }
+}
+
+/// `text` with every occurrence of each of `secrets` masked.
+fn redacted(mut text: String, secrets: &[String]) -> String {
+    for secret in secrets {
+        if !secret.is_empty() {
+            text = text.replace(secret.as_str(), "***");
+        }
+    }
+    text
+}
+
+impl Drop for Tee {
+    fn drop(&mut self) {
+        let text = redacted(
+            String::from_utf8_lossy(&self.captured).into_owned(),
+            self.config.redact.as_deref().unwrap_or_default(),
+        );
+        let line = format!(
+            "[capture] {} ({} bytes, first {} shown): {}",
+            self.label,
+            self.total,
+            self.captured.len(),
+            text
+        );
+        match &self.config.file {
+            Some(path) => match WRITER.try_send((path.clone(), line)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => log_error(&format!(
+                    "capture queue full, dropped a capture for {}",
+                    path
+                )),
+                Err(TrySendError::Disconnected(_)) => log_error(&format!(
+                    "capture writer stopped, dropped a capture for {}",
+                    path
+                )),
+            },
+            None => log_info(&line),
+        }
+    }
+}
+
+/// Passes `body` through unchanged while capturing its first bytes under
+/// `label`. With `read_only` the capture is logged even if it has a file.
+pub fn tee_body(body: Body, label: String, config: &Capture, read_only: bool) -> Body {
+    let mut config = config.clone();
+    if read_only {
+        config.file = None;
+    }
+    let mut tee = Tee {
+        label,
+        config,
+        captured: Vec::new(),
+        total: 0,
+    };
+    Body::wrap_stream(body.map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            tee.push(chunk);
+        }
+        chunk
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn capture(yaml: &str) -> Capture {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn captures_the_start_of_a_body_to_its_file() {
+        let path = std::env::temp_dir().join(format!("capture-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = capture(&format!(
+            "file: {}\nmax_bytes: 5\nredact: [sec]",
+            path.display()
+        ));
+        let body = tee_body(
+            Body::from("a secret body"),
+            "req".to_string(),
+            &config,
+            false,
+        );
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(body, "a secret body");
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let written = loop {
+            let written = std::fs::read_to_string(&path).unwrap_or_default();
+            if !written.is_empty() || Instant::now() > deadline {
+                break written;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(written, "[capture] req (13 bytes, first 5 shown): a ***\n");
+    }
+
+    #[test]
+    fn masks_header_secrets() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer tok123456".parse().unwrap());
+        headers.insert("cookie", "a=first-secret; b=second-secret".parse().unwrap());
+        let redact = redacting_headers(&capture("{}"), &headers, &[])
+            .redact
+            .unwrap();
+        for secret in ["tok123456", "first-secret", "second-secret"] {
+            assert!(redact.iter().any(|r| r == secret), "{:?}", redact);
+        }
+    }
+
+    #[test]
+    fn short_cookie_values_leave_the_body_alone() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "cookie",
+            "theme=dark; a=1; lang=en; sid=0123456789abcdef"
+                .parse()
+                .unwrap(),
+        );
+        let redact = redacting_headers(&capture("{}"), &headers, &[])
+            .redact
+            .unwrap();
+        let body =
+            "{\"lang\": \"en\", \"count\": 1, \"theme\": \"dark\", \"sid\": \"0123456789abcdef\"}";
+        assert_eq!(
+            redacted(body.to_string(), &redact),
+            "{\"lang\": \"en\", \"count\": 1, \"theme\": \"dark\", \"sid\": \"***\"}"
+        );
+    }
+
+    #[tokio::test]
+    async fn read_only_logs_captures_instead_of_writing() {
+        let path = std::env::temp_dir().join(format!("capture-ro-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config: crate::config::Config = serde_yaml::from_str(&format!(
+            "read_only: true\nhosts:\n  ro.test:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n    capture:\n      file: {}\n",
+            path.display()
+        ))
+        .unwrap();
+        let conflicts = config.read_only_conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].contains("ro.test"), "{:?}", conflicts);
+
+        let capture = config.hosts["ro.test"].capture.as_ref().unwrap();
+        let body = tee_body(Body::from("body"), "req".to_string(), capture, true);
+        hyper::body::to_bytes(body).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!path.exists());
+    }
+}
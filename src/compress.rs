@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{LazyLock, Mutex},
+};
+
+use async_compression::{tokio::bufread::GzipEncoder, Level};
+use futures_util::TryStreamExt;
+use hyper::{
+    header::{
+        HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+        CONTENT_TYPE, VARY,
+    },
+    Body, Request, Response, StatusCode,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::{config::Config, log::log_info};
+
+/// Whether an upstream takes gzip request bodies, as learned from its
+/// responses, keyed by `ip:port`. Without an entry `request_compression`
+/// is trusted.
+static REQUEST_GZIP: LazyLock<Mutex<HashMap<String, bool>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn accepts_gzip(accept_encoding: Option<&HeaderValue>) -> bool {
+    let accept_encoding = match accept_encoding.and_then(|v| v.to_str().ok()) {
+        Some(v) => v,
+        None => return false,
+    };
+    accept_encoding.split(',').any(|item| {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        let rejected = parts.any(|p| {
+            let p = p.trim();
+            p.starts_with("q=")
+                && p[2..]
+                    .trim()
+                    .parse::<f32>()
+                    .map(|q| q == 0.0)
+                    .unwrap_or(false)
+        });
+        (coding.eq_ignore_ascii_case("gzip") || coding == "*") && !rejected
+    })
+}
+
+fn gzip_body(body: Body, level: u32) -> Body {
+    let reader = StreamReader::new(body.map_err(io::Error::other));
+    let encoder = GzipEncoder::with_quality(reader, Level::Precise(level));
+    Body::wrap_stream(ReaderStream::new(encoder))
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    content_type.starts_with("text/")
+        || content_type.contains("json")
+        || content_type.contains("javascript")
+        || content_type.contains("xml")
+}
+
+/// Gzips the upstream response body on the fly when the client accepts it and
+/// the content is worth compressing. Partial responses are left alone, their
+/// `Content-Range` describes the uncompressed bytes. `level` is passed straight to the
+/// encoder, 1 is fastest and 9 is smallest.
+pub fn maybe_compress(
+    res: Response<Body>,
+    accept_encoding: Option<&HeaderValue>,
+    level: u32,
+    min_length: u64,
+) -> Response<Body> {
+    if !accepts_gzip(accept_encoding)
+        || res.status() == StatusCode::NO_CONTENT
+        || res.status() == StatusCode::NOT_MODIFIED
+        || res.status() == StatusCode::PARTIAL_CONTENT
+        || res.headers().contains_key(CONTENT_RANGE)
+        || res.headers().contains_key(CONTENT_ENCODING)
+    {
+        return res;
+    }
+    let compressible = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(is_compressible)
+        .unwrap_or(false);
+    let too_small = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|len| len < min_length)
+        .unwrap_or(false);
+    if !compressible || too_small {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    Response::from_parts(parts, gzip_body(body, level))
+}
+
+/// Gzips a request body on its way to an upstream that is configured as
+/// accepting `Content-Encoding: gzip`. Only compressible bodies with a known
+/// length of at least `min_length` are touched.
+pub fn compress_request(req: Request<Body>, level: u32, min_length: u64) -> Request<Body> {
+    let headers = req.headers();
+    let compressible = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(is_compressible)
+        .unwrap_or(false);
+    let large_enough = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|len| len >= min_length)
+        .unwrap_or(false);
+    if !compressible || !large_enough || headers.contains_key(CONTENT_ENCODING) {
+        return req;
+    }
+
+    let (mut parts, body) = req.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    Request::from_parts(parts, gzip_body(body, level))
+}
+
+/// Whether to gzip request bodies to `upstream`, false once it has shown it
+/// does not take them.
+pub fn upstream_takes_gzip(upstream: &str) -> bool {
+    REQUEST_GZIP
+        .lock()
+        .unwrap()
+        .get(upstream)
+        .copied()
+        .unwrap_or(true)
+}
+
+/// Learns from a response whether `upstream` takes gzip request bodies: a
+/// 415 to a body we gzipped says no, an `Accept-Encoding` response header
+/// (RFC 7694) says either way. Anything else leaves what is known.
+pub fn learn_request_gzip(upstream: &str, sent_gzip: bool, res: &Response<Body>) {
+    let takes_gzip = match res.headers().get(ACCEPT_ENCODING) {
+        Some(accepted) => accepts_gzip(Some(accepted)),
+        None if sent_gzip && res.status() == StatusCode::UNSUPPORTED_MEDIA_TYPE => false,
+        None => return,
+    };
+    let previous = REQUEST_GZIP
+        .lock()
+        .unwrap()
+        .insert(upstream.to_string(), takes_gzip);
+    if !takes_gzip && previous != Some(false) {
+        log_info(&format!(
+            "upstream {} does not take gzip request bodies, sending them uncompressed",
+            upstream
+        ));
+    }
+}
+
+/// Forgets what was learned about upstreams no longer used by a host with
+/// `request_compression`, so re-adding one starts from the config again.
+pub fn prune_request_gzip(config: &Config) {
+    let mut known = REQUEST_GZIP.lock().unwrap();
+    if known.is_empty() {
+        return;
+    }
+    let upstreams: Vec<String> = config
+        .hosts
+        .values()
+        .filter(|host| host.request_compression.is_some())
+        .flat_map(|host| host.targets())
+        .map(|target| target.authority())
+        .collect();
+    known.retain(|upstream, _| upstreams.contains(upstream));
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::tokio::bufread::GzipDecoder;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn response(status: u16, headers: &[(&str, &str)], body: &'static str) -> Response<Body> {
+        let mut res = Response::builder().status(status);
+        for (name, value) in headers {
+            res = res.header(*name, *value);
+        }
+        res.body(Body::from(body)).unwrap()
+    }
+
+    fn gzip() -> HeaderValue {
+        HeaderValue::from_static("gzip, deflate")
+    }
+
+    #[test]
+    fn gzip_refused_with_q_zero() {
+        assert!(accepts_gzip(Some(&gzip())));
+        assert!(accepts_gzip(Some(&HeaderValue::from_static("*"))));
+        assert!(!accepts_gzip(Some(&HeaderValue::from_static("gzip;q=0"))));
+        assert!(!accepts_gzip(Some(&HeaderValue::from_static(
+            "br, gzip; q=0.0"
+        ))));
+        assert!(!accepts_gzip(None));
+    }
+
+    #[tokio::test]
+    async fn compresses_json_responses() {
+        let body = "{\"hello\":\"world\"}";
+        let res = response(
+            200,
+            &[
+                ("content-type", "application/json"),
+                ("content-length", "17"),
+            ],
+            body,
+        );
+        let res = maybe_compress(res, Some(&gzip()), 6, 0);
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(res.headers()[VARY], "accept-encoding");
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+
+        let compressed = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let mut decoded = String::new();
+        GzipDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .await
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn leaves_other_responses_alone() {
+        let json = ("content-type", "application/json");
+        let skipped = [
+            response(206, &[json, ("content-range", "bytes 0-1/10")], "{}"),
+            response(200, &[json, ("content-encoding", "br")], "{}"),
+            response(200, &[json, ("content-length", "2")], "{}"),
+            response(200, &[("content-type", "image/png")], "png"),
+            response(204, &[json], ""),
+        ];
+        for res in skipped {
+            let encoding = res.headers().get(CONTENT_ENCODING).cloned();
+            let res = maybe_compress(res, Some(&gzip()), 6, 1024);
+            assert_eq!(res.headers().get(CONTENT_ENCODING), encoding.as_ref());
+        }
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::post("http://example.com/");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Body::from("{\"hello\":\"world\"}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn compresses_large_enough_request_bodies() {
+        let req = request(&[
+            ("content-type", "application/json"),
+            ("content-length", "17"),
+        ]);
+        let req = compress_request(req, 6, 16);
+        assert_eq!(req.headers()[CONTENT_ENCODING], "gzip");
+        assert!(!req.headers().contains_key(CONTENT_LENGTH));
+
+        let compressed = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        let mut decoded = String::new();
+        GzipDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .await
+            .unwrap();
+        assert_eq!(decoded, "{\"hello\":\"world\"}");
+    }
+
+    #[test]
+    fn leaves_other_request_bodies_alone() {
+        let json = ("content-type", "application/json");
+        let length = ("content-length", "17");
+        let skipped = [
+            (request(&[json, length]), 1024),
+            (request(&[json]), 0),
+            (request(&[("content-type", "image/png"), length]), 0),
+            (request(&[json, length, ("content-encoding", "br")]), 0),
+        ];
+        for (req, min_length) in skipped {
+            let encoding = req.headers().get(CONTENT_ENCODING).cloned();
+            let req = compress_request(req, 6, min_length);
+            assert_eq!(req.headers().get(CONTENT_ENCODING), encoding.as_ref());
+        }
+    }
+
+    #[test]
+    fn learns_whether_upstreams_take_gzip() {
+        let status = |code| response(code, &[], "");
+        let upstream = "10.0.76.1:80";
+        assert!(upstream_takes_gzip(upstream));
+        learn_request_gzip(upstream, false, &status(415));
+        assert!(upstream_takes_gzip(upstream));
+        learn_request_gzip(upstream, true, &status(415));
+        assert!(!upstream_takes_gzip(upstream));
+        learn_request_gzip(upstream, true, &status(200));
+        assert!(!upstream_takes_gzip(upstream));
+        let accepted = response(415, &[("accept-encoding", "gzip")], "");
+        learn_request_gzip(upstream, true, &accepted);
+        assert!(upstream_takes_gzip(upstream));
+        let identity = response(200, &[("accept-encoding", "identity")], "");
+        learn_request_gzip(upstream, false, &identity);
+        assert!(!upstream_takes_gzip(upstream));
+    }
+}
@@ -1,53 +1,1604 @@
+use hyper::header::{HeaderName, HeaderValue};
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Read},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use validator::{Validate, ValidationError};
 
+use crate::{
+    ipmatch::IpRange,
+    tls::{load_certified_key, PemSource},
+};
+
 type Port = u16;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Validate)]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, Validate)]
 pub struct Config {
     pub port: Option<Port>,
     pub ssl: Option<bool>,
     pub ssl_port: Option<Port>,
+    /// Exit when the https listener cannot bind, e.g. on a privileged
+    /// `ssl_port` without the rights, instead of serving http only.
+    pub ssl_bind_fatal: Option<bool>,
+    /// Handshakes not done after this long are aborted, defaults to 10.
+    #[validate(range(min = 1))]
+    pub tls_handshake_timeout_secs: Option<u64>,
+    /// TLS handshakes run at once, more wait their turn so a burst of new
+    /// connections cannot take the cpu from established ones.
+    #[validate(range(min = 1))]
+    pub max_concurrent_handshakes: Option<usize>,
     pub ssl_key_file: Option<String>,
     pub ssl_cert_file: Option<String>,
+    /// Inline PEM or `env:VAR`, takes precedence over `ssl_key_file`.
+    pub ssl_key: Option<String>,
+    /// Inline PEM or `env:VAR`, takes precedence over `ssl_cert_file`.
+    pub ssl_cert: Option<String>,
+    /// DER encoded OCSP response stapled with the default cert.
+    pub ssl_ocsp_file: Option<String>,
+    /// Log every host cert failure on each tls rebuild instead of only new
+    /// ones plus a summary.
+    pub log_every_cert_failure: Option<bool>,
+    /// How often the most frequent server names matching no host are
+    /// logged, 0 turns the report off.
+    pub sni_report_interval_secs: Option<u64>,
+    /// Local development only: serve a generated self-signed cert when the
+    /// default cert files do not exist.
+    pub dev_mode: Option<bool>,
+    /// For read-only root filesystems: nothing is ever written to disk,
+    /// features that would write a file log instead.
+    pub read_only: Option<bool>,
+    /// Written with the pid once every listener is bound.
+    pub ready_file: Option<String>,
+    /// Sends `READY=1` to systemd when it set `NOTIFY_SOCKET`, on by default.
+    pub sd_notify: Option<bool>,
+    pub reload_interval_secs: Option<u64>,
+    pub admin_port: Option<Port>,
+    #[validate]
+    pub compression: Option<Compression>,
+    pub absolute_form: Option<AbsoluteFormPolicy>,
+    pub retry_stale_connections: Option<bool>,
+    pub bind_retries: Option<u32>,
+    pub bind_retry_backoff_ms: Option<u64>,
+    pub reuse_address: Option<bool>,
+    #[validate(range(min = 1))]
+    pub listen_backlog: Option<u32>,
+    pub blocked_methods: Option<Vec<String>>,
+    #[validate]
+    pub rate_limit: Option<RateLimit>,
+    pub health: Option<HealthCheck>,
+    pub prune_interval_secs: Option<u64>,
+    /// Closes a connection whose client accepted no response bytes for this
+    /// long. Unset never times out.
+    pub client_write_timeout_secs: Option<u64>,
+    /// How long in-flight requests may take to finish after SIGINT or
+    /// SIGTERM, defaults to 30.
+    pub shutdown_timeout_secs: Option<u64>,
+    /// More http listeners next to `port`, e.g. to route `example.com:8080`
+    /// and `example.com:9090` to different hosts.
+    pub extra_ports: Option<Vec<Port>>,
+    /// Local address upstream connections are made from, e.g. on a
+    /// multi-homed machine. The system chooses when unset.
+    pub upstream_source_address: Option<IpAddr>,
+    /// Idle upstream connections send TCP keepalive probes after this long,
+    /// so a peer that vanished is noticed. Off when unset.
+    #[validate(range(min = 1))]
+    pub upstream_tcp_keepalive_secs: Option<u64>,
+    /// Pooled upstream connections idle this long are closed instead of
+    /// reused, keep it below any NAT or firewall idle timeout on the way.
+    /// Defaults to 90.
+    pub upstream_idle_timeout_secs: Option<u64>,
+    /// Client connections one ip may hold open at once over all listeners,
+    /// more are closed right after accept.
+    #[validate(range(min = 1))]
+    pub max_connections_per_ip: Option<u32>,
+    /// Websocket tunnels open at once over all hosts, more upgrades get 503.
+    pub max_ws_connections: Option<u32>,
+    /// Closes websockets of hosts a reload removed or moved to other
+    /// upstreams, so clients reconnect against the new config.
+    #[validate]
+    pub ws_drain: Option<WsDrain>,
+    /// Upstream connects taking at least this long are logged with the time
+    /// each phase took.
+    pub slow_connect_log_ms: Option<u64>,
+    /// Keep header names as the client and the HTTP/1.1 upstream wrote
+    /// them instead of lowercasing, for backends matching them by case.
+    pub preserve_header_case: Option<bool>,
+    /// Largest http/1 response head taken from an upstream, bigger ones
+    /// answer 502. Below 8192 hyper cannot work.
+    #[validate(range(min = 8192))]
+    pub max_response_header_bytes: Option<usize>,
+    /// Longest path and query a client may send, longer ones answer 414.
+    #[validate(range(min = 1))]
+    pub max_uri_length: Option<usize>,
+    /// Share of requests logged with all their headers, 0.0 to 1.0.
+    #[validate(range(min = 0.0, max = 1.0))]
+    pub debug_sample_rate: Option<f64>,
+    /// More headers whose values the debug log and body captures mask, on
+    /// top of `Authorization`, `Proxy-Authorization`, `Cookie` and
+    /// `Set-Cookie`.
+    pub redact_headers: Option<Vec<String>>,
+    /// Header carrying each request's id to the upstream and back to the
+    /// client, e.g. `X-Request-Id`. No ids are added when unset.
+    pub request_id_header: Option<String>,
+    /// Client headers only a front proxy may set, removed from requests of
+    /// any other peer before anything reads them.
+    pub trusted_incoming_headers: Option<TrustedIncomingHeaders>,
+    pub alt_svc: Option<AltSvc>,
+    pub runtime: Option<RuntimeFlavor>,
+    /// Multi-thread runtime only, defaults to the number of cpu cores.
+    #[validate(range(min = 1))]
+    pub worker_threads: Option<usize>,
+    #[validate]
+    pub unknown_host_response: Option<UnknownHostResponse>,
+    /// Key of the host serving requests no other host matches, which then
+    /// never reach `unknown_host_response`.
+    pub default_host: Option<String>,
+    /// Keys are a host name, matching any port, or `name:port`. A name of
+    /// the form `*.example.com` matches every subdomain of `example.com`.
     pub hosts: HashMap<String, Host>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Validate)]
 pub struct Host {
+    pub ip: Option<String>,
+    pub port: Option<Port>,
+    #[validate(custom(function = "host_protocol_check"))]
+    pub protocol: String,
+    /// Requests rotate through these.
+    pub upstreams: Option<Vec<UpstreamEntry>>,
+    /// How `upstreams` combines with `ip`/`port` when both are set.
+    pub upstream_precedence: Option<UpstreamPrecedence>,
+    /// Read per request, a hot reload switches it live.
+    pub balance: Option<BalanceStrategy>,
+    /// Pins requests with the same header value to the same upstream,
+    /// taking precedence over `balance` when the header is present.
+    pub hash_routing: Option<HashRouting>,
+    /// More names for this entry, written like the keys. They route here,
+    /// get its cert and share all of its per-host state.
+    pub aliases: Option<Vec<String>>,
+    pub ssl_cert_file: Option<String>,
+    pub ssl_key_file: Option<String>,
+    pub ssl_cert: Option<String>,
+    pub ssl_key: Option<String>,
+    pub ssl_ocsp_file: Option<String>,
+    #[validate(range(min = 1, max = 9))]
+    pub compression_level: Option<u32>,
+    pub range_requests: Option<bool>,
+    pub via: Option<Via>,
+    pub user_agent: Option<UserAgent>,
+    pub json_redaction: Option<JsonRedaction>,
+    /// Websocket tunnels open at once for this host, counted together with
+    /// its aliases.
+    pub max_ws_connections: Option<u32>,
+    pub default_charset: Option<String>,
+    pub behind_https: Option<bool>,
+    /// Never served over plain http: GET and HEAD are redirected to https,
+    /// anything else is refused with 403.
+    pub require_https: Option<bool>,
+    pub accept_routes: Option<Vec<AcceptRoute>>,
+    /// Tried in order on the request path before `accept_routes`, the first
+    /// match picks the upstream.
+    pub regex_routes: Option<Vec<RegexRoute>>,
+    /// `Host` sent upstream unless the route or upstream sets its own.
+    pub upstream_host_header: Option<String>,
+    /// Forward the client's `Host`, otherwise the upstream's address is sent.
+    /// Defaults to true.
+    pub preserve_host: Option<bool>,
+    /// Per attempt, a timed out attempt answers 504.
+    pub timeout_ms: Option<u64>,
+    /// For the whole request including retries and backoffs, answers 504
+    /// once passed.
+    pub deadline_ms: Option<u64>,
+    /// From the request arriving to the last body byte. Passed before the
+    /// upstream's head arrives it answers 504, later the body is cut off
+    /// and the connection closed.
+    pub response_deadline_ms: Option<u64>,
+    /// Header telling the upstream the time left of the budget set by the
+    /// options above, so it can give up when the proxy does. Whole
+    /// milliseconds, or gRPC's encoding for `grpc-timeout`.
+    pub deadline_header: Option<String>,
+    /// Overrides the global `request_id_header` for this host.
+    pub request_id_header: Option<String>,
+    pub retries: Option<u32>,
+    pub retry_backoff_ms: Option<u64>,
+    pub retry_backoff_max_ms: Option<u64>,
+    /// What a response that is not valid HTTP leads to.
+    pub invalid_response: Option<InvalidResponsePolicy>,
+    /// Defaults to `never_sent`, or `idempotent` when `invalid_response`
+    /// is `retry`.
+    pub retry_mode: Option<RetryMode>,
+    pub upstream_version: Option<UpstreamVersion>,
+    /// Give this host a connection pool of its own instead of the shared one.
+    pub isolated_pool: Option<bool>,
+    pub state_scope: Option<StateScope>,
+    pub h2c_upgrade: Option<H2cUpgrade>,
+    pub body_inspection: Option<BodyInspection>,
+    /// Requested from every upstream once the host is live, at startup or
+    /// when a reload adds it.
+    pub warmup: Option<Warmup>,
+    /// Open a new http/1 connection for every request and ask the upstream
+    /// to close it, for upstreams that break on reused connections.
+    pub disable_keepalive: Option<bool>,
+    /// Pass a client's `Connection: close` on so the upstream connection is
+    /// closed as well, by default only the client connection is.
+    pub forward_connection_close: Option<bool>,
+    /// Concurrent GET/HEAD requests for the same path share one upstream
+    /// request, unless they carry credentials or negotiate differently.
+    pub single_flight: Option<bool>,
+    /// Forward the request target's path and query untouched, only the
+    /// authority is swapped for the upstream's.
+    pub raw_path_passthrough: Option<bool>,
+    /// Share of new traces that are sampled, 0.0 to 1.0. Unset forwards
+    /// `traceparent` untouched.
+    #[validate(range(min = 0.0, max = 1.0))]
+    pub trace_sample_rate: Option<f64>,
+    /// Debugging only, captured bodies may contain personal data.
+    pub capture: Option<Capture>,
+    #[validate]
+    pub no_upstream_response: Option<CustomResponse>,
+    /// `Retry-After` seconds on the 502, 503 and 504 the proxy answers
+    /// itself for this host.
+    pub error_retry_after_secs: Option<u64>,
+    /// Present puts the host in maintenance, every request gets this
+    /// response.
+    #[validate]
+    pub maintenance: Option<CustomResponse>,
+    /// Addresses or CIDR blocks proxied as usual during maintenance.
+    pub maintenance_allow_ips: Option<Vec<String>>,
+    /// Logs and counts in the metrics when the share of 5xx answers gets
+    /// too high.
+    #[validate]
+    pub error_alert: Option<ErrorAlert>,
+    /// Answer of a host with `protocol: echo`, 200 with an empty body when
+    /// unset.
+    #[validate]
+    pub echo_response: Option<CustomResponse>,
+    /// Only set this for upstreams that accept gzip request bodies.
+    #[validate]
+    pub request_compression: Option<Compression>,
+    #[validate]
+    pub upstream_header_limit: Option<HeaderLimit>,
+    /// Order of the steps changing the request before it is sent, unlisted
+    /// steps follow in their default order.
+    pub request_pipeline: Option<Vec<RequestStep>>,
+    pub security_headers: Option<SecurityHeaders>,
+    /// One token bucket for the whole host, whichever clients the requests
+    /// come from.
+    #[validate]
+    pub aggregate_rate_limit: Option<HostRateLimit>,
+}
+
+/// Sends requests preferring `media_type` in their `Accept` header to
+/// `upstream`, e.g. `application/vnd.v2+json` to a v2 backend.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AcceptRoute {
+    pub media_type: String,
+    /// `protocol://ip:port`
+    pub upstream: String,
+    /// `Host` sent for this route, for upstreams serving several vhosts.
+    pub host_header: Option<String>,
+}
+
+impl AcceptRoute {
+    pub fn target(&self) -> Result<Target, String> {
+        Ok(Target {
+            host_header: self.host_header.clone(),
+            ..Target::parse(&self.upstream)?
+        })
+    }
+}
+
+/// Sends requests whose path matches `pattern` to `upstream`, e.g.
+/// `^/users/\d+/posts` to a posts service.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct RegexRoute {
+    pub pattern: Pattern,
+    /// `protocol://ip:port`
+    pub upstream: String,
+    /// `Host` sent for this route, for upstreams serving several vhosts.
+    pub host_header: Option<String>,
+}
+
+impl RegexRoute {
+    pub fn target(&self) -> Result<Target, String> {
+        Ok(Target {
+            host_header: self.host_header.clone(),
+            ..Target::parse(&self.upstream)?
+        })
+    }
+}
+
+const MAX_PATTERN_LENGTH: usize = 1024;
+const MAX_COMPILED_PATTERN_SIZE: usize = 1 << 20;
+
+/// A regex compiled once, when the config is read. Matching takes linear
+/// time whatever the pattern, so no input can make it backtrack; the size
+/// limits keep a single pattern from taking much memory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, String> {
+        if pattern.len() > MAX_PATTERN_LENGTH {
+            return Err(format!(
+                "regex pattern is longer than {} bytes",
+                MAX_PATTERN_LENGTH
+            ));
+        }
+        RegexBuilder::new(&pattern)
+            .size_limit(MAX_COMPILED_PATTERN_SIZE)
+            .dfa_size_limit(MAX_COMPILED_PATTERN_SIZE)
+            .build()
+            .map(Pattern)
+            .map_err(|e| format!("invalid regex pattern `{}`: {}", pattern, e))
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> Self {
+        pattern.0.as_str().to_string()
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// An `upstreams` entry, `protocol://ip:port` or a map with its own `Host`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum UpstreamEntry {
+    Url(String),
+    Detailed {
+        url: String,
+        host_header: Option<String>,
+        /// Share of requests under the `weighted` and `weighted_random`
+        /// strategies, defaults to 1.
+        weight: Option<u32>,
+    },
+}
+
+impl UpstreamEntry {
+    pub fn target(&self) -> Result<Target, String> {
+        match self {
+            UpstreamEntry::Url(url) => Target::parse(url),
+            UpstreamEntry::Detailed {
+                url,
+                host_header,
+                weight,
+            } => {
+                if *weight == Some(0) {
+                    return Err(format!("upstream `{}`: weight must be at least 1", url));
+                }
+                Ok(Target {
+                    host_header: host_header.clone(),
+                    weight: weight.unwrap_or(1),
+                    ..Target::parse(url)?
+                })
+            }
+        }
+    }
+}
+
+/// Where a request is forwarded to.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Target {
+    pub protocol: String,
     pub ip: String,
     pub port: Port,
-    #[validate(custom(function = "protocol_check"))]
-    pub protocol: String,
+    /// Overrides the host's `Host` handling for this target.
+    pub host_header: Option<String>,
+    pub weight: u32,
+}
+
+impl Target {
+    /// Parses `protocol://ip:port`.
+    pub fn parse(url: &str) -> Result<Target, String> {
+        let (protocol, address) = url
+            .split_once("://")
+            .ok_or_else(|| format!("upstream `{}` must look like protocol://ip:port", url))?;
+        protocol_check(protocol).map_err(|_| {
+            format!(
+                "upstream `{}`: protocol only support 'http' or 'https'",
+                url
+            )
+        })?;
+        let (ip, port) = address
+            .rsplit_once(':')
+            .ok_or_else(|| format!("upstream `{}` is missing the port", url))?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("upstream `{}` has an invalid port", url))?;
+        Ok(Target {
+            protocol: protocol.to_string(),
+            ip: ip.to_string(),
+            port,
+            host_header: None,
+            weight: 1,
+        })
+    }
+
+    pub fn authority(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+/// How a host spreads requests over its targets.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    #[default]
+    RoundRobin,
+    /// The target with the fewest requests in flight.
+    LeastConn,
+    /// Round robin where each target takes `weight` turns in a row.
+    Weighted,
+    /// A random target, each chosen with a chance proportional to `weight`.
+    WeightedRandom,
+}
+
+/// Which targets a host uses when it sets both `ip`/`port` and `upstreams`.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamPrecedence {
+    /// Only `upstreams`, `ip`/`port` is ignored.
+    #[default]
+    Upstreams,
+    /// `ip`/`port` is added after `upstreams`.
+    Append,
+    /// Setting both is a config error.
+    Strict,
+}
+
+impl Host {
+    /// The `Host` to send to `target`: the target's own, then
+    /// `upstream_host_header`, then the client's unless `preserve_host` is
+    /// off. `None` leaves the client's header alone.
+    pub fn host_header_for(&self, target: &Target) -> Option<String> {
+        target
+            .host_header
+            .clone()
+            .or_else(|| self.upstream_host_header.clone())
+            .or_else(|| (!self.preserve_host.unwrap_or(true)).then(|| target.authority()))
+    }
+
+    /// The key the host's per-host state is kept under, see `StateScope`.
+    /// Without upstreams there is nothing to share and the domain is used.
+    pub fn state_key(&self, domain: &str) -> String {
+        let mut upstreams: Vec<String> = self.targets().iter().map(Target::authority).collect();
+        if self.state_scope.unwrap_or_default() == StateScope::Host || upstreams.is_empty() {
+            return domain.to_string();
+        }
+        upstreams.sort();
+        upstreams.dedup();
+        format!("upstreams {}", upstreams.join(","))
+    }
+
+    /// The entry's key followed by its aliases.
+    pub fn names<'a>(&'a self, domain: &'a str) -> impl Iterator<Item = &'a str> {
+        std::iter::once(domain).chain(self.aliases.iter().flatten().map(String::as_str))
+    }
+
+    /// The host's own cert and key, inline values winning over files.
+    pub fn ssl_sources(&self) -> Option<(PemSource, PemSource)> {
+        let source = |value: &Option<String>, file: &Option<String>| match (value, file) {
+            (Some(value), _) => Some(PemSource::Value(value.clone())),
+            (None, Some(file)) => Some(PemSource::File(file.clone())),
+            (None, None) => None,
+        };
+        Some((
+            source(&self.ssl_cert, &self.ssl_cert_file)?,
+            source(&self.ssl_key, &self.ssl_key_file)?,
+        ))
+    }
+
+    fn single_target(&self) -> Option<Target> {
+        match (&self.ip, self.port) {
+            (Some(ip), Some(port)) => Some(Target {
+                protocol: self.protocol.clone(),
+                ip: ip.clone(),
+                port,
+                host_header: None,
+                weight: 1,
+            }),
+            _ => None,
+        }
+    }
+
+    /// `protocol: echo` hosts answer every request themselves with
+    /// `echo_response`, there is no upstream to connect to.
+    pub fn is_echo(&self) -> bool {
+        self.protocol == "echo"
+    }
+
+    /// Every target requests can go to, in rotation order.
+    pub fn targets(&self) -> Vec<Target> {
+        if self.is_echo() {
+            return Vec::new();
+        }
+        let mut targets: Vec<Target> = self
+            .upstreams
+            .iter()
+            .flatten()
+            .filter_map(|upstream| upstream.target().ok())
+            .collect();
+        let single = self.single_target();
+        if targets.is_empty()
+            || self.upstream_precedence.unwrap_or_default() == UpstreamPrecedence::Append
+        {
+            targets.extend(single);
+        }
+        targets
+    }
+
+    /// `targets` followed by the targets of the regex and accept routes,
+    /// every upstream a request to this host may go to.
+    pub fn all_targets(&self) -> Vec<Target> {
+        let routes = self
+            .regex_routes
+            .iter()
+            .flatten()
+            .map(RegexRoute::target)
+            .chain(self.accept_routes.iter().flatten().map(AcceptRoute::target))
+            .filter_map(Result::ok);
+        let mut targets = self.targets();
+        if !self.is_echo() {
+            targets.extend(routes);
+        }
+        targets
+    }
+
+    /// Whether some request could still go to `authority`, through the
+    /// targets or a route.
+    pub fn routes_to(&self, authority: &str) -> bool {
+        self.all_targets()
+            .iter()
+            .any(|target| target.authority() == authority)
+    }
+
+    fn check_targets(&self) -> Result<(), String> {
+        if self.is_echo() {
+            return Ok(());
+        }
+        if self.ip.is_some() != self.port.is_some() {
+            return Err("`ip` and `port` must be set together".to_string());
+        }
+        for upstream in self.upstreams.iter().flatten() {
+            upstream.target()?;
+        }
+        let has_single = self.ip.is_some();
+        let has_upstreams = self.upstreams.as_ref().map(|u| !u.is_empty());
+        match (has_single, has_upstreams) {
+            (_, Some(false)) => Err("`upstreams` is empty".to_string()),
+            (false, None) => Err("either `ip`/`port` or `upstreams` is required".to_string()),
+            (true, Some(true)) if self.upstream_precedence == Some(UpstreamPrecedence::Strict) => {
+                Err(
+                    "both `ip`/`port` and `upstreams` are set but `upstream_precedence` is strict"
+                        .to_string(),
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Adds this proxy to the `Via` header of forwarded requests.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Via {
+    pub pseudonym: Option<String>,
+    /// Also append to the response sent back to the client.
+    pub response: Option<bool>,
+}
+
+impl Via {
+    pub fn pseudonym(&self) -> &str {
+        self.pseudonym.as_deref().unwrap_or("reverse-proxy")
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct TrustedIncomingHeaders {
+    /// Addresses or CIDR blocks of the front proxies.
+    pub trusted_proxies: Vec<String>,
+    /// Defaults to `DEFAULT_HEADERS`.
+    pub headers: Option<Vec<String>>,
+}
+
+impl TrustedIncomingHeaders {
+    /// Headers a client could spoof its address, scheme or certificate with.
+    pub const DEFAULT_HEADERS: [&'static str; 9] = [
+        "forwarded",
+        "x-forwarded-for",
+        "x-forwarded-host",
+        "x-forwarded-proto",
+        "x-forwarded-port",
+        "x-forwarded-ssl",
+        "x-real-ip",
+        "x-client-cert",
+        "x-ssl-client-cert",
+    ];
+
+    pub fn header_names(&self) -> Vec<&str> {
+        match &self.headers {
+            Some(headers) => headers.iter().map(String::as_str).collect(),
+            None => Self::DEFAULT_HEADERS.to_vec(),
+        }
+    }
+}
+
+/// `User-Agent` for forwarded requests, for upstreams that reject requests
+/// without one.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct UserAgent {
+    pub value: String,
+    /// Replace the client's `User-Agent` too, not only a missing one.
+    pub always: Option<bool>,
+}
+
+/// Gzip for upstream responses. Present means enabled.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Validate)]
+pub struct Compression {
+    #[validate(range(min = 1, max = 9))]
+    pub level: Option<u32>,
+    pub min_length: Option<u64>,
+}
+
+/// Cap on the size of the headers sent upstream, checked by the
+/// `header_limit` step of the request pipeline.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Validate)]
+pub struct HeaderLimit {
+    #[validate(range(min = 1))]
+    pub max_bytes: usize,
+    /// Removed in this order until the headers fit, each a header name or
+    /// `cookie:<name>`. Requests that still do not fit get a 431.
+    pub strip: Option<Vec<String>>,
+}
+
+/// Security response headers, present means enabled. Unset fields use the
+/// default, an empty value leaves that header out.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SecurityHeaders {
+    /// Defaults to `DENY`.
+    pub frame_options: Option<String>,
+    /// Defaults to `strict-origin-when-cross-origin`.
+    pub referrer_policy: Option<String>,
+    /// Unset keeps the upstream's.
+    pub content_security_policy: Option<String>,
+    /// Unset keeps the upstream's.
+    pub permissions_policy: Option<String>,
+    /// Https responses only, unset keeps the upstream's.
+    pub hsts_max_age_secs: Option<u64>,
+}
+
+/// Token bucket per client ip.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Validate)]
+pub struct RateLimit {
+    #[validate(range(min = 0.001))]
+    pub requests_per_sec: f64,
+    /// Bucket size, defaults to one second worth of requests.
+    pub burst: Option<u32>,
+    /// Own limits for some methods, e.g. `POST`. Each listed method gets its
+    /// own bucket per client ip, the others share the default one.
+    #[validate]
+    pub methods: Option<HashMap<String, MethodRateLimit>>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Validate)]
+pub struct MethodRateLimit {
+    #[validate(range(min = 0.001))]
+    pub requests_per_sec: f64,
+    pub burst: Option<u32>,
+}
+
+/// Raised when more than `threshold` of the host's answers over the last
+/// `window_secs` were 5xx.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Validate)]
+pub struct ErrorAlert {
+    #[validate(range(min = 0.0, max = 1.0))]
+    pub threshold: f64,
+    /// Defaults to 60.
+    #[validate(range(min = 1, max = 3600))]
+    pub window_secs: Option<u64>,
+    /// Windows with fewer requests never alert, defaults to 20.
+    pub min_requests: Option<u32>,
+}
+
+/// Token bucket shared by every request to a host.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Validate)]
+pub struct HostRateLimit {
+    #[validate(range(min = 0.001))]
+    pub requests_per_sec: f64,
+    /// Bucket size, defaults to one second worth of requests.
+    pub burst: Option<u32>,
+}
+
+/// A fixed response the proxy answers with itself, 503 unless `status` says
+/// otherwise.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Validate)]
+pub struct CustomResponse {
+    #[validate(range(min = 100, max = 599))]
+    pub status: Option<u16>,
+    pub body: Option<String>,
+    pub content_type: Option<String>,
+    pub retry_after_secs: Option<u64>,
+}
+
+/// Sent instead of the generic 424 for a request whose host matches no
+/// entry in `hosts`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Validate)]
+pub struct UnknownHostResponse {
+    #[validate(range(min = 100, max = 599))]
+    pub status: Option<u16>,
+    pub body: Option<String>,
+    pub content_type: Option<String>,
+    /// Closes the connection without any response, the other fields are
+    /// ignored.
+    pub close: Option<bool>,
+}
+
+/// Writes the first bytes of request and response bodies to a log or file.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Capture {
+    /// Only requests whose path starts with this, all when unset.
+    pub path_prefix: Option<String>,
+    /// Appends to this file instead of logging.
+    pub file: Option<String>,
+    /// Per body, defaults to 4096.
+    pub max_bytes: Option<usize>,
+    /// Strings replaced with `***` before anything is written.
+    pub redact: Option<Vec<String>>,
+}
+
+/// Fields taken out of json responses before they reach the client.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct JsonRedaction {
+    /// Dotted paths from the document root, e.g. `user.email`, `*` matches
+    /// every key or array element.
+    pub paths: Vec<String>,
+    /// Written in place of the value, the field is removed when unset.
+    pub mask: Option<String>,
+    /// Larger responses pass through untouched, defaults to 1 MiB.
+    pub max_bytes: Option<u64>,
+}
+
+/// `Alt-Svc` added to responses served over https.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AltSvc {
+    /// ALPN ids to advertise, defaults to `h2`.
+    pub protocols: Option<Vec<String>>,
+    /// Defaults to `ssl_port`.
+    pub port: Option<Port>,
+    pub max_age_secs: Option<u64>,
+}
+
+impl AltSvc {
+    /// Every entry has to be a token, as `Alt-Svc` carries it unquoted, so
+    /// ALPN ids outside the token characters come percent-encoded, e.g.
+    /// `http%2F1.1`. `h3` and its drafts are refused: there is no QUIC
+    /// listener for clients to switch to.
+    fn check(&self) -> Result<(), String> {
+        for protocol in self.protocols.iter().flatten() {
+            let tchar = |b: &u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(b);
+            if protocol.is_empty() || !protocol.as_bytes().iter().all(tchar) {
+                return Err(format!("alt_svc: invalid protocol `{}`", protocol));
+            }
+            if protocol.to_ascii_lowercase().starts_with("h3") {
+                return Err(format!(
+                    "alt_svc: cannot advertise `{}`, there is no http/3 (quic) listener",
+                    protocol
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn header_value(&self, port: Port) -> String {
+        let max_age = self.max_age_secs.unwrap_or(86400);
+        let protocols = match &self.protocols {
+            Some(protocols) => protocols.clone(),
+            None => vec!["h2".to_string()],
+        };
+        protocols
+            .iter()
+            .map(|protocol| format!("{}=\":{}\"; ma={}", protocol, port, max_age))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Passive health tracking: an upstream that fails `max_failures` requests
+/// in a row (connect errors or 5xx) is answered with 503 for `cooldown_secs`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct HealthCheck {
+    pub max_failures: Option<u32>,
+    pub cooldown_secs: Option<u64>,
+    /// Also probe every upstream periodically, a failed probe counts like a
+    /// failed request and a passing one brings the upstream back.
+    pub probe: Option<HealthProbe>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct HealthProbe {
+    pub path: String,
+    /// Defaults to 10.
+    pub interval_secs: Option<u64>,
+    /// Defaults to 2000.
+    pub timeout_ms: Option<u64>,
+    /// Any 2xx passes when unset.
+    pub expected_status: Option<u16>,
+    /// The body has to contain this as well.
+    pub expected_body_contains: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Validate)]
+pub struct WsDrain {
+    /// Sent in the close frame, defaults to 1012 (service restart).
+    #[validate(range(min = 1000, max = 4999))]
+    pub close_code: Option<u16>,
+    /// How long the client gets to answer the close frame before the
+    /// tunnel is dropped, defaults to 1000.
+    pub grace_ms: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct HashRouting {
+    pub header: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Warmup {
+    pub path: String,
+    /// Defaults to 10000.
+    pub timeout_ms: Option<u64>,
+}
+
+/// Rejects requests whose body matches one of `deny_patterns`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct BodyInspection {
+    pub deny_patterns: Vec<Pattern>,
+    /// Largest body buffered for inspection, bigger ones answer 413.
+    /// Defaults to 65536.
+    pub max_bytes: Option<u64>,
+}
+
+/// HTTP version spoken to a host's upstream, independent of how the client
+/// connected.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamVersion {
+    #[default]
+    Http1,
+    /// Prior knowledge for `http`, ALPN for `https` upstreams.
+    Http2,
+}
+
+/// What a host's isolated pool, aggregate rate limit, websocket slots and
+/// balancing position are kept per.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum StateScope {
+    #[default]
+    Host,
+    /// Shared by every host with the same set of upstreams.
+    Upstream,
+}
+
+/// What an HTTP/1.1 `Upgrade: h2c` request leads to.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum H2cUpgrade {
+    /// Answered over HTTP/1.1 as if the client had not asked to upgrade.
+    #[default]
+    Ignore,
+    /// Passed on to the upstream, which may switch to h2c; after its 101
+    /// the connection is tunnelled like a websocket.
+    Tunnel,
+}
+
+/// Which failures `retries` covers. Either way only idempotent methods are
+/// retried: a POST that provably never left the proxy is still sent once.
+/// The replay of a request that met a dead pooled connection
+/// (`retry_stale_connections`) follows the same rule.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryMode {
+    /// Only failures where the request certainly never reached the upstream:
+    /// the connect failed or the request was dropped before being written.
+    NeverSent,
+    /// Also timeouts, connections lost mid-request and 502/503/504, which
+    /// may repeat side effects the upstream already carried out.
+    Idempotent,
+}
+
+/// Handling of an upstream answering with something that does not parse as
+/// HTTP, e.g. a non-HTTP service on the configured port.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidResponsePolicy {
+    /// Answer 502 right away.
+    #[default]
+    Fail,
+    /// Treat it like a connection error, retried within `retries`.
+    Retry,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeFlavor {
+    #[default]
+    MultiThread,
+    /// Everything on the main thread.
+    CurrentThread,
+}
+
+/// What to do with an http/1.x request whose target is an absolute uri
+/// (`GET http://example.com/ HTTP/1.1`), which only forward proxies expect.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AbsoluteFormPolicy {
+    /// Answer 400.
+    #[default]
+    Reject,
+    /// Route by the target's authority instead of the `Host` header.
+    Honor,
+}
+
+/// Gzip settings with their defaults applied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GzipSettings {
+    pub level: u32,
+    pub min_length: u64,
+}
+
+impl GzipSettings {
+    fn resolve(level: Option<u32>, min_length: Option<u64>) -> Self {
+        GzipSettings {
+            level: level.unwrap_or(6),
+            min_length: min_length.unwrap_or(1024),
+        }
+    }
+}
+
+/// A step of the request pipeline. Each only does something when the
+/// option it belongs to is set.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestStep {
+    /// Drops `Range`/`If-Range` when `range_requests` is off.
+    StripRange,
+    Via,
+    UserAgent,
+    /// `trace_sample_rate`
+    Trace,
+    /// `behind_https`
+    Forwarded,
+    /// The `Host` sent upstream.
+    HostHeader,
+    /// `upstream_header_limit`, last by default so it sees every header the
+    /// other steps add. The request id and `Connection` rewrites are made
+    /// before the pipeline and counted; the deadline header and request
+    /// compression's `Content-Encoding` are added after it and are not.
+    HeaderLimit,
 }
 
+impl RequestStep {
+    pub const DEFAULT_ORDER: [RequestStep; 7] = [
+        RequestStep::StripRange,
+        RequestStep::Via,
+        RequestStep::UserAgent,
+        RequestStep::Trace,
+        RequestStep::Forwarded,
+        RequestStep::HostHeader,
+        RequestStep::HeaderLimit,
+    ];
+}
+
+/// The settings below as requests to one host run with them: the host's own
+/// value where it has one, else the global value, else the built-in default.
+/// Per-host options not listed here, such as timeouts, retries and the
+/// routes, are still read from the `Host` itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectiveSettings {
+    pub response_compression: Option<GzipSettings>,
+    pub request_compression: Option<GzipSettings>,
+    pub range_requests: bool,
+    pub behind_https: bool,
+    pub https_port: Port,
+    /// The `Alt-Svc` value for https responses.
+    pub alt_svc: Option<String>,
+    pub raw_path_passthrough: bool,
+    pub single_flight: bool,
+    pub balance: BalanceStrategy,
+    pub upstream_version: UpstreamVersion,
+    pub retry_stale_connections: bool,
+    pub read_only: bool,
+    pub request_id_header: Option<String>,
+    /// Every step, in the order they run.
+    pub request_pipeline: Vec<RequestStep>,
+}
+
+impl Config {
+    /// Merges the `EffectiveSettings` fields of `host` with the global ones
+    /// and the defaults, proxying and the admin API read them from here.
+    pub fn effective_settings(&self, host: &Host) -> EffectiveSettings {
+        let https_port = self.ssl_port.unwrap_or(443);
+        EffectiveSettings {
+            response_compression: self.compression.as_ref().map(|compression| {
+                GzipSettings::resolve(
+                    host.compression_level.or(compression.level),
+                    compression.min_length,
+                )
+            }),
+            request_compression: host.request_compression.as_ref().map(|compression| {
+                GzipSettings::resolve(compression.level, compression.min_length)
+            }),
+            range_requests: host.range_requests.unwrap_or(true),
+            behind_https: host.behind_https.unwrap_or(false),
+            https_port,
+            alt_svc: self
+                .alt_svc
+                .as_ref()
+                .map(|alt_svc| alt_svc.header_value(alt_svc.port.unwrap_or(https_port))),
+            raw_path_passthrough: host.raw_path_passthrough.unwrap_or(false),
+            single_flight: host.single_flight.unwrap_or(false),
+            balance: host.balance.unwrap_or_default(),
+            upstream_version: host.upstream_version.unwrap_or_default(),
+            retry_stale_connections: self.retry_stale_connections.unwrap_or(true),
+            read_only: self.read_only.unwrap_or(false),
+            request_id_header: host
+                .request_id_header
+                .clone()
+                .or_else(|| self.request_id_header.clone()),
+            request_pipeline: {
+                let mut steps = host.request_pipeline.clone().unwrap_or_default();
+                for step in RequestStep::DEFAULT_ORDER {
+                    if !steps.contains(&step) {
+                        steps.push(step);
+                    }
+                }
+                steps
+            },
+        }
+    }
+
+    pub fn ssl_enabled(&self) -> bool {
+        self.ssl.unwrap_or(false)
+    }
+
+    /// Every port the proxy listens on, with the option that sets it.
+    pub fn listener_ports(&self) -> Vec<(String, Port)> {
+        let mut ports = vec![("port".to_string(), self.port.unwrap_or(80))];
+        if self.ssl_enabled() {
+            ports.push(("ssl_port".to_string(), self.ssl_port.unwrap_or(443)));
+        }
+        for port in self.extra_ports.iter().flatten() {
+            ports.push(("extra_ports".to_string(), *port));
+        }
+        if let Some(port) = self.admin_port {
+            ports.push(("admin_port".to_string(), port));
+        }
+        ports
+    }
+
+    /// `TRACE` is blocked unless `blocked_methods` is set explicitly, an
+    /// empty list allows everything.
+    pub fn is_method_blocked(&self, method: &str) -> bool {
+        match &self.blocked_methods {
+            Some(methods) => methods.iter().any(|m| m.eq_ignore_ascii_case(method)),
+            None => method == "TRACE",
+        }
+    }
+
+    /// The host entry serving `host`, used both for routing requests and for
+    /// picking the cert of a TLS handshake. From most to least specific:
+    ///
+    /// 1. a key equal to the name, as `name:port` and then bare
+    /// 2. an alias equal to the name, the same way
+    /// 3. a `*.suffix` key or alias, the longest suffix winning and a
+    ///    wildcard with the port winning over one without
+    /// 4. `default_host`
+    ///
+    /// Without a port in `host` the port the request came in on is used. An
+    /// alias resolves to the key of the entry declaring it.
+    pub fn resolve_host(
+        &self,
+        host: &str,
+        listen_port: Port,
+    ) -> Option<(&String, &Host, HostMatch)> {
+        let (name, port) = split_host_port(host);
+        let port = port.unwrap_or(listen_port);
+        let with_port = format!("{}:{}", name, port);
+        let tag = |kind| move |(key, host)| (key, host, kind);
+        self.find_key(&with_port)
+            .or_else(|| self.find_key(name))
+            .map(tag(HostMatch::Exact))
+            .or_else(|| {
+                self.find_alias(&with_port)
+                    .or_else(|| self.find_alias(name))
+                    .map(tag(HostMatch::Alias))
+            })
+            .or_else(|| self.find_wildcard(name, port).map(tag(HostMatch::Wildcard)))
+            .or_else(|| {
+                let default = self.default_host.as_ref()?;
+                self.hosts
+                    .get_key_value(default)
+                    .map(tag(HostMatch::Default))
+            })
+    }
+
+    fn find_key(&self, name: &str) -> Option<(&String, &Host)> {
+        self.hosts.get_key_value(name).or_else(|| {
+            self.hosts
+                .iter()
+                .find(|(domain, _)| domain.eq_ignore_ascii_case(name))
+        })
+    }
+
+    fn find_alias(&self, name: &str) -> Option<(&String, &Host)> {
+        self.hosts.iter().find(|(_, host)| {
+            host.aliases
+                .iter()
+                .flatten()
+                .any(|alias| alias.eq_ignore_ascii_case(name))
+        })
+    }
+
+    /// The host with the most specific wildcard covering `name`, ties going
+    /// to the smallest key so the choice does not depend on map order.
+    fn find_wildcard(&self, name: &str, port: Port) -> Option<(&String, &Host)> {
+        self.hosts
+            .iter()
+            .filter_map(|(domain, host)| {
+                let best = host
+                    .names(domain)
+                    .filter_map(|pattern| wildcard_match(pattern, name, port))
+                    .max()?;
+                Some((best, domain, host))
+            })
+            .max_by(|(a, a_domain, _), (b, b_domain, _)| a.cmp(b).then(b_domain.cmp(a_domain)))
+            .map(|(_, domain, host)| (domain, host))
+    }
+
+    /// Settings that would write to disk, which `read_only` overrides.
+    pub fn read_only_conflicts(&self) -> Vec<String> {
+        if !self.read_only.unwrap_or(false) {
+            return Vec::new();
+        }
+        let mut conflicts: Vec<String> = self
+            .hosts
+            .iter()
+            .filter_map(|(domain, host)| {
+                let file = host.capture.as_ref()?.file.as_ref()?;
+                Some(format!(
+                    "read_only: host `{}` captures to the log instead of {}",
+                    domain, file
+                ))
+            })
+            .collect();
+        if let Some(file) = &self.ready_file {
+            conflicts.push(format!("read_only: ready_file {} is not written", file));
+        }
+        conflicts.sort();
+        conflicts
+    }
+
+    pub fn slow_connect_log(&self) -> Option<Duration> {
+        self.slow_connect_log_ms.map(Duration::from_millis)
+    }
+
+    pub fn client_write_timeout(&self) -> Option<Duration> {
+        self.client_write_timeout_secs.map(Duration::from_secs)
+    }
+
+    pub fn tls_handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.tls_handshake_timeout_secs.unwrap_or(10))
+    }
+
+    pub fn upstream_tcp_keepalive(&self) -> Option<Duration> {
+        self.upstream_tcp_keepalive_secs.map(Duration::from_secs)
+    }
+
+    /// `None` for 0, which keeps idle connections forever.
+    pub fn upstream_idle_timeout(&self) -> Option<Duration> {
+        match self.upstream_idle_timeout_secs.unwrap_or(90) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs.unwrap_or(30))
+    }
+
+    pub fn ssl_cert_path(&self) -> String {
+        self.ssl_cert_file
+            .clone()
+            .unwrap_or_else(|| "./ssl/certificate.crt".to_string())
+    }
+
+    pub fn ssl_key_path(&self) -> String {
+        self.ssl_key_file
+            .clone()
+            .unwrap_or_else(|| "./ssl/private.pem".to_string())
+    }
+
+    pub fn ssl_cert_source(&self) -> PemSource {
+        match &self.ssl_cert {
+            Some(value) => PemSource::Value(value.clone()),
+            None => PemSource::File(self.ssl_cert_path()),
+        }
+    }
+
+    pub fn ssl_key_source(&self) -> PemSource {
+        match &self.ssl_key {
+            Some(value) => PemSource::Value(value.clone()),
+            None => PemSource::File(self.ssl_key_path()),
+        }
+    }
+
+    /// Whether `dev_mode` replaces the default cert, which it does only when
+    /// a cert or key file is missing. Inline material is always used.
+    pub fn needs_dev_cert(&self) -> bool {
+        let missing = |source: PemSource| match source {
+            PemSource::File(path) => !Path::new(&path).exists(),
+            PemSource::Value(_) => false,
+        };
+        self.dev_mode.unwrap_or(false)
+            && (missing(self.ssl_cert_source()) || missing(self.ssl_key_source()))
+    }
+}
+
+/// How `Config::resolve_host` found a host, most specific first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostMatch {
+    Exact,
+    Alias,
+    Wildcard,
+    Default,
+}
+
+/// How specific the wildcard `pattern` is when it covers `name`, the length
+/// of its suffix and then whether it names the port. `*.example.com` covers
+/// `a.example.com` and `a.b.example.com` but not `example.com`.
+fn wildcard_match(pattern: &str, name: &str, port: Port) -> Option<(usize, bool)> {
+    let (pattern, pattern_port) = split_host_port(pattern);
+    let suffix = pattern.strip_prefix('*')?;
+    if !suffix.starts_with('.') || pattern_port.map(|p| p != port).unwrap_or(false) {
+        return None;
+    }
+    let split = name.len().checked_sub(suffix.len()).filter(|&i| i > 0)?;
+    if !name.is_char_boundary(split) || !name[split..].eq_ignore_ascii_case(suffix) {
+        return None;
+    }
+    Some((suffix.len(), pattern_port.is_some()))
+}
+
+/// Splits `name:port`, keeping the brackets of an ipv6 literal in the name.
+pub fn split_host_port(host: &str) -> (&str, Option<Port>) {
+    let split = match host.rfind(':') {
+        Some(i) if host.starts_with('[') && host[..i].ends_with(']') => Some(i),
+        Some(i) if !host.starts_with('[') && !host[..i].contains(':') => Some(i),
+        _ => None,
+    };
+    match split.and_then(|i| host[i + 1..].parse().ok().map(|port| (i, port))) {
+        Some((i, port)) => (&host[..i], Some(port)),
+        None => (host, None),
+    }
+}
+
+/// `--config` value that reads the config from stdin.
+pub const STDIN_CONFIG: &str = "-";
+
 pub fn read_yaml_file(yaml_path: &str) -> Config {
     let yaml_content = fs::read_to_string(yaml_path).ok().unwrap_or_default();
     let result: Config = serde_yaml::from_str(&yaml_content).ok().unwrap_or(Config {
         port: Some(80),
         ssl_port: Some(443),
-        hosts: HashMap::new(),
         ssl: Some(false),
         ssl_key_file: Some(String::from("./ssl/private.pem")),
         ssl_cert_file: Some(String::from("./ssl/certificate.crt")),
+        ..Default::default()
     });
-    match result.validate() {
-        Ok(_) => {
-            for host in result.hosts.values() {
-                match host.validate() {
-                    Err(e) => panic!("{}", e),
-                    _ => (),
+    if let Err(e) = validate_fields(&result) {
+        panic!("{}", e);
+    }
+    result
+}
+
+/// Reads a whole config from `reader`, `name` says where from in errors.
+/// There is no fallback to defaults: stdin can't be read a second time, so
+/// a config piped in that doesn't parse must stop the startup.
+pub fn read_config(mut reader: impl Read, name: &str) -> Result<Config, String> {
+    let mut yaml_content = String::new();
+    reader
+        .read_to_string(&mut yaml_content)
+        .map_err(|e| format!("failed to read {}: {}", name, e))?;
+    let config: Config = serde_yaml::from_str(&yaml_content)
+        .map_err(|e| format!("failed to parse {}: {}", name, e))?;
+    validate_fields(&config)?;
+    Ok(config)
+}
+
+/// Loads a config for hot reload. Unlike `read_yaml_file` this never falls
+/// back to defaults or panics: any problem is returned so the caller can keep
+/// the config that is currently live.
+pub fn load_config(yaml_path: &str) -> Result<Config, String> {
+    let file =
+        fs::File::open(yaml_path).map_err(|e| format!("failed to read {}: {}", yaml_path, e))?;
+    read_config(file, yaml_path)
+}
+
+/// Where the config is read from, given by `--config` or `--config-dir`.
+#[derive(Debug, Clone)]
+pub enum ConfigSource {
+    /// A single file, or stdin for `-`.
+    File(String),
+    /// A directory of `.yml` fragments merged into one config.
+    Dir(String),
+}
+
+impl ConfigSource {
+    /// Loads the config for hot reload, see `load_config`.
+    pub fn load(&self) -> Result<Config, String> {
+        match self {
+            ConfigSource::File(path) => load_config(path),
+            ConfigSource::Dir(dir) => load_config_dir(dir),
+        }
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::File(path) | ConfigSource::Dir(path) => f.write_str(path),
+        }
+    }
+}
+
+/// The `.yml` files directly in `dir`, in the order they are merged.
+pub fn config_fragments(dir: &str) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "yml"))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Merges the fragments of `dir` in file name order into one config. Every
+/// host and every other top level setting may only come from one fragment,
+/// a second definition is an error naming both files.
+pub fn load_config_dir(dir: &str) -> Result<Config, String> {
+    let fragments = config_fragments(dir).map_err(|e| format!("failed to read {}: {}", dir, e))?;
+    let mut merged = serde_yaml::Mapping::new();
+    let mut hosts = serde_yaml::Mapping::new();
+    let mut defined_in: HashMap<String, String> = HashMap::new();
+    for path in fragments {
+        let name = path.display().to_string();
+        let yaml_content =
+            fs::read_to_string(&path).map_err(|e| format!("failed to read {}: {}", name, e))?;
+        // An empty fragment parses as null.
+        let fragment: Option<serde_yaml::Mapping> = serde_yaml::from_str(&yaml_content)
+            .map_err(|e| format!("failed to parse {}: {}", name, e))?;
+        for (key, value) in fragment.into_iter().flatten() {
+            let key_name = key
+                .as_str()
+                .ok_or_else(|| format!("{}: top level keys must be strings", name))?
+                .to_string();
+            if key_name != "hosts" {
+                if let Some(first) = defined_in.insert(key_name.clone(), name.clone()) {
+                    return Err(format!(
+                        "`{}` is set in both {} and {}",
+                        key_name, first, name
+                    ));
+                }
+                merged.insert(key, value);
+                continue;
+            }
+            let entries: Option<serde_yaml::Mapping> = serde_yaml::from_value(value)
+                .map_err(|e| format!("failed to parse {}: hosts: {}", name, e))?;
+            for (domain, host) in entries.into_iter().flatten() {
+                let domain_name = domain
+                    .as_str()
+                    .ok_or_else(|| format!("{}: host keys must be strings", name))?;
+                let owner = format!("hosts.{}", domain_name.to_ascii_lowercase());
+                if let Some(first) = defined_in.insert(owner, name.clone()) {
+                    return Err(format!(
+                        "host `{}` is defined in both {} and {}",
+                        domain_name, first, name
+                    ));
                 }
+                hosts.insert(domain, host);
             }
-            return result;
         }
-        Err(e) => panic!("{}", e),
     }
+    merged.insert("hosts".into(), serde_yaml::Value::Mapping(hosts));
+    let config: Config = serde_yaml::from_value(serde_yaml::Value::Mapping(merged))
+        .map_err(|e| format!("failed to parse {}: {}", dir, e))?;
+    validate_fields(&config)?;
+    Ok(config)
+}
+
+fn validate_fields(config: &Config) -> Result<(), String> {
+    config.validate().map_err(|e| e.to_string())?;
+    if let Some(name) = &config.request_id_header {
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid request_id_header `{}`", name))?;
+    }
+    for name in config.redact_headers.iter().flatten() {
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid redact_headers entry `{}`", name))?;
+    }
+    if let Some(policy) = &config.trusted_incoming_headers {
+        for range in &policy.trusted_proxies {
+            IpRange::parse(range).map_err(|e| format!("trusted_incoming_headers: {}", e))?;
+        }
+        for name in policy.header_names() {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("trusted_incoming_headers: invalid header `{}`", name))?;
+        }
+    }
+    if let Some(alt_svc) = &config.alt_svc {
+        alt_svc.check()?;
+    }
+    if let Some(default) = &config.default_host {
+        if !config.hosts.contains_key(default) {
+            return Err(format!("default_host `{}` is not in hosts", default));
+        }
+    }
+    for (domain, host) in &config.hosts {
+        host.validate()
+            .map_err(|e| format!("host `{}`: {}", domain, e))?;
+        host.check_targets()
+            .map_err(|e| format!("host `{}`: {}", domain, e))?;
+        for route in host.accept_routes.iter().flatten() {
+            route
+                .target()
+                .map_err(|e| format!("host `{}`: {}", domain, e))?;
+        }
+        for route in host.regex_routes.iter().flatten() {
+            route
+                .target()
+                .map_err(|e| format!("host `{}`: {}", domain, e))?;
+        }
+        if let Some(warmup) = &host.warmup {
+            if !warmup.path.starts_with('/') {
+                return Err(format!(
+                    "host `{}`: warmup path `{}` must start with `/`",
+                    domain, warmup.path
+                ));
+            }
+        }
+        if host.retry_mode == Some(RetryMode::NeverSent)
+            && host.invalid_response == Some(InvalidResponsePolicy::Retry)
+        {
+            return Err(format!(
+                "host `{}`: invalid_response `retry` needs retry_mode `idempotent`",
+                domain
+            ));
+        }
+        let steps = host.request_pipeline.iter().flatten();
+        for (index, step) in steps.clone().enumerate() {
+            if steps.clone().skip(index + 1).any(|other| other == step) {
+                return Err(format!(
+                    "host `{}`: request_pipeline lists {} more than once",
+                    domain,
+                    serde_json::to_string(step).unwrap_or_default()
+                ));
+            }
+        }
+        if let Some(name) = &host.deadline_header {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("host `{}`: invalid deadline_header `{}`", domain, name))?;
+        }
+        if let Some(name) = &host.request_id_header {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("host `{}`: invalid request_id_header `{}`", domain, name))?;
+        }
+        if host.require_https.unwrap_or(false) && !config.ssl.unwrap_or(false) {
+            return Err(format!(
+                "host `{}`: require_https is set but ssl is off",
+                domain
+            ));
+        }
+        if let Some(hash_routing) = &host.hash_routing {
+            HeaderName::from_bytes(hash_routing.header.as_bytes()).map_err(|_| {
+                format!(
+                    "host `{}`: invalid hash_routing header `{}`",
+                    domain, hash_routing.header
+                )
+            })?;
+        }
+        if let Some(user_agent) = &host.user_agent {
+            HeaderValue::from_str(&user_agent.value).map_err(|_| {
+                format!(
+                    "host `{}`: invalid user_agent `{}`",
+                    domain, user_agent.value
+                )
+            })?;
+        }
+        for range in host.maintenance_allow_ips.iter().flatten() {
+            IpRange::parse(range).map_err(|e| format!("host `{}`: {}", domain, e))?;
+        }
+    }
+    let mut owners: HashMap<&str, &str> = config
+        .hosts
+        .keys()
+        .map(|domain| (domain.as_str(), domain.as_str()))
+        .collect();
+    for (domain, host) in &config.hosts {
+        for alias in host.aliases.iter().flatten() {
+            if let Some(owner) = owners.insert(alias, domain) {
+                return Err(format!(
+                    "host `{}`: alias `{}` is already used by `{}`",
+                    domain, alias, owner
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks everything that would make a config fail once it is live: field
+/// validation, listener port conflicts and whether the TLS material loads.
+pub fn validate_config(config: &Config) -> Result<(), String> {
+    validate_fields(config)?;
+    let ports = config.listener_ports();
+    for (i, (name, port)) in ports.iter().enumerate() {
+        if let Some((other, _)) = ports[..i].iter().find(|(_, taken)| taken == port) {
+            return Err(format!(
+                "`{}` and `{}` are both {}, the listeners would conflict",
+                other, name, port
+            ));
+        }
+    }
+    let proxy_ports: Vec<Port> = ports
+        .iter()
+        .filter(|(name, _)| name != "admin_port")
+        .map(|(_, port)| *port)
+        .collect();
+    for (domain, host) in &config.hosts {
+        for name in host.names(domain) {
+            if let (_, Some(port)) = split_host_port(name) {
+                if !proxy_ports.contains(&port) {
+                    return Err(format!(
+                        "host `{}`: no listener on port {} for `{}`, add it to extra_ports",
+                        domain, port, name
+                    ));
+                }
+            }
+        }
+    }
+    if config.ssl_enabled() && !config.needs_dev_cert() {
+        load_certified_key(&config.ssl_cert_source(), &config.ssl_key_source())
+            .map_err(|e| format!("failed to load the default tls cert: {}", e))?;
+    }
+    Ok(())
 }
 
 pub fn protocol_check(value: &str) -> Result<(), ValidationError> {
-    if vec!["http", "https"].contains(&value) {
+    if ["http", "https"].contains(&value) {
         Ok(())
     } else {
         Err(ValidationError::new(
@@ -55,3 +1606,349 @@ pub fn protocol_check(value: &str) -> Result<(), ValidationError> {
         ))
     }
 }
+
+/// A host may also answer requests itself with `echo`, upstreams may not.
+pub fn host_protocol_check(value: &str) -> Result<(), ValidationError> {
+    if value == "echo" {
+        return Ok(());
+    }
+    protocol_check(value)
+        .map_err(|_| ValidationError::new("protocol only support 'http', 'https' or 'echo'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> Config {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    const HOSTS: &str = "hosts:\n  a.com:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n";
+
+    #[test]
+    fn listener_ports_must_differ() {
+        for ports in [
+            "extra_ports: [80]\n",
+            "extra_ports: [8080, 8080]\n",
+            "admin_port: 80\n",
+            "extra_ports: [9090]\nadmin_port: 9090\n",
+        ] {
+            let e = validate_config(&parse(&format!("{}{}", ports, HOSTS))).unwrap_err();
+            assert!(e.contains("would conflict"), "{}: {}", ports, e);
+        }
+        validate_config(&parse(&format!(
+            "extra_ports: [8080]\nadmin_port: 9090\n{}",
+            HOSTS
+        )))
+        .unwrap();
+    }
+
+    #[test]
+    fn ssl_port_only_conflicts_with_ssl_on() {
+        validate_config(&parse(&format!("port: 8443\nssl_port: 8443\n{}", HOSTS))).unwrap();
+        let e = validate_config(&parse(&format!(
+            "port: 8443\nssl: true\nssl_port: 8443\n{}",
+            HOSTS
+        )))
+        .unwrap_err();
+        assert!(e.contains("`port` and `ssl_port`"), "{}", e);
+    }
+
+    #[test]
+    fn alt_svc_protocols_must_be_tokens_without_h3() {
+        for protocols in ["[h3]", "[h2, H3-29]", "['http/1.1']", "['']", "['h2 x']"] {
+            let yaml = format!("alt_svc:\n  protocols: {}\n{}", protocols, HOSTS);
+            let e = validate_config(&parse(&yaml)).unwrap_err();
+            assert!(e.starts_with("alt_svc:"), "{}: {}", protocols, e);
+        }
+        let yaml = format!("alt_svc:\n  protocols: [h2, http%2F1.1]\n{}", HOSTS);
+        validate_config(&parse(&yaml)).unwrap();
+    }
+
+    fn upstream_host(precedence: &str) -> Config {
+        parse(&format!(
+            "hosts:\n  a.com:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n    \
+             upstreams: ['http://127.0.0.2:9000', 'http://127.0.0.3:9000']\n{}",
+            precedence
+        ))
+    }
+
+    #[test]
+    fn upstream_precedence_decides_the_targets() {
+        let ips = |precedence: &str| -> Vec<String> {
+            upstream_host(precedence).hosts["a.com"]
+                .targets()
+                .iter()
+                .map(|target| target.ip.clone())
+                .collect()
+        };
+        assert_eq!(ips(""), ["127.0.0.2", "127.0.0.3"]);
+        assert_eq!(
+            ips("    upstream_precedence: append\n"),
+            ["127.0.0.2", "127.0.0.3", "127.0.0.1"]
+        );
+        assert_eq!(parse(HOSTS).hosts["a.com"].targets()[0].ip, "127.0.0.1");
+
+        let e = validate_config(&upstream_host("    upstream_precedence: strict\n")).unwrap_err();
+        assert!(e.contains("`upstream_precedence` is strict"), "{}", e);
+        validate_config(&upstream_host("    upstream_precedence: append\n")).unwrap();
+    }
+
+    #[test]
+    fn host_key_ports_need_a_listener() {
+        let host = "    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n";
+        let config = |top: &str, key: &str| parse(&format!("{}hosts:\n  {}:\n{}", top, key, host));
+        validate_config(&config("", "a.com:80")).unwrap();
+        validate_config(&config("extra_ports: [8080]\n", "a.com:8080")).unwrap();
+        let e = validate_config(&config("", "a.com:8080")).unwrap_err();
+        assert!(e.contains("no listener on port 8080"), "{}", e);
+        let e = validate_config(&config("admin_port: 9090\n", "a.com:9090")).unwrap_err();
+        assert!(e.contains("no listener on port 9090"), "{}", e);
+        let e = validate_config(&parse(&format!(
+            "hosts:\n  a.com:\n{}    aliases: [b.com:81]\n",
+            host
+        )))
+        .unwrap_err();
+        assert!(e.contains("no listener on port 81"), "{}", e);
+    }
+
+    #[test]
+    fn same_name_routes_by_port() {
+        let config = parse(
+            "extra_ports: [8080]\nhosts:\n  a.com:80:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n  a.com:8080:\n    ip: 127.0.0.1\n    port: 9001\n    protocol: http\n",
+        );
+        validate_config(&config).unwrap();
+        assert_eq!(config.resolve_host("a.com", 80).unwrap().1.port, Some(9000));
+        assert_eq!(
+            config.resolve_host("a.com", 8080).unwrap().1.port,
+            Some(9001)
+        );
+        assert_eq!(
+            config.resolve_host("a.com:80", 8080).unwrap().1.port,
+            Some(9000)
+        );
+        assert!(config.resolve_host("a.com", 9090).is_none());
+    }
+
+    /// Hosts named after their upstream port, so a lookup tells which one
+    /// it found.
+    fn hosts(entries: &[(&str, u16, &str)]) -> Config {
+        let mut yaml = String::from("hosts:\n");
+        for (key, port, extra) in entries {
+            yaml.push_str(&format!(
+                "  \"{}\":\n    ip: 127.0.0.1\n    port: {}\n    protocol: http\n{}",
+                key, port, extra
+            ));
+        }
+        parse(&yaml)
+    }
+
+    fn resolved(config: &Config, host: &str) -> Option<(Port, HostMatch)> {
+        config
+            .resolve_host(host, 80)
+            .map(|(_, host, found)| (host.port.unwrap(), found))
+    }
+
+    #[test]
+    fn host_resolution_precedence() {
+        let config = hosts(&[
+            ("a.example.com", 1, "    aliases: [alias.example.com]\n"),
+            ("*.example.com", 2, ""),
+            ("*.b.example.com", 3, ""),
+            ("fallback.com", 4, ""),
+        ]);
+        assert_eq!(
+            resolved(&config, "a.example.com"),
+            Some((1, HostMatch::Exact))
+        );
+        assert_eq!(
+            resolved(&config, "A.Example.COM"),
+            Some((1, HostMatch::Exact))
+        );
+        assert_eq!(
+            resolved(&config, "alias.example.com"),
+            Some((1, HostMatch::Alias))
+        );
+        assert_eq!(
+            resolved(&config, "x.example.com"),
+            Some((2, HostMatch::Wildcard))
+        );
+        assert_eq!(
+            resolved(&config, "x.b.example.com"),
+            Some((3, HostMatch::Wildcard))
+        );
+        assert_eq!(resolved(&config, "example.com"), None);
+
+        let mut config = config;
+        config.default_host = Some("fallback.com".to_string());
+        assert_eq!(
+            resolved(&config, "other.org"),
+            Some((4, HostMatch::Default))
+        );
+        assert_eq!(
+            resolved(&config, "a.example.com"),
+            Some((1, HostMatch::Exact))
+        );
+    }
+
+    #[test]
+    fn wildcards_with_the_port_win() {
+        let config = hosts(&[("*.example.com", 1, ""), ("*.example.com:80", 2, "")]);
+        assert_eq!(
+            resolved(&config, "x.example.com"),
+            Some((2, HostMatch::Wildcard))
+        );
+        assert_eq!(
+            config
+                .resolve_host("x.example.com:81", 80)
+                .map(|(_, h, _)| h.port),
+            Some(Some(1))
+        );
+    }
+
+    #[test]
+    fn upstream_host_header_precedence() {
+        let host = |extra: &str| -> Host {
+            serde_yaml::from_str(&format!(
+                "protocol: http\nupstreams:\n  - http://10.0.0.1:80\n  - url: http://10.0.0.2:80\n    host_header: own.internal\n{}",
+                extra
+            ))
+            .unwrap()
+        };
+        let headers = |host: Host| -> Vec<Option<String>> {
+            host.targets()
+                .iter()
+                .map(|target| host.host_header_for(target))
+                .collect()
+        };
+        let own = Some("own.internal".to_string());
+        assert_eq!(headers(host("")), [None, own.clone()]);
+        assert_eq!(
+            headers(host("preserve_host: false\n")),
+            [Some("10.0.0.1:80".to_string()), own.clone()]
+        );
+        assert_eq!(
+            headers(host(
+                "upstream_host_header: app.internal\npreserve_host: false\n"
+            )),
+            [Some("app.internal".to_string()), own]
+        );
+    }
+
+    #[test]
+    fn configs_are_read_whole_or_refused() {
+        let config = read_config(io::Cursor::new(HOSTS), "stdin").unwrap();
+        assert_eq!(config.hosts["a.com"].port, Some(9000));
+
+        let err = read_config(io::Cursor::new("hosts: [a.com"), "stdin").unwrap_err();
+        assert!(err.starts_with("failed to parse stdin"), "{}", err);
+        let err = read_config(io::Cursor::new(vec![0xff, 0xfe]), "stdin").unwrap_err();
+        assert!(err.starts_with("failed to read stdin"), "{}", err);
+    }
+
+    #[test]
+    fn effective_settings_prefer_the_host_then_global_then_default() {
+        let config = parse(
+            "ssl_port: 8443\nrequest_id_header: x-global-id\ncompression:\n  level: 3\n  min_length: 100\nalt_svc: {}\nhosts:\n  own.com:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n    compression_level: 9\n    request_id_header: x-own-id\n    request_pipeline: [header_limit, via]\n  plain.com:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n",
+        );
+        let own = config.effective_settings(&config.hosts["own.com"]);
+        assert_eq!(
+            own.response_compression,
+            Some(GzipSettings {
+                level: 9,
+                min_length: 100
+            })
+        );
+        assert_eq!(own.request_id_header.as_deref(), Some("x-own-id"));
+        assert_eq!(
+            &own.request_pipeline[..3],
+            [
+                RequestStep::HeaderLimit,
+                RequestStep::Via,
+                RequestStep::StripRange
+            ]
+        );
+        assert_eq!(own.request_pipeline.len(), RequestStep::DEFAULT_ORDER.len());
+
+        let plain = config.effective_settings(&config.hosts["plain.com"]);
+        assert_eq!(plain.response_compression.unwrap().level, 3);
+        assert_eq!(plain.request_id_header.as_deref(), Some("x-global-id"));
+        assert_eq!(plain.request_pipeline, RequestStep::DEFAULT_ORDER);
+        assert!(plain.alt_svc.unwrap().contains(":8443"));
+        assert!(plain.range_requests);
+        assert!(plain.retry_stale_connections);
+        assert_eq!(plain.balance, BalanceStrategy::default());
+    }
+
+    #[test]
+    fn echo_is_a_host_protocol_only() {
+        assert!(host_protocol_check("echo").is_ok());
+        assert!(host_protocol_check("ftp").is_err());
+        assert!(Target::parse("http://127.0.0.1:8080").is_ok());
+        assert!(Target::parse("echo://127.0.0.1:8080").is_err());
+    }
+
+    /// A fresh directory holding `fragments`, named after `test`.
+    fn fragment_dir(test: &str, fragments: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("reverse-proxy-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, yaml) in fragments {
+            fs::write(dir.join(name), yaml).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn config_dir_merges_fragments() {
+        let dir = fragment_dir(
+            "merge",
+            &[
+                ("10-base.yml", "port: 8080\n"),
+                (
+                    "20-a.yml",
+                    "hosts:\n  a.com:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n",
+                ),
+                (
+                    "30-b.yml",
+                    "hosts:\n  b.com:\n    ip: 127.0.0.1\n    port: 9001\n    protocol: http\n",
+                ),
+                ("40-empty.yml", ""),
+                ("notes.txt", "port: 1\n"),
+            ],
+        );
+        let config = load_config_dir(dir.to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(config.port, Some(8080));
+        let mut domains: Vec<&String> = config.hosts.keys().collect();
+        domains.sort();
+        assert_eq!(domains, ["a.com", "b.com"]);
+    }
+
+    #[test]
+    fn config_dir_rejects_settings_defined_twice() {
+        let host = "hosts:\n  A.com:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n";
+        for (test, first, second, expected) in [
+            (
+                "twice-host",
+                host,
+                "hosts:\n  a.com: {}\n",
+                "host `a.com` is defined in both",
+            ),
+            (
+                "twice-key",
+                "port: 80\n",
+                "port: 81\n",
+                "`port` is set in both",
+            ),
+        ] {
+            let dir = fragment_dir(test, &[("a.yml", first), ("b.yml", second)]);
+            let e = load_config_dir(dir.to_str().unwrap()).unwrap_err();
+            fs::remove_dir_all(&dir).unwrap();
+            assert!(e.contains(expected), "{}", e);
+            assert!(e.contains("a.yml") && e.contains("b.yml"), "{}", e);
+        }
+    }
+}
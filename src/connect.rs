@@ -0,0 +1,274 @@
+use std::{
+    error::Error,
+    future::{ready, Future, Ready},
+    io::{self, IoSlice},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+    vec,
+};
+
+use hyper::{
+    client::{
+        connect::{dns::Name, Connected, Connection},
+        HttpConnector,
+    },
+    service::Service,
+    Uri,
+};
+use hyper_tls::{HttpsConnector, MaybeHttpsStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{lookup_host, TcpStream},
+};
+
+use crate::{
+    log::log_info,
+    metrics::{add, incr, METRICS},
+};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Hands `HttpConnector` the addresses `PhaseConnector` already looked up.
+#[derive(Clone)]
+struct Resolved(Vec<SocketAddr>);
+
+impl Service<Name> for Resolved {
+    type Response = vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Ready<Result<Self::Response, io::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _name: Name) -> Self::Future {
+        ready(Ok(self.0.clone().into_iter()))
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Phases {
+    dns: Duration,
+    tcp: Duration,
+}
+
+/// A tcp connection that remembers how long its lookup and connect took.
+pub struct TimedStream {
+    stream: TcpStream,
+    phases: Phases,
+}
+
+impl AsyncRead for TimedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TimedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl Connection for TimedStream {
+    fn connected(&self) -> Connected {
+        self.stream.connected()
+    }
+}
+
+/// Opens the tcp connection, doing the name lookup itself so it can be timed
+/// apart from the connect.
+#[derive(Clone)]
+pub struct PhaseConnector {
+    source: Option<IpAddr>,
+    keepalive: Option<Duration>,
+}
+
+impl PhaseConnector {
+    /// Connects from `source` when set, otherwise the system picks the local
+    /// address. With `keepalive` the connection sends TCP keepalive probes
+    /// after being idle that long.
+    pub fn new(source: Option<IpAddr>, keepalive: Option<Duration>) -> Self {
+        Self { source, keepalive }
+    }
+}
+
+impl Service<Uri> for PhaseConnector {
+    type Response = TimedStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<TimedStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let source = self.source;
+        let keepalive = self.keepalive;
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .ok_or("upstream uri has no host")?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string();
+            let started = Instant::now();
+            // An ip literal never reaches the resolver.
+            let addrs = match host.parse::<IpAddr>() {
+                Ok(_) => Vec::new(),
+                Err(_) => {
+                    let port = match uri.port_u16() {
+                        Some(port) => port,
+                        None if uri.scheme_str() == Some("https") => 443,
+                        None => 80,
+                    };
+                    lookup_host((host.as_str(), port)).await?.collect()
+                }
+            };
+            let dns = started.elapsed();
+            let mut http = HttpConnector::new_with_resolver(Resolved(addrs));
+            http.enforce_http(false);
+            http.set_local_address(source);
+            http.set_keepalive(keepalive);
+            let stream = http.call(uri).await?;
+            Ok(TimedStream {
+                stream,
+                phases: Phases {
+                    dns,
+                    tcp: started.elapsed() - dns,
+                },
+            })
+        })
+    }
+}
+
+/// Times every upstream connection by phase, lookup, tcp connect and tls
+/// handshake, into the metrics. Connections taking `slow_log` or longer are
+/// also logged with their breakdown.
+#[derive(Clone)]
+pub struct TimedConnector {
+    inner: HttpsConnector<PhaseConnector>,
+    slow_log: Option<Duration>,
+}
+
+impl TimedConnector {
+    pub fn new(inner: HttpsConnector<PhaseConnector>, slow_log: Option<Duration>) -> Self {
+        Self { inner, slow_log }
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+impl Service<Uri> for TimedConnector {
+    type Response = MaybeHttpsStream<TimedStream>;
+    type Error = BoxError;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<MaybeHttpsStream<TimedStream>, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let started = Instant::now();
+        let authority = uri.authority().map(|a| a.to_string()).unwrap_or_default();
+        let slow_log = self.slow_log;
+        let connecting = self.inner.call(uri);
+        Box::pin(async move {
+            let stream = connecting.await?;
+            let total = started.elapsed();
+            let (phases, tls) = match &stream {
+                MaybeHttpsStream::Http(s) => (s.phases, None),
+                MaybeHttpsStream::Https(s) => {
+                    let phases = s.get_ref().get_ref().get_ref().phases;
+                    (phases, Some(total.saturating_sub(phases.dns + phases.tcp)))
+                }
+            };
+            incr(&METRICS.upstream_connects);
+            add(&METRICS.upstream_dns_micros, micros(phases.dns));
+            add(&METRICS.upstream_tcp_connect_micros, micros(phases.tcp));
+            if let Some(tls) = tls {
+                incr(&METRICS.upstream_tls_handshakes);
+                add(&METRICS.upstream_tls_handshake_micros, micros(tls));
+            }
+            if slow_log.map(|slow| total >= slow).unwrap_or(false) {
+                log_info(&format!(
+                    "slow upstream connect to {} took {}ms: dns {}ms, tcp {}ms, tls {}",
+                    authority,
+                    total.as_millis(),
+                    phases.dns.as_millis(),
+                    phases.tcp.as_millis(),
+                    tls.map(|tls| format!("{}ms", tls.as_millis()))
+                        .unwrap_or_else(|| "-".to_string())
+                ));
+            }
+            Ok(stream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn connects_are_timed_by_phase() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let _ = listener.accept().await;
+            }
+        });
+
+        let mut phases = PhaseConnector::new(None, None);
+        let by_name = format!("http://localhost:{}/", port).parse().unwrap();
+        let stream = phases.call(by_name).await.unwrap();
+        assert!(stream.phases.dns > Duration::ZERO);
+        assert!(stream.phases.tcp > Duration::ZERO);
+
+        let connects = || METRICS.upstream_connects.load(Ordering::Relaxed);
+        let before = connects();
+        let mut timed = TimedConnector::new(HttpsConnector::new_with_connector(phases), None);
+        let uri = format!("http://127.0.0.1:{}/", port).parse().unwrap();
+        let stream = timed.call(uri).await.unwrap();
+        assert!(matches!(stream, MaybeHttpsStream::Http(_)));
+        assert!(connects() > before);
+    }
+}
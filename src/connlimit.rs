@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{self, IoSlice},
+    net::IpAddr,
+    pin::Pin,
+    sync::{LazyLock, Mutex},
+    task::{Context, Poll},
+};
+
+use axum_server::accept::Accept;
+use hyper::server::conn::AddrStream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    metrics::{incr, METRICS},
+    reload::{snapshot, SharedConfig},
+};
+
+/// Open client connections per ip, over all listeners.
+static CONNECTIONS: LazyLock<Mutex<HashMap<IpAddr, u32>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// One open connection of `ip`, given back on drop.
+pub struct ConnectionSlot {
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Takes a slot unless `ip` already holds `limit` connections. Without a
+/// limit connections are still counted so one set later by a reload sees
+/// those already open.
+fn acquire_connection_slot(ip: IpAddr, limit: Option<u32>) -> Option<ConnectionSlot> {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    let count = connections.entry(ip).or_insert(0);
+    if limit.map(|limit| *count >= limit).unwrap_or(false) {
+        return None;
+    }
+    *count += 1;
+    Some(ConnectionSlot { ip })
+}
+
+/// A client connection holding its ip's slot until it is closed.
+pub struct LimitedStream<S> {
+    inner: S,
+    _slot: ConnectionSlot,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for LimitedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for LimitedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Closes connections from an ip already holding `max_connections_per_ip`
+/// before `inner` sees them, so no TLS handshake is spent on them. The limit
+/// is read from the live config on each accept.
+#[derive(Clone)]
+pub struct ConnectionLimitAcceptor<A> {
+    inner: A,
+    shared: SharedConfig,
+}
+
+impl<A> ConnectionLimitAcceptor<A> {
+    pub fn new(inner: A, shared: SharedConfig) -> Self {
+        Self { inner, shared }
+    }
+}
+
+impl<S, A> Accept<AddrStream, S> for ConnectionLimitAcceptor<A>
+where
+    A: Accept<AddrStream, S>,
+    A::Future: Send + 'static,
+{
+    type Stream = LimitedStream<A::Stream>;
+    type Service = A::Service;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: AddrStream, service: S) -> Self::Future {
+        let limit = snapshot(&self.shared).max_connections_per_ip;
+        let slot = match acquire_connection_slot(stream.remote_addr().ip(), limit) {
+            Some(slot) => slot,
+            None => {
+                incr(&METRICS.client_connections_refused);
+                return Box::pin(async {
+                    Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        "too many connections from this ip",
+                    ))
+                });
+            }
+        };
+        let accepting = self.inner.accept(stream, service);
+        Box::pin(async move {
+            let (inner, service) = accepting.await?;
+            Ok((LimitedStream { inner, _slot: slot }, service))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(ip: IpAddr) -> Option<u32> {
+        CONNECTIONS.lock().unwrap().get(&ip).copied()
+    }
+
+    #[test]
+    fn connections_are_limited_per_ip_and_given_back() {
+        let ip: IpAddr = "192.0.2.57".parse().unwrap();
+        let other: IpAddr = "192.0.2.58".parse().unwrap();
+        let first = acquire_connection_slot(ip, Some(2)).unwrap();
+        let second = acquire_connection_slot(ip, Some(2)).unwrap();
+        assert!(acquire_connection_slot(ip, Some(2)).is_none());
+        let elsewhere = acquire_connection_slot(other, Some(2)).unwrap();
+
+        drop(first);
+        let third = acquire_connection_slot(ip, Some(2)).unwrap();
+        drop((second, third, elsewhere));
+        assert_eq!(open(ip), None);
+        assert_eq!(open(other), None);
+    }
+
+    #[test]
+    fn connections_are_counted_without_a_limit() {
+        let ip: IpAddr = "2001:db8::57".parse().unwrap();
+        let slots: Vec<_> = (0..3)
+            .map(|_| acquire_connection_slot(ip, None).unwrap())
+            .collect();
+        assert_eq!(open(ip), Some(3));
+        assert!(acquire_connection_slot(ip, Some(3)).is_none());
+        drop(slots);
+        assert_eq!(open(ip), None);
+    }
+}
@@ -0,0 +1,126 @@
+use std::{net::SocketAddr, time::Instant};
+
+use axum::extract::ConnectInfo;
+use hyper::{
+    header::{HeaderName, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE},
+    Body, HeaderMap, Request, Response,
+};
+use rand::Rng;
+
+use crate::{error::ProxyError, log::log_info};
+
+/// Never written out, they carry credentials.
+const MASKED: [HeaderName; 4] = [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE];
+
+/// Whether the value of `name` is masked, always for `MASKED` and for the
+/// names in `extra`, which come from `redact_headers`.
+pub fn is_redacted(name: &HeaderName, extra: &[String]) -> bool {
+    MASKED.contains(name)
+        || extra
+            .iter()
+            .any(|masked| name.as_str().eq_ignore_ascii_case(masked))
+}
+
+pub fn sampled(rate: f64) -> bool {
+    rate > 0.0 && rand::thread_rng().gen::<f64>() < rate
+}
+
+fn format_headers(out: &mut String, prefix: &str, headers: &HeaderMap, redact: &[String]) {
+    for (name, value) in headers {
+        let value = if is_redacted(name, redact) {
+            "[REDACTED]".into()
+        } else {
+            String::from_utf8_lossy(value.as_bytes())
+        };
+        out.push_str(&format!("\n{} {}: {}", prefix, name, value));
+    }
+}
+
+/// The request as the client sent it, kept until its outcome is known.
+pub struct DebugRecord {
+    started: Instant,
+    text: String,
+    redact: Vec<String>,
+}
+
+impl DebugRecord {
+    pub fn new<B>(req: &Request<B>, redact: Vec<String>) -> Self {
+        let client = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let mut text = format!(
+            "{} {} {:?} from {}",
+            req.method(),
+            req.uri(),
+            req.version(),
+            client
+        );
+        format_headers(&mut text, ">", req.headers(), &redact);
+        Self {
+            started: Instant::now(),
+            text,
+            redact,
+        }
+    }
+
+    /// Logs the request with the response head it got, or the error the
+    /// proxy answered with.
+    pub fn log(self, result: &Result<Response<Body>, ProxyError>) {
+        let mut text = self.text;
+        let elapsed = self.started.elapsed().as_millis();
+        match result {
+            Ok(res) => {
+                text.push_str(&format!("\n< {:?} {}", res.version(), res.status()));
+                format_headers(&mut text, "<", res.headers(), &self.redact);
+            }
+            Err(e) => text.push_str(&format!("\n< {} {}", e.status(), e)),
+        }
+        log_info(&format!(
+            "[debug] {}\nresponse head after {}ms",
+            text, elapsed
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling_follows_the_rate() {
+        assert!(!sampled(0.0));
+        assert!(sampled(1.0));
+    }
+
+    #[test]
+    fn records_the_request_line_and_headers() {
+        let mut req = Request::get("http://example.com/a?b=c")
+            .header("accept", "text/html")
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([192, 0, 2, 1], 4000))));
+        let record = DebugRecord::new(&req, Vec::new());
+        assert_eq!(
+            record.text,
+            "GET http://example.com/a?b=c HTTP/1.1 from 192.0.2.1:4000\n> accept: text/html"
+        );
+    }
+
+    #[test]
+    fn redacts_credentials_and_configured_headers() {
+        let req = Request::get("http://example.com/")
+            .header("cookie", "session=secret")
+            .header("x-api-key", "secret")
+            .header("x-request-id", "abc")
+            .body(())
+            .unwrap();
+        let record = DebugRecord::new(&req, vec!["X-Api-Key".to_string()]);
+        assert!(!record.text.contains("secret"), "{}", record.text);
+        assert!(record.text.contains("> cookie: [REDACTED]"));
+        assert!(record.text.contains("> x-api-key: [REDACTED]"));
+        assert!(record.text.contains("> x-request-id: abc"));
+    }
+}
@@ -0,0 +1,146 @@
+use serde_yaml::Value;
+
+use crate::{
+    balance::in_flight_counts,
+    config::Config,
+    health::health_report,
+    log::log_info,
+    metrics,
+    reload::{snapshot, SharedConfig},
+};
+
+/// Leaves out unset options, the dump only shows what is configured.
+fn drop_nulls(value: &mut Value) {
+    match value {
+        Value::Mapping(map) => {
+            map.retain(|_, v| !v.is_null());
+            map.iter_mut().for_each(|(_, v)| drop_nulls(v));
+        }
+        Value::Sequence(items) => items.iter_mut().for_each(drop_nulls),
+        _ => {}
+    }
+}
+
+/// The live config as yaml, inline keys blanked out.
+fn config_yaml(config: &Config) -> String {
+    let mut config = config.clone();
+    let redact = |key: &mut Option<String>| {
+        if key.is_some() {
+            *key = Some("[REDACTED]".to_string());
+        }
+    };
+    redact(&mut config.ssl_key);
+    for host in config.hosts.values_mut() {
+        redact(&mut host.ssl_key);
+    }
+    let mut value = match serde_yaml::to_value(&config) {
+        Ok(value) => value,
+        Err(e) => return format!("unserializable: {}\n", e),
+    };
+    drop_nulls(&mut value);
+    serde_yaml::to_string(&value).unwrap_or_else(|e| format!("unserializable: {}\n", e))
+}
+
+/// The live config, upstream health, requests in flight and all metrics.
+fn state_dump(config: &Config) -> String {
+    let mut out = String::from("[dump] config:\n");
+    out.push_str(&config_yaml(config));
+    out.push_str("[dump] upstream health:");
+    let health = health_report();
+    if health.is_empty() {
+        out.push_str(" none tracked yet");
+    }
+    for (upstream, failures, ejected) in health {
+        out.push_str(&format!(
+            "\n{} {} failures in a row{}",
+            upstream,
+            failures,
+            if ejected { ", ejected" } else { "" }
+        ));
+    }
+    out.push_str("\n[dump] requests in flight per upstream:");
+    let in_flight = in_flight_counts();
+    if in_flight.is_empty() {
+        out.push_str(" none");
+    }
+    for (upstream, count) in in_flight {
+        out.push_str(&format!("\n{} {}", upstream, count));
+    }
+    out.push_str("\n[dump] metrics:");
+    for line in metrics::render()
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+    {
+        out.push('\n');
+        out.push_str(line);
+    }
+    out
+}
+
+/// Logs the state dump in one entry.
+pub fn log_state_dump(shared: &SharedConfig) {
+    log_info(&state_dump(&snapshot(shared)));
+}
+
+/// Dumps the state on every SIGUSR1, for boxes without the admin port.
+#[cfg(unix)]
+pub fn spawn_dump_task(shared: SharedConfig) {
+    on_sigusr1(move || log_state_dump(&shared));
+}
+
+/// Runs `dump` for every SIGUSR1 from now on.
+#[cfg(unix)]
+fn on_sigusr1(mut dump: impl FnMut() + Send + 'static) {
+    use crate::log::log_error;
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+        Ok(sigusr1) => sigusr1,
+        Err(e) => {
+            log_error(&format!("failed to listen for SIGUSR1: {}", e));
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while sigusr1.recv().await.is_some() {
+            dump();
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_dump_task(_shared: SharedConfig) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_shows_the_config_without_keys() {
+        let config: Config = serde_yaml::from_str(
+            "ssl_key: secret-key\nhosts:\n  dump.test:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n",
+        )
+        .unwrap();
+        let dump = state_dump(&config);
+        assert!(dump.contains("dump.test:"), "{}", dump);
+        assert!(dump.contains("[REDACTED]"), "{}", dump);
+        assert!(!dump.contains("secret-key"), "{}", dump);
+        assert!(!dump.contains("null"), "{}", dump);
+        assert!(dump.contains("[dump] metrics:\nreverse_proxy_"), "{}", dump);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sigusr1_dumps_instead_of_terminating() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        on_sigusr1(move || {
+            let _ = tx.send(());
+        });
+        let sent = std::process::Command::new("kill")
+            .args(["-USR1", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(sent.success());
+        let dumped = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv()).await;
+        assert_eq!(dumped, Ok(Some(())));
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt;
+
+use axum::response::{IntoResponse, Response};
+use hyper::{
+    header::{HeaderValue, RETRY_AFTER},
+    Method, StatusCode,
+};
+
+use crate::upstream::UpstreamError;
+
+/// Why the proxy answered a request itself instead of with an upstream
+/// response. The status comes from `status`, the body is the `Display` text.
+#[derive(Debug)]
+pub enum ProxyError {
+    RateLimited,
+    MethodNotAllowed(Method),
+    AbsoluteFormRejected,
+    EmptyHost,
+    /// The path and query are over `max_uri_length`.
+    UriTooLong,
+    MissingHost,
+    UnknownHost,
+    /// The health check has ejected every upstream of the host.
+    NoHealthyUpstream,
+    /// `max_ws_connections` reached, globally or for the host.
+    TooManyWebSockets,
+    InvalidUpstreamUri(String),
+    /// The body matched a `body_inspection` deny pattern.
+    BodyDenied,
+    /// The body is over the `body_inspection` buffer.
+    BodyTooLarge,
+    /// A request other than GET or HEAD to a `require_https` host over
+    /// plain http.
+    HttpsRequired,
+    /// Still over `upstream_header_limit` after stripping.
+    HeadersTooLarge,
+    UpstreamTimeout(UpstreamError),
+    /// The upstream's answer was not valid HTTP.
+    UpstreamInvalidResponse(UpstreamError),
+    /// The upstream's response head was over `max_response_header_bytes`.
+    UpstreamHeadersTooLarge(UpstreamError),
+    /// A 206 from the upstream of a host with `range_requests` off.
+    UnexpectedPartialContent,
+    /// Any other upstream failure, while connecting or later.
+    UpstreamFailed(UpstreamError),
+    /// A 502, 503 or 504 sent with the host's `error_retry_after_secs`.
+    RetryAfter(Box<ProxyError>, u64),
+}
+
+impl ProxyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ProxyError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ProxyError::AbsoluteFormRejected | ProxyError::EmptyHost => StatusCode::BAD_REQUEST,
+            ProxyError::UriTooLong => StatusCode::URI_TOO_LONG,
+            ProxyError::MissingHost | ProxyError::UnknownHost => StatusCode::FAILED_DEPENDENCY,
+            ProxyError::NoHealthyUpstream | ProxyError::TooManyWebSockets => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ProxyError::InvalidUpstreamUri(_)
+            | ProxyError::UpstreamInvalidResponse(_)
+            | ProxyError::UpstreamHeadersTooLarge(_)
+            | ProxyError::UnexpectedPartialContent
+            | ProxyError::UpstreamFailed(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::BodyDenied | ProxyError::HttpsRequired => StatusCode::FORBIDDEN,
+            ProxyError::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ProxyError::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ProxyError::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::RetryAfter(e, _) => e.status(),
+        }
+    }
+}
+
+impl From<UpstreamError> for ProxyError {
+    fn from(e: UpstreamError) -> Self {
+        if e.is_timeout() {
+            ProxyError::UpstreamTimeout(e)
+        } else if e.is_headers_too_large() {
+            ProxyError::UpstreamHeadersTooLarge(e)
+        } else if e.is_invalid_response() {
+            ProxyError::UpstreamInvalidResponse(e)
+        } else {
+            ProxyError::UpstreamFailed(e)
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::RateLimited => write!(f, "Too many requests"),
+            ProxyError::MethodNotAllowed(method) => write!(f, "Method {} is not allowed", method),
+            ProxyError::AbsoluteFormRejected => {
+                write!(f, "Absolute-form request targets are not accepted")
+            }
+            ProxyError::EmptyHost => write!(f, "The `Host` header is empty"),
+            ProxyError::UriTooLong => write!(f, "Request uri is too long"),
+            ProxyError::MissingHost => write!(f, "The `Host` does not exist in the headers"),
+            ProxyError::UnknownHost => write!(f, "Unkown `Host` in the headers"),
+            ProxyError::NoHealthyUpstream => write!(f, "Upstream is unhealthy"),
+            ProxyError::TooManyWebSockets => write!(f, "Too many websocket connections"),
+            ProxyError::InvalidUpstreamUri(e) => write!(f, "Invalid upstream uri: {}", e),
+            ProxyError::BodyDenied => write!(f, "Request body is not allowed"),
+            ProxyError::BodyTooLarge => write!(f, "Request body is too large"),
+            ProxyError::HttpsRequired => write!(f, "This host is only served over https"),
+            ProxyError::HeadersTooLarge => write!(f, "Request headers are too large"),
+            ProxyError::UpstreamHeadersTooLarge(_) => {
+                write!(f, "Upstream response headers are too large")
+            }
+            ProxyError::UnexpectedPartialContent => write!(f, "Upstream sent a partial response"),
+            ProxyError::UpstreamTimeout(e)
+            | ProxyError::UpstreamInvalidResponse(e)
+            | ProxyError::UpstreamFailed(e) => {
+                write!(f, "Upstream request failed: {}", e)
+            }
+            ProxyError::RetryAfter(e, _) => e.fmt(f),
+        }
+    }
+}
+
+impl IntoResponse for ProxyError {
+    fn into_response(self) -> Response {
+        let mut res = (self.status(), self.to_string()).into_response();
+        if let ProxyError::RetryAfter(_, secs) = self {
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn upstream_errors_map_to_gateway_statuses() {
+        let timeout: ProxyError = UpstreamError::Timeout.into();
+        assert!(matches!(timeout, ProxyError::UpstreamTimeout(_)));
+        assert_eq!(timeout.status(), StatusCode::GATEWAY_TIMEOUT);
+        let shared: ProxyError = UpstreamError::Shared(Arc::new(UpstreamError::Timeout)).into();
+        assert_eq!(shared.status(), StatusCode::GATEWAY_TIMEOUT);
+        let abandoned: ProxyError = UpstreamError::Abandoned.into();
+        assert!(matches!(abandoned, ProxyError::UpstreamFailed(_)));
+        assert_eq!(abandoned.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn proxy_errors_have_their_own_status() {
+        for (e, status) in [
+            (ProxyError::RateLimited, StatusCode::TOO_MANY_REQUESTS),
+            (
+                ProxyError::MethodNotAllowed(Method::TRACE),
+                StatusCode::METHOD_NOT_ALLOWED,
+            ),
+            (ProxyError::EmptyHost, StatusCode::BAD_REQUEST),
+            (ProxyError::UriTooLong, StatusCode::URI_TOO_LONG),
+            (ProxyError::UnknownHost, StatusCode::FAILED_DEPENDENCY),
+            (
+                ProxyError::TooManyWebSockets,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (ProxyError::BodyTooLarge, StatusCode::PAYLOAD_TOO_LARGE),
+            (ProxyError::HttpsRequired, StatusCode::FORBIDDEN),
+            (
+                ProxyError::HeadersTooLarge,
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ),
+        ] {
+            assert_eq!(e.status(), status, "{}", e);
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_after_keeps_the_status_and_adds_the_header() {
+        let e = ProxyError::RetryAfter(Box::new(ProxyError::NoHealthyUpstream), 7);
+        assert_eq!(e.to_string(), "Upstream is unhealthy");
+        let res = e.into_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[RETRY_AFTER], "7");
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "Upstream is unhealthy");
+    }
+}
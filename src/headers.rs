@@ -0,0 +1,768 @@
+use std::time::Duration;
+
+use hyper::{
+    header::{
+        HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, FORWARDED,
+        TRANSFER_ENCODING, UPGRADE, VIA,
+    },
+    HeaderMap, Request, Response, Version,
+};
+
+use crate::config::SecurityHeaders;
+
+fn protocol_version(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
+
+/// Appends `<version> <pseudonym>` to the `Via` chain (RFC 7230 5.7.1),
+/// keeping the entries of earlier proxies in front.
+pub fn append_via(headers: &mut HeaderMap, version: Version, pseudonym: &str) {
+    let entry = format!("{} {}", protocol_version(version), pseudonym);
+    let mut chain: Vec<String> = headers
+        .get_all(VIA)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+        .collect();
+    chain.push(entry);
+    if let Ok(value) = HeaderValue::from_str(&chain.join(", ")) {
+        headers.insert(VIA, value);
+    }
+}
+
+/// Adds `; charset=<charset>` to a `text/*` content type that has none.
+pub fn ensure_charset(headers: &mut HeaderMap, charset: &str) {
+    let content_type = match headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        Some(content_type) => content_type,
+        None => return,
+    };
+    let lower = content_type.to_ascii_lowercase();
+    if !lower.trim_start().starts_with("text/") || lower.contains("charset=") {
+        return;
+    }
+    let value = format!(
+        "{}; charset={}",
+        content_type.trim_end().trim_end_matches(';'),
+        charset
+    );
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(CONTENT_TYPE, value);
+    }
+}
+
+/// Makes the upstream believe it is served over https no matter how the
+/// client reached the proxy, so it builds `https://` links and redirects.
+/// The `Host` header is forwarded as received. `Forwarded` gets an element
+/// for this hop appended, the ones from earlier proxies are kept.
+pub fn mark_behind_https(headers: &mut HeaderMap, host: &str, port: u16) {
+    let set = |headers: &mut HeaderMap, name: &'static str, value: &str| {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    };
+    set(headers, "x-forwarded-proto", "https");
+    set(headers, "x-forwarded-ssl", "on");
+    set(headers, "x-forwarded-host", host);
+    set(headers, "x-forwarded-port", &port.to_string());
+    let mut elements: Vec<&str> = headers
+        .get_all(FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .collect();
+    let own = format!("proto=https;host=\"{}\"", host);
+    elements.push(&own);
+    if let Ok(value) = HeaderValue::from_str(&elements.join(", ")) {
+        headers.insert(FORWARDED, value);
+    }
+}
+
+/// Media types from an `Accept` header ordered by preference: highest `q`
+/// first, ties kept in header order, `q=0` dropped.
+pub fn preferred_media_types(accept: &str) -> Vec<String> {
+    let mut types: Vec<(String, f32)> = accept
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let media_type = parts.next()?.trim().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .filter_map(|q| q.trim().parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+            if media_type.is_empty() || q <= 0.0 {
+                None
+            } else {
+                Some((media_type, q))
+            }
+        })
+        .collect();
+    types.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    types
+        .into_iter()
+        .map(|(media_type, _)| media_type)
+        .collect()
+}
+
+/// HTTP/1.0 connection management headers, they describe the client's hop
+/// only and must not reach the upstream or come back from it.
+fn remove_http10_connection_headers(headers: &mut HeaderMap) {
+    headers.remove(CONNECTION);
+    headers.remove(HeaderName::from_static("keep-alive"));
+    headers.remove(HeaderName::from_static("proxy-connection"));
+}
+
+/// The lowercased tokens listed in `Connection`.
+fn connection_tokens(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+/// Whether an HTTP/1.1 client asks with `Connection: close` to close its
+/// connection after this request.
+pub fn asks_to_close(headers: &HeaderMap) -> bool {
+    connection_tokens(headers)
+        .iter()
+        .any(|token| token == "close")
+}
+
+/// Takes `close` out of the client's `Connection` so that only the client
+/// connection is closed and the upstream one goes back to the pool.
+pub fn drop_close_token(headers: &mut HeaderMap) {
+    let kept: Vec<String> = connection_tokens(headers)
+        .into_iter()
+        .filter(|token| token != "close")
+        .collect();
+    headers.remove(CONNECTION);
+    if kept.is_empty() {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(&kept.join(", ")) {
+        headers.insert(CONNECTION, value);
+    }
+}
+
+/// The upstream's `Connection`, `Keep-Alive` and the headers `Connection`
+/// names describe its hop to the proxy, they are replaced by what holds for
+/// the client's: `Connection: close` when the client asked for it.
+pub fn reset_connection_headers(headers: &mut HeaderMap, close: bool) {
+    for token in connection_tokens(headers) {
+        if let Ok(name) = HeaderName::from_bytes(token.as_bytes()) {
+            headers.remove(name);
+        }
+    }
+    headers.remove(CONNECTION);
+    headers.remove(HeaderName::from_static("keep-alive"));
+    if close {
+        headers.insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+}
+
+/// Forwards an HTTP/1.0 client request as HTTP/1.1 so the upstream connection
+/// is managed the usual way.
+pub fn upgrade_from_http10<B>(req: &mut Request<B>) {
+    *req.version_mut() = Version::HTTP_11;
+    remove_http10_connection_headers(req.headers_mut());
+}
+
+/// Answers an HTTP/1.0 client in its own version. Chunked encoding does not
+/// exist in 1.0, without it the server falls back to `Content-Length` or
+/// closing the connection to delimit the body.
+pub fn downgrade_to_http10<B>(res: &mut Response<B>) {
+    *res.version_mut() = Version::HTTP_10;
+    res.headers_mut().remove(TRANSFER_ENCODING);
+    remove_http10_connection_headers(res.headers_mut());
+}
+
+/// Size of the headers as sent over http/1.1, `name: value\r\n` each.
+fn header_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum()
+}
+
+/// Drops the cookie `name` from every `Cookie` header, and the header itself
+/// once no cookie is left in it.
+fn remove_cookie(headers: &mut HeaderMap, name: &str) {
+    let values: Vec<String> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(';')
+                .map(str::trim)
+                .filter(|pair| pair.split('=').next().map(str::trim) != Some(name))
+                .collect::<Vec<_>>()
+                .join("; ")
+        })
+        .collect();
+    headers.remove(COOKIE);
+    for value in values.iter().filter(|v| !v.is_empty()) {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.append(COOKIE, value);
+        }
+    }
+}
+
+/// Removes the `strip` entries in order until the headers fit in
+/// `max_bytes`. An entry is a header name or `cookie:<name>` for a single
+/// cookie. Returns whether the headers fit.
+pub fn trim_headers(headers: &mut HeaderMap, max_bytes: usize, strip: &[String]) -> bool {
+    for entry in strip {
+        if header_size(headers) <= max_bytes {
+            return true;
+        }
+        match entry.strip_prefix("cookie:") {
+            Some(cookie) => remove_cookie(headers, cookie),
+            None => {
+                headers.remove(entry.as_str());
+            }
+        }
+    }
+    header_size(headers) <= max_bytes
+}
+
+/// Why the body framing of a request is ambiguous, `None` when it is not.
+/// Proxies that disagree on where such a body ends are what request
+/// smuggling exploits.
+///
+/// This sees the headers after hyper parsed them. On http/1 hyper already
+/// answers conflicting lengths and non-chunked codings with a 400, drops a
+/// `Content-Length` that follows `Transfer-Encoding` and merges repeats of
+/// the same length, so those reach the upstream with a single framing and
+/// cannot be told apart here. Repeated lengths still arrive over http/2.
+pub fn ambiguous_framing(headers: &HeaderMap) -> Option<&'static str> {
+    let lengths: Vec<&HeaderValue> = headers.get_all(CONTENT_LENGTH).iter().collect();
+    if !lengths.is_empty() && headers.contains_key(TRANSFER_ENCODING) {
+        return Some("both Content-Length and Transfer-Encoding are set");
+    }
+    if lengths.len() > 1 {
+        return Some("Content-Length is set more than once");
+    }
+    let encodings: Vec<&HeaderValue> = headers.get_all(TRANSFER_ENCODING).iter().collect();
+    if encodings.len() > 1 {
+        return Some("Transfer-Encoding is set more than once");
+    }
+    if let Some(encoding) = encodings.first() {
+        // Codings are compared ignoring case and the spaces and tabs around
+        // them, so `chunked` with anything else attached is not chunked.
+        let ows = |b: &u8| *b == b' ' || *b == b'\t';
+        let codings: Vec<&[u8]> = encoding
+            .as_bytes()
+            .split(|b| *b == b',')
+            .map(|coding| {
+                let start = coding.iter().position(|b| !ows(b)).unwrap_or(coding.len());
+                let end = coding
+                    .iter()
+                    .rposition(|b| !ows(b))
+                    .map_or(start, |i| i + 1);
+                &coding[start..end]
+            })
+            .collect();
+        let chunked = |coding: &&[u8]| coding.eq_ignore_ascii_case(b"chunked");
+        let (last, rest) = codings.split_last().unwrap();
+        if !chunked(last) {
+            return Some("Transfer-Encoding does not end with chunked");
+        }
+        if rest.iter().any(chunked) {
+            return Some("Transfer-Encoding applies chunked more than once");
+        }
+    }
+    let invalid = lengths
+        .iter()
+        .any(|v| v.is_empty() || !v.as_bytes().iter().all(u8::is_ascii_digit));
+    if invalid {
+        return Some("Content-Length is not a number");
+    }
+    None
+}
+
+/// `grpc-timeout` gets gRPC's own encoding, any other header whole
+/// milliseconds.
+fn is_grpc_timeout(name: &HeaderName) -> bool {
+    name.as_str() == "grpc-timeout"
+}
+
+/// Reads a time budget in the format `set_deadline_header` writes.
+fn parse_budget(name: &HeaderName, value: &str) -> Option<Duration> {
+    if !is_grpc_timeout(name) {
+        return value.trim().parse().ok().map(Duration::from_millis);
+    }
+    let value = value.trim();
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount.saturating_mul(3600)),
+        "M" => Duration::from_secs(amount.saturating_mul(60)),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// Tells the upstream how much time is left for the request under `name`,
+/// unless the client already asked for less.
+pub fn set_deadline_header(headers: &mut HeaderMap, name: &HeaderName, remaining: Duration) {
+    let shorter = headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_budget(name, v))
+        .map(|asked| asked <= remaining)
+        .unwrap_or(false);
+    if shorter {
+        return;
+    }
+    let millis = remaining.as_millis();
+    let value = if !is_grpc_timeout(name) {
+        millis.to_string()
+    } else if millis < 100_000_000 {
+        // gRPC allows at most 8 digits.
+        format!("{}m", millis)
+    } else {
+        format!("{}S", remaining.as_secs().min(99_999_999))
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(name.clone(), value);
+    }
+}
+
+/// The protocol an HTTP/1.1 request asks to switch to, when it names one in
+/// `Upgrade` and lists `upgrade` in `Connection`.
+pub fn upgrade_protocol(headers: &HeaderMap) -> Option<String> {
+    let upgrade_requested = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    if !upgrade_requested {
+        return None;
+    }
+    headers
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Longest client supplied request id that is passed on as it is.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// The request's id under `name`: the client's own when it sent a usable
+/// one, up to 128 visible ascii characters, otherwise a new random one that
+/// replaces whatever was there.
+pub fn ensure_request_id(headers: &mut HeaderMap, name: &HeaderName) -> HeaderValue {
+    let usable = headers.get(name).filter(|id| {
+        let id = id.as_bytes();
+        !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LENGTH
+            && id.iter().all(|b| b.is_ascii_graphic())
+    });
+    if let Some(id) = usable {
+        return id.clone();
+    }
+    let id = HeaderValue::from_str(&format!("{:032x}", rand::random::<u128>()))
+        .expect("hex is a valid header value");
+    headers.insert(name.clone(), id.clone());
+    id
+}
+
+/// Turns an `Upgrade: h2c` request back into a plain HTTP/1.1 one, the way
+/// a server without h2c ignores the upgrade (RFC 7540 3.2): `Upgrade`,
+/// `HTTP2-Settings` and their `Connection` tokens are removed.
+pub fn drop_h2c_upgrade(headers: &mut HeaderMap) {
+    let settings = HeaderName::from_static("http2-settings");
+    let kept: Vec<String> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|token| {
+            !token.is_empty()
+                && !token.eq_ignore_ascii_case("upgrade")
+                && !token.eq_ignore_ascii_case(settings.as_str())
+        })
+        .map(str::to_string)
+        .collect();
+    headers.remove(UPGRADE);
+    headers.remove(&settings);
+    headers.remove(CONNECTION);
+    if kept.is_empty() {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(&kept.join(", ")) {
+        headers.insert(CONNECTION, value);
+    }
+}
+
+/// Sets the security header bundle in a fixed order, replacing whatever the
+/// upstream sent for the same headers. Headers the bundle has no value for,
+/// a CSP or Permissions-Policy that is not configured or HSTS without
+/// `hsts_max_age_secs`, keep the upstream's.
+pub fn apply_security_headers(headers: &mut HeaderMap, bundle: &SecurityHeaders, tls: bool) {
+    let or_default = |value: &Option<String>, default: &str| {
+        Some(value.clone().unwrap_or_else(|| default.to_string()))
+    };
+    let bundled = [
+        ("x-content-type-options", Some("nosniff".to_string())),
+        ("x-frame-options", or_default(&bundle.frame_options, "DENY")),
+        (
+            "referrer-policy",
+            or_default(&bundle.referrer_policy, "strict-origin-when-cross-origin"),
+        ),
+        (
+            "content-security-policy",
+            bundle.content_security_policy.clone(),
+        ),
+        ("permissions-policy", bundle.permissions_policy.clone()),
+        (
+            "strict-transport-security",
+            // Browsers ignore HSTS over plain http, so none is sent there.
+            bundle.hsts_max_age_secs.map(|max_age| {
+                if tls {
+                    format!("max-age={}", max_age)
+                } else {
+                    String::new()
+                }
+            }),
+        ),
+    ];
+    for (name, value) in bundled {
+        let value = match value {
+            Some(value) => value,
+            None => continue,
+        };
+        let name = HeaderName::from_static(name);
+        headers.remove(&name);
+        if value.is_empty() {
+            continue;
+        }
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn via_appends_to_the_chain() {
+        let mut headers = HeaderMap::new();
+        append_via(&mut headers, Version::HTTP_10, "edge");
+        assert_eq!(headers[VIA], "1.0 edge");
+
+        let mut headers = HeaderMap::new();
+        headers.append(VIA, HeaderValue::from_static("1.1 cdn"));
+        headers.append(VIA, HeaderValue::from_static("2 lb"));
+        append_via(&mut headers, Version::HTTP_2, "edge");
+        assert_eq!(
+            headers.get_all(VIA).iter().collect::<Vec<_>>(),
+            ["1.1 cdn, 2 lb, 2 edge"]
+        );
+    }
+
+    fn content_type(value: &'static str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(value));
+        ensure_charset(&mut headers, "utf-8");
+        headers[CONTENT_TYPE].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn charset_is_added_to_text_types_without_one() {
+        assert_eq!(content_type("text/html"), "text/html; charset=utf-8");
+        assert_eq!(content_type("text/plain;"), "text/plain; charset=utf-8");
+        assert_eq!(
+            content_type("text/html; Charset=latin1"),
+            "text/html; Charset=latin1"
+        );
+        assert_eq!(content_type("application/json"), "application/json");
+
+        let mut headers = HeaderMap::new();
+        ensure_charset(&mut headers, "utf-8");
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn media_types_by_preference() {
+        assert_eq!(
+            preferred_media_types("text/html;q=0.5, Application/JSON, */*;q=0.1, image/png;q=0"),
+            ["application/json", "text/html", "*/*"]
+        );
+        assert_eq!(
+            preferred_media_types("a/b;q=0.8, c/d; q=0.8 ,e/f;level=1"),
+            ["e/f", "a/b", "c/d"]
+        );
+        assert!(preferred_media_types("").is_empty());
+    }
+
+    #[test]
+    fn http10_connection_headers_stay_on_the_client_hop() {
+        let mut req = Request::get("/")
+            .version(Version::HTTP_10)
+            .header(CONNECTION, "keep-alive")
+            .header("keep-alive", "timeout=5")
+            .header("proxy-connection", "keep-alive")
+            .body(())
+            .unwrap();
+        upgrade_from_http10(&mut req);
+        assert_eq!(req.version(), Version::HTTP_11);
+        assert!(req.headers().is_empty(), "{:?}", req.headers());
+
+        let mut res = Response::builder()
+            .header(TRANSFER_ENCODING, "chunked")
+            .header(CONNECTION, "keep-alive")
+            .header(CONTENT_TYPE, "text/plain")
+            .body(())
+            .unwrap();
+        downgrade_to_http10(&mut res);
+        assert_eq!(res.version(), Version::HTTP_10);
+        assert_eq!(res.headers().len(), 1);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+    }
+
+    #[test]
+    fn behind_https_appends_to_forwarded() {
+        let mut headers = HeaderMap::new();
+        mark_behind_https(&mut headers, "a.com", 443);
+        assert_eq!(headers[FORWARDED], "proto=https;host=\"a.com\"");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-port"], "443");
+
+        let mut headers = HeaderMap::new();
+        headers.append(FORWARDED, HeaderValue::from_static("for=192.0.2.1"));
+        headers.append(
+            FORWARDED,
+            HeaderValue::from_static("for=192.0.2.2;proto=http"),
+        );
+        mark_behind_https(&mut headers, "a.com", 8443);
+        assert_eq!(
+            headers.get_all(FORWARDED).iter().collect::<Vec<_>>(),
+            ["for=192.0.2.1, for=192.0.2.2;proto=http, proto=https;host=\"a.com\""]
+        );
+    }
+
+    #[test]
+    fn trimming_strips_entries_in_order_until_headers_fit() {
+        let oversized = || {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-debug", HeaderValue::from_static("0123456789"));
+            headers.append(
+                COOKIE,
+                HeaderValue::from_static("session=abc; tracking=0123456789"),
+            );
+            headers.append(COOKIE, HeaderValue::from_static("tracking=x"));
+            headers
+        };
+        let strip = ["cookie:tracking".to_string(), "x-debug".to_string()];
+        let size = header_size(&oversized());
+
+        let mut headers = oversized();
+        assert!(trim_headers(&mut headers, size, &strip));
+        assert_eq!(headers.len(), 3, "already fits, nothing stripped");
+
+        let mut headers = oversized();
+        assert!(trim_headers(&mut headers, size - 20, &strip));
+        assert_eq!(
+            headers.get_all(COOKIE).iter().collect::<Vec<_>>(),
+            ["session=abc"]
+        );
+        assert!(headers.contains_key("x-debug"));
+
+        let mut headers = oversized();
+        assert!(!trim_headers(&mut headers, 10, &strip));
+        assert!(!headers.contains_key("x-debug"));
+    }
+
+    fn framing(headers: &[(&str, &[u8])]) -> Option<&'static str> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_bytes(value).unwrap(),
+            );
+        }
+        ambiguous_framing(&map)
+    }
+
+    #[test]
+    fn chunked_last_is_accepted() {
+        for value in [
+            &b"chunked"[..],
+            b"Chunked",
+            b" chunked\t",
+            b"gzip, CHUNKED",
+            b"gzip,chunked",
+        ] {
+            assert_eq!(
+                framing(&[("transfer-encoding", value)]),
+                None,
+                "{:?}",
+                value
+            );
+        }
+        assert_eq!(framing(&[("content-length", b"12")]), None);
+        assert_eq!(framing(&[]), None);
+    }
+
+    #[test]
+    fn other_transfer_encodings_are_rejected() {
+        for value in [
+            &b"gzip"[..],
+            b"chunked, gzip",
+            b"chunked, chunked",
+            b"chunked,",
+            b"xchunked",
+            b"identity",
+        ] {
+            assert!(
+                framing(&[("transfer-encoding", value)]).is_some(),
+                "{:?}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn repeated_framing_headers_are_rejected() {
+        let e = framing(&[
+            ("transfer-encoding", b"chunked"),
+            ("transfer-encoding", b"chunked"),
+        ]);
+        assert_eq!(e, Some("Transfer-Encoding is set more than once"));
+        let e = framing(&[("content-length", b"1"), ("content-length", b"1")]);
+        assert_eq!(e, Some("Content-Length is set more than once"));
+        let e = framing(&[("content-length", b"1"), ("transfer-encoding", b"chunked")]);
+        assert_eq!(e, Some("both Content-Length and Transfer-Encoding are set"));
+        assert!(framing(&[("content-length", b"+1")]).is_some());
+    }
+
+    fn bundle(yaml: &str) -> SecurityHeaders {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn upstream_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "content-security-policy",
+            HeaderValue::from_static("default-src 'self'"),
+        );
+        headers.insert(
+            "strict-transport-security",
+            HeaderValue::from_static("max-age=60"),
+        );
+        headers.insert("x-frame-options", HeaderValue::from_static("ALLOWALL"));
+        headers
+    }
+
+    #[test]
+    fn unconfigured_bundle_headers_keep_the_upstream_ones() {
+        let mut headers = upstream_headers();
+        apply_security_headers(&mut headers, &bundle("{}"), true);
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+        assert_eq!(headers["x-frame-options"], "DENY");
+        assert_eq!(
+            headers["referrer-policy"],
+            "strict-origin-when-cross-origin"
+        );
+        assert_eq!(headers["content-security-policy"], "default-src 'self'");
+        assert_eq!(headers["strict-transport-security"], "max-age=60");
+        assert!(!headers.contains_key("permissions-policy"));
+    }
+
+    #[test]
+    fn configured_bundle_headers_replace_the_upstream_ones() {
+        let mut headers = upstream_headers();
+        let configured = bundle(
+            "frame_options: ''\ncontent_security_policy: default-src 'none'\nhsts_max_age_secs: 3600",
+        );
+        apply_security_headers(&mut headers, &configured, true);
+        assert!(!headers.contains_key("x-frame-options"));
+        assert_eq!(headers["content-security-policy"], "default-src 'none'");
+        assert_eq!(headers["strict-transport-security"], "max-age=3600");
+
+        let mut headers = upstream_headers();
+        apply_security_headers(&mut headers, &configured, false);
+        assert!(!headers.contains_key("strict-transport-security"));
+    }
+
+    fn deadline(name: &'static str, asked: Option<&'static str>, remaining: Duration) -> String {
+        let name = HeaderName::from_static(name);
+        let mut headers = HeaderMap::new();
+        if let Some(asked) = asked {
+            headers.insert(name.clone(), HeaderValue::from_static(asked));
+        }
+        set_deadline_header(&mut headers, &name, remaining);
+        headers[&name].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn deadline_header_carries_the_remaining_budget() {
+        let second = Duration::from_secs(1);
+        assert_eq!(deadline("x-request-timeout", None, second), "1000");
+        assert_eq!(deadline("x-request-timeout", Some("250"), second), "250");
+        assert_eq!(deadline("x-request-timeout", Some("5000"), second), "1000");
+        assert_eq!(deadline("x-request-timeout", Some("soon"), second), "1000");
+        assert_eq!(deadline("grpc-timeout", None, second), "1000m");
+        assert_eq!(deadline("grpc-timeout", Some("2S"), second), "1000m");
+        assert_eq!(deadline("grpc-timeout", Some("500000u"), second), "500000u");
+        let days = Duration::from_secs(200_000);
+        assert_eq!(deadline("grpc-timeout", None, days), "200000S");
+    }
+
+    #[test]
+    fn grpc_timeouts_parse_every_unit() {
+        let grpc = HeaderName::from_static("grpc-timeout");
+        for (value, expected) in [
+            ("1H", Duration::from_secs(3600)),
+            ("2M", Duration::from_secs(120)),
+            ("3S", Duration::from_secs(3)),
+            ("4m", Duration::from_millis(4)),
+            ("5u", Duration::from_micros(5)),
+            ("6n", Duration::from_nanos(6)),
+        ] {
+            assert_eq!(parse_budget(&grpc, value), Some(expected), "{}", value);
+        }
+        for value in ["", "5", "5s", "-1S", "S"] {
+            assert_eq!(parse_budget(&grpc, value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn ignored_h2c_upgrades_keep_other_connection_tokens() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONNECTION,
+            HeaderValue::from_static("Upgrade, HTTP2-Settings, x-hop"),
+        );
+        headers.insert(UPGRADE, HeaderValue::from_static("h2c"));
+        headers.insert(
+            "http2-settings",
+            HeaderValue::from_static("AAMAAABkAAQAAP__"),
+        );
+        assert_eq!(upgrade_protocol(&headers).as_deref(), Some("h2c"));
+        drop_h2c_upgrade(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[CONNECTION], "x-hop");
+        assert_eq!(upgrade_protocol(&headers), None);
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::future::join_all;
+use hyper::{body::HttpBody, header::HOST, Body, Request, Version};
+
+use crate::{
+    config::{HealthCheck, HealthProbe, Target, UpstreamVersion},
+    log::{log_error, log_info},
+    reload::{snapshot, SharedConfig},
+    upstream::{client_for, HttpClient, UpstreamClient},
+};
+
+/// Bytes of a probe answer read for `expected_body_contains`, the rest is
+/// not looked at.
+const MAX_PROBE_BODY: usize = 64 * 1024;
+
+struct UpstreamHealth {
+    consecutive_failures: u32,
+    unhealthy_until: Option<Instant>,
+    last_seen: Instant,
+}
+
+/// Passive health per upstream, keyed by `ip:port`.
+static HEALTH: LazyLock<Mutex<HashMap<String, UpstreamHealth>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn is_healthy(upstream: &str) -> bool {
+    let health = HEALTH.lock().unwrap();
+    match health.get(upstream).and_then(|h| h.unhealthy_until) {
+        Some(until) => Instant::now() >= until,
+        None => true,
+    }
+}
+
+pub fn mark_success(upstream: &str) {
+    let mut health = HEALTH.lock().unwrap();
+    let entry = health
+        .entry(upstream.to_string())
+        .or_insert(UpstreamHealth {
+            consecutive_failures: 0,
+            unhealthy_until: None,
+            last_seen: Instant::now(),
+        });
+    entry.consecutive_failures = 0;
+    entry.unhealthy_until = None;
+    entry.last_seen = Instant::now();
+}
+
+/// Counts a failed request, after `max_failures` in a row the upstream is
+/// taken out for `cooldown_secs`.
+pub fn mark_failure(upstream: &str, check: &HealthCheck) {
+    let mut health = HEALTH.lock().unwrap();
+    let entry = health
+        .entry(upstream.to_string())
+        .or_insert(UpstreamHealth {
+            consecutive_failures: 0,
+            unhealthy_until: None,
+            last_seen: Instant::now(),
+        });
+    entry.consecutive_failures += 1;
+    entry.last_seen = Instant::now();
+    if entry.consecutive_failures >= check.max_failures.unwrap_or(3) {
+        entry.unhealthy_until =
+            Some(Instant::now() + Duration::from_secs(check.cooldown_secs.unwrap_or(10)));
+    }
+}
+
+/// Failures in a row and whether the upstream is ejected right now, per
+/// upstream seen so far.
+pub fn health_report() -> Vec<(String, u32, bool)> {
+    let now = Instant::now();
+    let health = HEALTH.lock().unwrap();
+    let mut report: Vec<(String, u32, bool)> = health
+        .iter()
+        .map(|(upstream, h)| {
+            let ejected = h.unhealthy_until.map(|until| until > now).unwrap_or(false);
+            (upstream.clone(), h.consecutive_failures, ejected)
+        })
+        .collect();
+    report.sort();
+    report
+}
+
+/// Drops entries not seen for `idle` unless the upstream is still ejected.
+pub fn prune_health(idle: Duration) -> usize {
+    prune_idle(&mut HEALTH.lock().unwrap(), idle)
+}
+
+fn prune_idle(health: &mut HashMap<String, UpstreamHealth>, idle: Duration) -> usize {
+    let now = Instant::now();
+    let before = health.len();
+    health.retain(|_, h| {
+        h.last_seen.elapsed() < idle || h.unhealthy_until.map(|until| until > now).unwrap_or(false)
+    });
+    before - health.len()
+}
+
+/// Checks the upstream's answer to `probe.path` against the expected status
+/// and the start of the body.
+async fn probe_upstream(
+    client: &UpstreamClient,
+    version: Version,
+    target: &Target,
+    host_header: &str,
+    probe: &HealthProbe,
+) -> Result<(), String> {
+    let uri = format!("{}://{}{}", target.protocol, target.authority(), probe.path);
+    let req = Request::get(uri)
+        .version(version)
+        .header(HOST, host_header)
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+    let exchange = async {
+        let res = client.request(req).await.map_err(|e| e.to_string())?;
+        let status = res.status();
+        let mut body = Vec::new();
+        if probe.expected_body_contains.is_some() {
+            let mut stream = res.into_body();
+            while body.len() < MAX_PROBE_BODY {
+                match stream.data().await {
+                    Some(chunk) => body.extend_from_slice(&chunk.map_err(|e| e.to_string())?),
+                    None => break,
+                }
+            }
+        }
+        Ok::<_, String>((status, body))
+    };
+    let timeout = Duration::from_millis(probe.timeout_ms.unwrap_or(2000));
+    let (status, body) = tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| "probe timed out".to_string())??;
+    let status_ok = match probe.expected_status {
+        Some(expected) => status.as_u16() == expected,
+        None => status.is_success(),
+    };
+    if !status_ok {
+        return Err(format!("probe answered {}", status));
+    }
+    if let Some(needle) = &probe.expected_body_contains {
+        if !String::from_utf8_lossy(&body).contains(needle.as_str()) {
+            return Err(format!("probe body does not contain `{}`", needle));
+        }
+    }
+    Ok(())
+}
+
+/// Probes the upstreams of every host with `health.probe`, routes included,
+/// using the client and http version the host's requests use.
+pub fn spawn_probe_task(shared: SharedConfig, client: HttpClient) {
+    tokio::spawn(async move {
+        loop {
+            let config = snapshot(&shared);
+            let configured = config
+                .health
+                .as_ref()
+                .and_then(|check| check.probe.as_ref().map(|probe| (check, probe)));
+            let (check, probe) = match configured {
+                Some(configured) => configured,
+                None => {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+            let mut seen = HashSet::new();
+            let mut probes = Vec::new();
+            for (domain, host) in &config.hosts {
+                let client = client_for(domain, host, &config, &client);
+                let (client, version) = match host.upstream_version.unwrap_or_default() {
+                    UpstreamVersion::Http1 => (client.pooled, Version::HTTP_11),
+                    UpstreamVersion::Http2 => (client.h2, Version::HTTP_2),
+                };
+                for target in host.all_targets() {
+                    if !seen.insert(target.authority()) {
+                        continue;
+                    }
+                    let host_header = host
+                        .host_header_for(&target)
+                        .unwrap_or_else(|| domain.clone());
+                    let client = client.clone();
+                    probes.push(async move {
+                        let result =
+                            probe_upstream(&client, version, &target, &host_header, probe).await;
+                        (target.authority(), result)
+                    });
+                }
+            }
+            for (upstream, result) in join_all(probes).await {
+                let was_healthy = is_healthy(&upstream);
+                match result {
+                    Ok(()) => mark_success(&upstream),
+                    Err(e) => {
+                        mark_failure(&upstream, check);
+                        if was_healthy && !is_healthy(&upstream) {
+                            log_error(&format!("upstream {} ejected: {}", upstream, e));
+                        }
+                    }
+                }
+                if !was_healthy && is_healthy(&upstream) {
+                    log_info(&format!(
+                        "upstream {} passes its health probe again",
+                        upstream
+                    ));
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(
+                probe.interval_secs.unwrap_or(10).max(1),
+            ))
+            .await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{config::Host, upstream::create_http_client};
+
+    #[test]
+    fn upstreams_dropped_by_a_reload_are_pruned_once_ejection_ends() {
+        let idle = Duration::from_secs(1);
+        let now = Instant::now();
+        let entry = |last_seen, unhealthy_until| UpstreamHealth {
+            consecutive_failures: 3,
+            unhealthy_until,
+            last_seen,
+        };
+        let long_ago = now.checked_sub(2 * idle).unwrap();
+        // The reload kept the first upstream, which still gets traffic, and
+        // removed the other two, which no request reaches any more.
+        let mut health = HashMap::from([
+            ("127.0.0.1:9000".to_string(), entry(now, None)),
+            ("127.0.0.1:9001".to_string(), entry(long_ago, None)),
+            (
+                "127.0.0.1:9002".to_string(),
+                entry(long_ago, Some(now + Duration::from_secs(60))),
+            ),
+        ]);
+        assert_eq!(prune_idle(&mut health, idle), 1);
+        let mut left: Vec<&String> = health.keys().collect();
+        left.sort();
+        assert_eq!(left, ["127.0.0.1:9000", "127.0.0.1:9002"]);
+
+        health.get_mut("127.0.0.1:9002").unwrap().unhealthy_until = Some(now);
+        assert_eq!(prune_idle(&mut health, idle), 1);
+        assert_eq!(health.keys().collect::<Vec<_>>(), ["127.0.0.1:9000"]);
+    }
+
+    /// An upstream answering 200 with a chunked body that never ends.
+    async fn endless_upstream() -> Target {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = Target::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let head = "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n";
+                    if stream.write_all(head.as_bytes()).await.is_err() {
+                        return;
+                    }
+                    while stream.write_all(b"4\r\nok..\r\n").await.is_ok() {}
+                });
+            }
+        });
+        target
+    }
+
+    fn probe(yaml: &str) -> HealthProbe {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn probe_reads_only_the_start_of_the_body() {
+        let client = create_http_client(&serde_yaml::from_str("hosts: {}").unwrap());
+        let target = endless_upstream().await;
+        let check = |yaml| {
+            let (client, target) = (client.pooled.clone(), target.clone());
+            async move {
+                probe_upstream(&client, Version::HTTP_11, &target, "a.com", &probe(yaml)).await
+            }
+        };
+        check("path: /\nexpected_body_contains: ok").await.unwrap();
+        let e = check("path: /\nexpected_body_contains: missing")
+            .await
+            .unwrap_err();
+        assert!(e.contains("does not contain"), "{}", e);
+        let e = check("path: /\nexpected_status: 204").await.unwrap_err();
+        assert!(e.contains("answered 200"), "{}", e);
+    }
+
+    #[test]
+    fn route_upstreams_are_probed_too() {
+        let host: Host = serde_yaml::from_str(
+            "ip: 127.0.0.1\nport: 9000\nprotocol: http\nregex_routes:\n  - pattern: ^/api\n    upstream: http://127.0.0.1:9001\n",
+        )
+        .unwrap();
+        let upstreams: Vec<String> = host.all_targets().iter().map(Target::authority).collect();
+        assert_eq!(upstreams, ["127.0.0.1:9000", "127.0.0.1:9001"]);
+    }
+}
@@ -0,0 +1,168 @@
+use std::borrow::Cow;
+
+use hyper::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    Body, Request,
+};
+
+use crate::{
+    config::BodyInspection,
+    error::ProxyError,
+    log::log_info,
+    metrics::{incr, METRICS},
+    redact::collect_up_to,
+};
+
+fn is_form(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| {
+            v.trim()
+                .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        })
+        .unwrap_or(false)
+}
+
+fn hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8)
+}
+
+/// A form body with `+` and `%XX` decoded, invalid escapes kept as they are.
+fn form_decode(body: &[u8]) -> String {
+    let mut out = Vec::with_capacity(body.len());
+    let mut i = 0;
+    while i < body.len() {
+        match body[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < body.len() => match (hex(body[i + 1]), hex(body[i + 2])) {
+                (Some(high), Some(low)) => {
+                    out.push(high << 4 | low);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Buffers the request body and checks it against the host's deny patterns,
+/// handing the request back with the same body when nothing matches. A form
+/// body is checked decoded as well, other encodings are matched as sent.
+/// Bodies over `max_bytes`, or that break off while being read, answer 413.
+pub async fn inspect_body(
+    req: Request<Body>,
+    config: &BodyInspection,
+    host: &str,
+) -> Result<Request<Body>, ProxyError> {
+    let max = config.max_bytes.unwrap_or(65536);
+    let declared = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.map(|len| len > max).unwrap_or(false) {
+        return Err(ProxyError::BodyTooLarge);
+    }
+    let form = is_form(&req);
+    let (parts, body) = req.into_parts();
+    let bytes = collect_up_to(body, max)
+        .await
+        .map_err(|_| ProxyError::BodyTooLarge)?;
+    let mut texts = vec![String::from_utf8_lossy(&bytes)];
+    if form {
+        texts.push(Cow::Owned(form_decode(&bytes)));
+    }
+    let denied = config
+        .deny_patterns
+        .iter()
+        .find(|pattern| texts.iter().any(|text| pattern.is_match(text)));
+    if let Some(pattern) = denied {
+        incr(&METRICS.request_bodies_denied);
+        log_info(&format!(
+            "request body to {} {} denied by pattern `{}`",
+            host,
+            parts.uri.path(),
+            pattern.as_str()
+        ));
+        return Err(ProxyError::BodyDenied);
+    }
+    Ok(Request::from_parts(parts, Body::from(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inspection(max_bytes: u64) -> BodyInspection {
+        serde_yaml::from_str(&format!(
+            "deny_patterns: ['(?i)union\\s+select']\nmax_bytes: {}",
+            max_bytes
+        ))
+        .unwrap()
+    }
+
+    fn request(content_type: &str, body: &'static str) -> Request<Body> {
+        Request::post("http://example.com/login")
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn inspected(req: Request<Body>, max_bytes: u64) -> Result<Request<Body>, ProxyError> {
+        inspect_body(req, &inspection(max_bytes), "example.com").await
+    }
+
+    #[test]
+    fn decodes_forms() {
+        assert_eq!(form_decode(b"a+b%20c%3d%zz%4"), "a b c=%zz%4");
+    }
+
+    #[tokio::test]
+    async fn passes_clean_bodies_on_unchanged() {
+        let req = request("text/plain", "select from union");
+        let req = inspected(req, 1024).await.unwrap();
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(body, "select from union");
+    }
+
+    #[tokio::test]
+    async fn denies_matching_bodies() {
+        let plain = request("text/plain", "1 UNION  SELECT password");
+        assert!(matches!(
+            inspected(plain, 1024).await,
+            Err(ProxyError::BodyDenied)
+        ));
+        let form = request(
+            "application/x-www-form-urlencoded; charset=utf-8",
+            "q=1+union%20select",
+        );
+        assert!(matches!(
+            inspected(form, 1024).await,
+            Err(ProxyError::BodyDenied)
+        ));
+        let json = request("application/json", "{\"q\":\"1+union%20select\"}");
+        assert!(inspected(json, 1024).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_over_the_limit() {
+        let req = request("text/plain", "0123456789");
+        assert!(matches!(
+            inspected(req, 4).await,
+            Err(ProxyError::BodyTooLarge)
+        ));
+        let declared = Request::post("http://example.com/")
+            .header(CONTENT_LENGTH, "100")
+            .body(Body::empty())
+            .unwrap();
+        assert!(matches!(
+            inspected(declared, 4).await,
+            Err(ProxyError::BodyTooLarge)
+        ));
+    }
+}
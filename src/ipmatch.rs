@@ -0,0 +1,96 @@
+use std::net::IpAddr;
+
+/// An address or a CIDR block, e.g. `10.0.0.1` or `10.0.0.0/8`.
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (addr, prefix) = match value.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value.trim(), None),
+        };
+        let network: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("`{}` is not an ip address or cidr", value))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("`{}` has an invalid prefix length", value))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether `ip` falls in any of `ranges`. Entries that do not parse never
+/// match, config validation rejects them up front.
+pub fn matches_any(ranges: &[String], ip: IpAddr) -> bool {
+    ranges
+        .iter()
+        .filter_map(|range| IpRange::parse(range).ok())
+        .any(|range| range.contains(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_match_their_addresses() {
+        let ranges = [
+            "10.0.0.0/8".to_string(),
+            "192.0.2.7".to_string(),
+            "2001:db8::/32".to_string(),
+        ];
+        assert!(matches_any(&ranges, ip("10.1.2.3")));
+        assert!(matches_any(&ranges, ip("192.0.2.7")));
+        assert!(!matches_any(&ranges, ip("192.0.2.8")));
+        assert!(matches_any(&ranges, ip("2001:db8::1")));
+        assert!(!matches_any(&ranges, ip("2001:db9::1")));
+        // Ipv4 clients on a dual-stack listener show up ipv4-mapped.
+        assert!(matches_any(&ranges, ip("::ffff:10.9.9.9")));
+        assert!(matches_any(&["0.0.0.0/0".to_string()], ip("203.0.113.1")));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        for value in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0/8",
+            "example.com",
+            "10.0.0.0/x",
+        ] {
+            assert!(IpRange::parse(value).is_err(), "{}", value);
+        }
+        assert!(!matches_any(&["10.0.0.0/33".to_string()], ip("10.0.0.1")));
+    }
+}
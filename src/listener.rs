@@ -0,0 +1,156 @@
+use std::{fmt, io, net::SocketAddr, time::Duration};
+
+use axum_server::HttpConfig;
+use tokio::net::TcpSocket;
+
+use crate::{config::Config, log::log_error};
+
+fn bind(addr: SocketAddr, reuse_address: bool, backlog: u32) -> io::Result<std::net::TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(reuse_address)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)?.into_std()
+}
+
+/// hyper settings shared by every listener.
+pub fn http_config(config: &Config) -> HttpConfig {
+    HttpConfig::new()
+        .http1_preserve_header_case(config.preserve_header_case.unwrap_or(false))
+        .build()
+}
+
+/// Binds `addr`, retrying with exponential backoff while the port is still
+/// held (e.g. by the previous process during a fast restart). Any other bind
+/// error fails right away.
+pub async fn bind_with_retry(
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<std::net::TcpListener, String> {
+    let retries = config.bind_retries.unwrap_or(5);
+    let mut backoff = Duration::from_millis(config.bind_retry_backoff_ms.unwrap_or(200));
+    let reuse_address = config.reuse_address.unwrap_or(true);
+    let backlog = config.listen_backlog.unwrap_or(1024);
+    let mut attempt = 0;
+    loop {
+        match bind(addr, reuse_address, backlog) {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && attempt < retries => {
+                attempt += 1;
+                log_error(&format!(
+                    "{} is in use, retrying in {:?} ({}/{})",
+                    addr, backoff, attempt, retries
+                ));
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(5));
+            }
+            Err(e) => {
+                return Err(format!(
+                    "failed to bind {} after {} retries: {}",
+                    addr, attempt, e
+                ))
+            }
+        }
+    }
+}
+
+/// Why the https listener did not start.
+#[derive(Debug)]
+pub enum HttpsStartError {
+    /// The cert or key could not be loaded.
+    Certs(String),
+    /// The port could not be bound, see `bind_with_retry`.
+    Bind(String),
+}
+
+impl fmt::Display for HttpsStartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpsStartError::Certs(e) => write!(f, "failed to load the https certs: {}", e),
+            HttpsStartError::Bind(e) => f.write_str(e),
+        }
+    }
+}
+
+/// Whether `err` should stop the process. `ssl_bind_fatal` covers the port
+/// only, bad certs leave the proxy serving http like before.
+pub fn bind_failure_is_fatal(config: &Config, err: &HttpsStartError) -> bool {
+    matches!(err, HttpsStartError::Bind(_)) && config.ssl_bind_fatal.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(&format!("{}hosts: {{}}", yaml)).unwrap()
+    }
+
+    fn local(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    /// Connections the kernel completes without the listener accepting them,
+    /// out of `attempts`.
+    async fn queued(listener: &std::net::TcpListener, attempts: usize) -> usize {
+        let addr = listener.local_addr().unwrap();
+        let mut clients = Vec::new();
+        for _ in 0..attempts {
+            let connect = TcpStream::connect(addr);
+            match tokio::time::timeout(Duration::from_millis(300), connect).await {
+                Ok(Ok(client)) => clients.push(client),
+                _ => break,
+            }
+        }
+        clients.len()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn listen_backlog_bounds_the_accept_queue() {
+        let small = bind_with_retry(local(0), &config("listen_backlog: 1\n"))
+            .await
+            .unwrap();
+        assert!(queued(&small, 5).await < 5);
+        let default = bind_with_retry(local(0), &config("")).await.unwrap();
+        assert_eq!(queued(&default, 5).await, 5);
+    }
+
+    #[tokio::test]
+    async fn bind_gives_up_after_its_retries() {
+        let taken = TcpListener::bind(local(0)).unwrap();
+        let addr = taken.local_addr().unwrap();
+        let config = config("bind_retries: 2\nbind_retry_backoff_ms: 1\nreuse_address: false\n");
+        let e = bind_with_retry(addr, &config).await.unwrap_err();
+        assert!(e.contains("after 2 retries"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn bind_retries_until_the_port_is_free() {
+        let taken = TcpListener::bind(local(0)).unwrap();
+        let addr = taken.local_addr().unwrap();
+        let config = config("bind_retries: 10\nbind_retry_backoff_ms: 20\n");
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(taken);
+        });
+        bind_with_retry(addr, &config).await.unwrap();
+    }
+
+    #[test]
+    fn only_bind_failures_are_fatal_and_only_when_asked() {
+        let bind = HttpsStartError::Bind("failed to bind 0.0.0.0:443".to_string());
+        let certs = HttpsStartError::Certs("no such file".to_string());
+        let strict = config("ssl_bind_fatal: true\n");
+        assert!(bind_failure_is_fatal(&strict, &bind));
+        assert!(!bind_failure_is_fatal(&strict, &certs));
+        assert!(!bind_failure_is_fatal(&config(""), &bind));
+    }
+}
@@ -1,5 +1,29 @@
-use ansi_term::Colour::{Red, Green, Yellow, Blue, White};
+use ansi_term::Colour::{Blue, Green, Red};
 
 pub fn log_proxy(domain: &str, protocol: &str, ip: &str, port: &str){
     println!("{} <----> {}", Green.paint(domain), Green.paint(format!("{}://{}:{}", protocol, ip, port)));
-}
\ No newline at end of file
+}
+
+pub fn log_echo(domain: &str) {
+    println!("{} <----> {}", Green.paint(domain), Green.paint("echo"));
+}
+
+pub fn log_info(msg: &str) {
+    println!("{}", Blue.paint(msg));
+}
+
+pub fn log_error(msg: &str) {
+    #[cfg(test)]
+    LOGGED_ERRORS.lock().unwrap().push(msg.to_string());
+    eprintln!("{}", Red.paint(msg));
+}
+
+/// Every line passed to `log_error` so far, for tests that check what a
+/// background task logged.
+#[cfg(test)]
+static LOGGED_ERRORS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+#[cfg(test)]
+pub fn logged_errors() -> Vec<String> {
+    LOGGED_ERRORS.lock().unwrap().clone()
+}
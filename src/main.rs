@@ -1,176 +1,338 @@
+pub mod abort;
+pub mod admin;
+pub mod alert;
+pub mod balance;
+pub mod capture;
+pub mod compress;
 pub mod config;
+pub mod connect;
+pub mod connlimit;
+pub mod debug;
+pub mod dump;
+pub mod error;
+pub mod headers;
+pub mod health;
+pub mod inspect;
+pub mod ipmatch;
+pub mod listener;
 pub mod log;
+pub mod metrics;
+pub mod proxy;
+pub mod prune;
+pub mod ratelimit;
+pub mod ready;
+pub mod redact;
+pub mod reload;
+pub mod runtime;
+pub mod shutdown;
+pub mod singleflight;
+pub mod stall;
+pub mod tls;
+pub mod trace;
+pub mod transform;
+pub mod tunnel;
+pub mod upstream;
+pub mod warmup;
 
-use axum::{
-    http::{uri::Uri, Request, },
-    Router, middleware::{self, Next}, response::IntoResponse,
+use axum::{middleware, Router};
+use axum_server::{Handle, HttpConfig};
+use reload::{
+    new_shared_config, snapshot, spawn_hot_reload_task, spawn_tls_watch_task, SharedConfig,
+    TlsArtifactChanged,
 };
-use axum_server::tls_rustls::RustlsConfig;
-use config::Config;
-use hyper::{client::HttpConnector, Body, StatusCode, header::HOST, Version};
-use hyper::Client;
-use hyper_tls::HttpsConnector;
-use std::{net::SocketAddr, path::PathBuf};
+use std::net::{SocketAddr, TcpListener};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use clap::{Parser};
 
-use crate::{config::read_yaml_file, log::log_proxy};
+use crate::{
+    abort::AbortAcceptor,
+    admin::admin_server,
+    config::{
+        load_config_dir, read_config, read_yaml_file, validate_config, Config, ConfigSource,
+        STDIN_CONFIG,
+    },
+    connlimit::ConnectionLimitAcceptor,
+    dump::spawn_dump_task,
+    health::spawn_probe_task,
+    listener::{bind_failure_is_fatal, bind_with_retry, http_config, HttpsStartError},
+    log::{log_echo, log_error, log_info, log_proxy},
+    proxy::{handle_request, Listener},
+    prune::spawn_prune_task,
+    ready::{signal_stopping, BindPending, Readiness},
+    runtime::build_runtime,
+    shutdown::{drain, drain_on_shutdown, wait_for_signal},
+    stall::WriteTimeoutAcceptor,
+    tls::{build_rustls_config, reload_certs, spawn_sni_report_task, MeteredAcceptor},
+    upstream::{create_http_client, HttpClient},
+    warmup::spawn_warmup_task,
+};
 
-type HttpClient = hyper::client::Client<HttpConnector, Body>;
-type HttpsClient = Client<HttpsConnector<HttpConnector>>;
 extern crate pest;
-#[macro_use]
 extern crate pest_derive;
 
 #[derive(clap::Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    /// Config file, `-` reads it from stdin
     #[clap(short, long, value_parser, value_name = "YAML")]
     config: Option<String>,
+    /// Directory whose `.yml` fragments are merged into the config, in file name order
+    #[clap(long, value_parser, value_name = "DIR", conflicts_with = "config")]
+    config_dir: Option<String>,
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let args = Args::parse();
-    let yaml_path = args.config.unwrap_or("./config.yml".to_string());
+    let source = match args.config_dir {
+        Some(dir) => ConfigSource::Dir(dir),
+        None => ConfigSource::File(args.config.unwrap_or("./config.yml".to_string())),
+    };
 
-    let config = read_yaml_file(&yaml_path);
+    let config = match &source {
+        ConfigSource::File(yaml_path) if yaml_path == STDIN_CONFIG => {
+            match read_config(std::io::stdin().lock(), "stdin") {
+                Ok(config) => config,
+                Err(e) => {
+                    log_error(&e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        ConfigSource::File(yaml_path) => read_yaml_file(yaml_path),
+        ConfigSource::Dir(dir) => match load_config_dir(dir) {
+            Ok(config) => config,
+            Err(e) => {
+                log_error(&e);
+                std::process::exit(1);
+            }
+        },
+    };
+    if let Err(e) = validate_config(&config) {
+        log_error(&format!("invalid config: {}", e));
+        std::process::exit(1);
+    }
+    let runtime = match build_runtime(&config) {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            log_error(&e);
+            std::process::exit(1);
+        }
+    };
+    log_info(&format!("runtime started with {} worker threads", runtime.metrics().num_workers()));
+    runtime.block_on(run(source, config));
+}
+
+async fn run(source: ConfigSource, config: Config) {
+    for conflict in config.read_only_conflicts() {
+        log_error(&conflict);
+    }
+    let shared_config = new_shared_config(config.clone());
+    spawn_hot_reload_task(source, shared_config.clone());
+    spawn_prune_task(shared_config.clone());
+    spawn_dump_task(shared_config.clone());
 
-    let httpclient = Client::new();
-    let httpsclient = Client::builder().build::<_, hyper::Body>(HttpsConnector::new());
+    let client = create_http_client(&config);
+    spawn_probe_task(shared_config.clone(), client.clone());
+    spawn_warmup_task(shared_config.clone(), client.clone());
+
+    if let Some(admin_port) = config.admin_port {
+        tokio::spawn(admin_server(admin_port, shared_config.clone()));
+    }
 
+    let shutdown = CancellationToken::new();
+    let readiness = Readiness::default();
     if let Some(enable_ssl) = config.ssl {
         if enable_ssl {
-            tokio::spawn(https_server(config.clone()));
+            let https = https_server_manager(shared_config.clone(), shutdown.clone(), readiness.pending());
+            let config = config.clone();
+            tokio::spawn(async move {
+                if let Err(e) = https.await {
+                    log_error(&e.to_string());
+                    if bind_failure_is_fatal(&config, &e) {
+                        log_error("https listener is required by ssl_bind_fatal, exiting");
+                        std::process::exit(1);
+                    }
+                    log_error("continuing without https");
+                }
+            });
         }
     }
 
-    let fn_config = config.clone();
-    let app = Router::new()
-        .layer(middleware::from_fn(move |req, next| {
-            proxy_http_reqs(req, next, httpclient.clone(), httpsclient.clone(), fn_config.clone())
-        }));
+    for port in config.extra_ports.iter().flatten() {
+        tokio::spawn(extra_http_server(shared_config.clone(), client.clone(), *port, shutdown.clone(), readiness.pending()));
+    }
+    let bound = readiness.pending();
+    readiness.spawn_signal_task(&config);
+
+    let listener = Listener { port: config.port.unwrap_or(80), tls: false };
+    let app = proxy_app(client, shared_config.clone(), listener);
     let addr = SocketAddr::from(([0, 0, 0, 0], config.port.unwrap_or(80)));
+    let listener = match bind_with_retry(addr, &config).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log_error(&e);
+            std::process::exit(1);
+        }
+    };
+    drop(bound);
     println!("http reverse proxy listening on {}", addr);
     for (domain, host) in &config.hosts {
-        log_proxy(&format!("http://{}", &domain), &host.protocol, &host.ip, &host.port.to_string());
+        for name in host.names(domain) {
+            if host.is_echo() {
+                log_echo(&format!("http://{}", name));
+            }
+            for target in host.targets() {
+                log_proxy(&format!("http://{}", name), &target.protocol, &target.ip, &target.port.to_string());
+            }
+        }
     }
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+    let handle = Handle::new();
+    drain_on_shutdown(&shutdown, handle.clone(), config.shutdown_timeout());
+    let server = axum_server::from_tcp(listener)
+        .http_config(http_config(&config))
+        .handle(handle)
+        .acceptor(ConnectionLimitAcceptor::new(
+            AbortAcceptor::new(WriteTimeoutAcceptor::new(config.client_write_timeout())),
+            shared_config.clone(),
+        ));
+    tokio::spawn(async move {
+        if let Err(e) = server
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+        {
+            log_error(&format!("http server on {} stopped: {}", addr, e));
+            std::process::exit(1);
+        }
+    });
+
+    wait_for_signal().await;
+    log_info("shutdown signal received, no longer accepting connections");
+    signal_stopping(&snapshot(&shared_config));
+    shutdown.cancel();
+    drain(snapshot(&shared_config).shutdown_timeout()).await;
+}
+
+/// Proxies every request that arrives on `listener`.
+fn proxy_app(client: HttpClient, shared_config: SharedConfig, listener: Listener) -> Router {
+    Router::new()
+        .layer(middleware::from_fn(move |req, _next| {
+            handle_request(req, client.clone(), shared_config.clone(), listener)
+        }))
+}
+
+/// An http listener from `extra_ports`. Unlike the main port a failed bind
+/// only disables this listener.
+async fn extra_http_server(shared_config: SharedConfig, client: HttpClient, port: u16, shutdown: CancellationToken, bound: BindPending) {
+    let config = snapshot(&shared_config);
+    let app = proxy_app(client, shared_config.clone(), Listener { port, tls: false });
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = match bind_with_retry(addr, &config).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log_error(&e);
+            bound.failed(&format!("http {}", addr));
+            return;
+        }
+    };
+    drop(bound);
+    println!("http reverse proxy listening on {}", addr);
+    let handle = Handle::new();
+    drain_on_shutdown(&shutdown, handle.clone(), config.shutdown_timeout());
+    if let Err(e) = axum_server::from_tcp(listener)
+        .http_config(http_config(&config))
+        .handle(handle)
+        .acceptor(ConnectionLimitAcceptor::new(
+            AbortAcceptor::new(WriteTimeoutAcceptor::new(config.client_write_timeout())),
+            shared_config,
+        ))
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
-        .unwrap();
+    {
+        log_error(&format!("http server on {} stopped: {}", addr, e));
+    }
 }
 
-async fn https_server(config: Config) {
-    let httpclient = Client::new();
-    let httpsclient = Client::builder().build::<_, hyper::Body>(HttpsConnector::new());
+/// Runs the https listener and swaps in fresh TLS material whenever the tls
+/// watch task reports a change. Only handshakes after the swap see the new
+/// certs, open connections are left alone. Once `shutdown` is cancelled the
+/// server drains like the http listeners. Returns early with the error when
+/// the listener cannot start.
+async fn https_server_manager(shared_config: SharedConfig, shutdown: CancellationToken, bound: BindPending) -> Result<(), HttpsStartError> {
+    let config = snapshot(&shared_config);
+    let client = create_http_client(&config);
 
-    let fn_config = config.clone();
-    let app = Router::new()
-        .layer(middleware::from_fn(move |req, next| {
-            proxy_https_reqs(req, next, httpclient.clone(), httpsclient.clone(), fn_config.clone())
-        }));
+    let listener = Listener { port: config.ssl_port.unwrap_or(443), tls: true };
+    let app = proxy_app(client, shared_config.clone(), listener);
     let addr = SocketAddr::from(([0, 0, 0, 0], config.ssl_port.unwrap_or(443)));
-    
-    let ssl_cfg = RustlsConfig::from_pem_file(
-        PathBuf::from(config.ssl_cert_file.unwrap_or("./ssl/certificate.crt".to_string())), 
-        PathBuf::from(config.ssl_key_file.unwrap_or("./ssl/private.pem".to_string())), 
-    )
-    .await
-    .unwrap();
+
+    let start = async {
+        let ssl_cfg = build_rustls_config(&config).map_err(|e| HttpsStartError::Certs(e.to_string()))?;
+        let listener = bind_with_retry(addr, &config).await.map_err(HttpsStartError::Bind)?;
+        Ok((ssl_cfg, listener))
+    };
+    let (ssl_cfg, listener) = match start.await {
+        Ok(ready) => ready,
+        Err(e) => {
+            bound.failed(&format!("https {}", addr));
+            return Err(e);
+        }
+    };
+    drop(bound);
 
     println!("https reverse proxy listening on {}", addr);
     for (domain, host) in &config.hosts {
-        log_proxy(&format!("https://{}", &domain), &host.protocol, &host.ip, &host.port.to_string());
-    }
-    axum_server::bind_rustls(addr, ssl_cfg)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
-}
-
-async fn proxy_https_reqs(
-    mut req: Request<Body>,
-    _next: Next<Body>,
-    httpclient: HttpClient,
-    httpsclient: HttpsClient,
-    config: Config
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-        let path = req.uri().path();
-        let path_query = req
-            .uri()
-            .path_and_query()
-            .map(|v| v.as_str())
-            .unwrap_or(path);
-        
-        let host = if let Some(header_host) = req.headers().get(HOST){
-            Some(header_host.to_str().unwrap())
-        }else {
-            req.uri().host()
-        };
-
-        if let Some(host) = host {
-            let host = host.to_string();
-            let host_config = config.hosts.get(&host);
-            match host_config {
-                Some(cfg) => {
-                    let uri = format!("{}://{}:{}{}", cfg.protocol, cfg.ip, cfg.port, path_query);
-                    *req.uri_mut() = Uri::try_from(uri).unwrap();
-                    *req.version_mut() = Version::HTTP_11;
-                    let res = match cfg.protocol.as_str() {
-                        "https" => httpsclient.request(req).await.unwrap(),
-                        _ =>  httpclient.request(req).await.unwrap(),
-                    };
-                    Ok(res)
-                },
-                None => {
-                    Err((StatusCode::FAILED_DEPENDENCY, "Unkown `Host` in the headers".to_string()))
-                },
+        for name in host.names(domain) {
+            if host.is_echo() {
+                log_echo(&format!("https://{}", name));
+            }
+            for target in host.targets() {
+                log_proxy(&format!("https://{}", name), &target.protocol, &target.ip, &target.port.to_string());
             }
-        }else {
-            Err((StatusCode::FAILED_DEPENDENCY, "The `Host` does not exist in the headers".to_string()))
         }
+    }
 
-}
+    let (tx, mut rx) = mpsc::channel(1);
+    spawn_tls_watch_task(shared_config.clone(), tx);
+    spawn_sni_report_task(shared_config.clone());
 
-async fn proxy_http_reqs(
-    mut req: Request<Body>,
-    _next: Next<Body>,
-    httpclient: HttpClient,
-    httpsclient: HttpsClient,
-    config: Config
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-        let path = req.uri().path();
-        let path_query = req
-            .uri()
-            .path_and_query()
-            .map(|v| v.as_str())
-            .unwrap_or(path);
-        
-        let host = if let Some(header_host) = req.headers().get(HOST){
-            Some(header_host.to_str().unwrap())
-        }else {
-            req.uri().host()
+    let acceptor = MeteredAcceptor::new(
+        ssl_cfg.clone(),
+        config.client_write_timeout(),
+        config.tls_handshake_timeout(),
+        config.max_concurrent_handshakes,
+    );
+    let handle = serve_https(listener, acceptor, shared_config.clone(), http_config(&config), app);
+    loop {
+        let changed: Option<TlsArtifactChanged> = tokio::select! {
+            changed = rx.recv() => changed,
+            _ = shutdown.cancelled() => None,
         };
-
-        if let Some(host) = host {
-            let host = host.to_string();
-            let host_config = config.hosts.get(&host);
-            match host_config {
-                Some(cfg) => {
-                    let uri = format!("{}://{}:{}{}", cfg.protocol, cfg.ip, cfg.port, path_query);
-                    *req.uri_mut() = Uri::try_from(uri).unwrap();
-                    let res = match cfg.protocol.as_str() {
-                        "https" => httpsclient.request(req).await.unwrap(),
-                        _ =>  httpclient.request(req).await.unwrap(),
-                    };
-                    Ok(res)
-                },
-                None => {
-                    Err((StatusCode::FAILED_DEPENDENCY, "Unkown `Host` in the headers".to_string()))
-                },
-            }
-        }else {
-            Err((StatusCode::FAILED_DEPENDENCY, "The `Host` does not exist in the headers".to_string()))
+        if changed.is_none() {
+            // Either shutting down or tls watching is off, in both cases the
+            // current server stays until shutdown.
+            shutdown.cancelled().await;
+            handle.graceful_shutdown(Some(snapshot(&shared_config).shutdown_timeout()));
+            return Ok(());
         }
+        reload_certs(&ssl_cfg, &snapshot(&shared_config));
+    }
+}
 
-}
\ No newline at end of file
+/// Starts the https server on `listener` and returns its handle.
+fn serve_https(listener: TcpListener, acceptor: MeteredAcceptor, shared_config: SharedConfig, http_config: HttpConfig, app: Router) -> Handle {
+    let handle = Handle::new();
+    let server = axum_server::from_tcp(listener)
+        .http_config(http_config)
+        .handle(handle.clone())
+        .acceptor(ConnectionLimitAcceptor::new(AbortAcceptor::new(acceptor), shared_config));
+    tokio::spawn(async move {
+        if let Err(e) = server
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+        {
+            log_error(&format!("https server stopped: {}", e));
+        }
+    });
+    handle
+}
@@ -0,0 +1,233 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct Metrics {
+    pub tls_handshake_failures: AtomicU64,
+    pub tls_handshake_timeouts: AtomicU64,
+    pub tls_sni_fallbacks: AtomicU64,
+    pub tls_cert_load_failures: AtomicU64,
+    pub client_write_timeouts: AtomicU64,
+    pub client_connections_refused: AtomicU64,
+    pub traces_sampled: AtomicU64,
+    pub traces_unsampled: AtomicU64,
+    pub ambiguous_requests_rejected: AtomicU64,
+    pub request_bodies_denied: AtomicU64,
+    pub upstream_connects: AtomicU64,
+    pub upstream_invalid_responses: AtomicU64,
+    pub upstream_oversized_headers: AtomicU64,
+    pub upstream_truncated_bodies: AtomicU64,
+    pub responses_cut_at_deadline: AtomicU64,
+    pub upstream_dns_micros: AtomicU64,
+    pub upstream_tcp_connect_micros: AtomicU64,
+    pub upstream_tls_handshakes: AtomicU64,
+    pub upstream_tls_handshake_micros: AtomicU64,
+    pub error_rate_alerts: AtomicU64,
+    pub websockets_rejected: AtomicU64,
+    pub active_requests: AtomicU64,
+    pub hosts_over_error_threshold: AtomicU64,
+    pub upgraded_connections: AtomicU64,
+    pub tls_handshakes_queued: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics {
+    tls_handshake_failures: AtomicU64::new(0),
+    tls_handshake_timeouts: AtomicU64::new(0),
+    tls_sni_fallbacks: AtomicU64::new(0),
+    tls_cert_load_failures: AtomicU64::new(0),
+    client_write_timeouts: AtomicU64::new(0),
+    client_connections_refused: AtomicU64::new(0),
+    traces_sampled: AtomicU64::new(0),
+    traces_unsampled: AtomicU64::new(0),
+    ambiguous_requests_rejected: AtomicU64::new(0),
+    request_bodies_denied: AtomicU64::new(0),
+    upstream_connects: AtomicU64::new(0),
+    upstream_invalid_responses: AtomicU64::new(0),
+    upstream_oversized_headers: AtomicU64::new(0),
+    upstream_truncated_bodies: AtomicU64::new(0),
+    responses_cut_at_deadline: AtomicU64::new(0),
+    upstream_dns_micros: AtomicU64::new(0),
+    upstream_tcp_connect_micros: AtomicU64::new(0),
+    upstream_tls_handshakes: AtomicU64::new(0),
+    upstream_tls_handshake_micros: AtomicU64::new(0),
+    error_rate_alerts: AtomicU64::new(0),
+    websockets_rejected: AtomicU64::new(0),
+    active_requests: AtomicU64::new(0),
+    hosts_over_error_threshold: AtomicU64::new(0),
+    upgraded_connections: AtomicU64::new(0),
+    tls_handshakes_queued: AtomicU64::new(0),
+};
+
+pub fn incr(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn add(counter: &AtomicU64, value: u64) {
+    counter.fetch_add(value, Ordering::Relaxed);
+}
+
+pub fn decr(gauge: &AtomicU64) {
+    gauge.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Keeps a gauge one higher for as long as it is alive.
+pub struct GaugeGuard(&'static AtomicU64);
+
+impl GaugeGuard {
+    pub fn new(gauge: &'static AtomicU64) -> Self {
+        incr(gauge);
+        Self(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Renders all counters in the prometheus text format.
+pub fn render() -> String {
+    let counters = [
+        (
+            "reverse_proxy_tls_handshake_failures_total",
+            "TLS handshakes that failed or were aborted",
+            &METRICS.tls_handshake_failures,
+        ),
+        (
+            "reverse_proxy_tls_handshake_timeouts_total",
+            "TLS handshakes aborted after tls_handshake_timeout_secs, also counted as failures",
+            &METRICS.tls_handshake_timeouts,
+        ),
+        (
+            "reverse_proxy_tls_sni_fallbacks_total",
+            "TLS handshakes served the default cert because the SNI matched no host",
+            &METRICS.tls_sni_fallbacks,
+        ),
+        (
+            "reverse_proxy_tls_cert_load_failures_total",
+            "Host certificates that failed to load",
+            &METRICS.tls_cert_load_failures,
+        ),
+        (
+            "reverse_proxy_client_write_timeouts_total",
+            "Connections closed because the client stopped reading the response",
+            &METRICS.client_write_timeouts,
+        ),
+        (
+            "reverse_proxy_client_connections_refused_total",
+            "Client connections closed on accept because their ip was over max_connections_per_ip",
+            &METRICS.client_connections_refused,
+        ),
+        (
+            "reverse_proxy_traces_sampled_total",
+            "Requests forwarded with a sampled traceparent",
+            &METRICS.traces_sampled,
+        ),
+        (
+            "reverse_proxy_traces_unsampled_total",
+            "Requests forwarded with an unsampled traceparent",
+            &METRICS.traces_unsampled,
+        ),
+        (
+            "reverse_proxy_ambiguous_requests_rejected_total",
+            "Requests rejected because their body length was ambiguous",
+            &METRICS.ambiguous_requests_rejected,
+        ),
+        (
+            "reverse_proxy_request_bodies_denied_total",
+            "Requests answered 403 because their body matched a body_inspection deny pattern",
+            &METRICS.request_bodies_denied,
+        ),
+        (
+            "reverse_proxy_upstream_connects_total",
+            "New upstream connections opened",
+            &METRICS.upstream_connects,
+        ),
+        (
+            "reverse_proxy_upstream_invalid_responses_total",
+            "Upstream answers that were not valid HTTP",
+            &METRICS.upstream_invalid_responses,
+        ),
+        (
+            "reverse_proxy_upstream_oversized_headers_total",
+            "Upstream responses whose head was over max_response_header_bytes",
+            &METRICS.upstream_oversized_headers,
+        ),
+        (
+            "reverse_proxy_upstream_truncated_bodies_total",
+            "Upstream response bodies that broke off before their end",
+            &METRICS.upstream_truncated_bodies,
+        ),
+        (
+            "reverse_proxy_responses_cut_at_deadline_total",
+            "Response bodies ended early by response_deadline_ms",
+            &METRICS.responses_cut_at_deadline,
+        ),
+        (
+            "reverse_proxy_upstream_dns_microseconds_total",
+            "Time spent resolving upstream names for new connections",
+            &METRICS.upstream_dns_micros,
+        ),
+        (
+            "reverse_proxy_upstream_tcp_connect_microseconds_total",
+            "Time spent on tcp connects to upstreams",
+            &METRICS.upstream_tcp_connect_micros,
+        ),
+        (
+            "reverse_proxy_upstream_tls_handshakes_total",
+            "TLS handshakes with https upstreams",
+            &METRICS.upstream_tls_handshakes,
+        ),
+        (
+            "reverse_proxy_upstream_tls_handshake_microseconds_total",
+            "Time spent on tls handshakes with https upstreams",
+            &METRICS.upstream_tls_handshake_micros,
+        ),
+        (
+            "reverse_proxy_error_rate_alerts_total",
+            "Times a host's 5xx share went above its error_alert threshold",
+            &METRICS.error_rate_alerts,
+        ),
+        (
+            "reverse_proxy_websockets_rejected_total",
+            "Websocket upgrades answered 503 because max_ws_connections was reached",
+            &METRICS.websockets_rejected,
+        ),
+    ];
+    let gauges = [
+        (
+            "reverse_proxy_active_requests",
+            "Requests whose response has not been fully sent yet",
+            &METRICS.active_requests,
+        ),
+        (
+            "reverse_proxy_upgraded_connections",
+            "Upgraded connections, e.g. websockets, currently tunneled to an upstream",
+            &METRICS.upgraded_connections,
+        ),
+        (
+            "reverse_proxy_hosts_over_error_threshold",
+            "Hosts whose 5xx share is above their error_alert threshold right now",
+            &METRICS.hosts_over_error_threshold,
+        ),
+        (
+            "reverse_proxy_tls_handshakes_queued",
+            "TLS handshakes waiting for a slot under max_concurrent_handshakes",
+            &METRICS.tls_handshakes_queued,
+        ),
+    ];
+    let mut out = String::new();
+    for (kind, metrics) in [("counter", &counters[..]), ("gauge", &gauges[..])] {
+        for (name, help, value) in metrics {
+            out.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                name,
+                help,
+                name,
+                kind,
+                name,
+                value.load(Ordering::Relaxed)
+            ));
+        }
+    }
+    out
+}
@@ -0,0 +1,1707 @@
+use std::{
+    error::Error,
+    io,
+    net::{IpAddr, SocketAddr},
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{
+        uri::{PathAndQuery, Uri},
+        Request,
+    },
+};
+use futures_util::{stream, FutureExt, StreamExt};
+use hyper::{
+    header::{
+        HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, ACCEPT_RANGES, ALT_SVC, CONNECTION,
+        CONTENT_ENCODING, CONTENT_TYPE, HOST, LOCATION, RETRY_AFTER,
+    },
+    Body, HeaderMap, Method, Response, StatusCode, Version,
+};
+
+use crate::{
+    abort::DropConnection,
+    alert::record_status,
+    balance::{hashed_target, next_target, track_in_flight},
+    capture::{redacting_headers, tee_body},
+    compress::{compress_request, learn_request_gzip, maybe_compress, upstream_takes_gzip},
+    config::{
+        split_host_port, AbsoluteFormPolicy, Config, CustomResponse, H2cUpgrade, Host, Target,
+        TrustedIncomingHeaders, UpstreamVersion,
+    },
+    debug::{sampled, DebugRecord},
+    error::ProxyError,
+    headers::{
+        ambiguous_framing, append_via, apply_security_headers, asks_to_close, downgrade_to_http10,
+        drop_close_token, drop_h2c_upgrade, ensure_charset, ensure_request_id,
+        preferred_media_types, reset_connection_headers, set_deadline_header, upgrade_from_http10,
+        upgrade_protocol,
+    },
+    health::{is_healthy, mark_failure, mark_success},
+    inspect::inspect_body,
+    ipmatch::matches_any,
+    log::log_error,
+    metrics::{incr, GaugeGuard, METRICS},
+    ratelimit::{check_host_rate_limit, check_rate_limit},
+    redact::redact_json,
+    reload::{snapshot, SharedConfig},
+    singleflight::{flight_key, single_flight},
+    transform::{apply_request_pipeline, StepContext},
+    tunnel::{acquire_ws_slot, spawn_tunnel, WsRoute},
+    upstream::{
+        client_for, send_upstream, watch_truncation, HttpClient, RetryPolicy, UpstreamError,
+    },
+};
+
+/// Host from the `Host` header, falling back to the request target's
+/// authority.
+pub fn extract_host<B>(req: &Request<B>) -> Option<String> {
+    if let Some(header_host) = req.headers().get(HOST) {
+        header_host.to_str().ok().map(|h| h.to_string())
+    } else {
+        req.uri().host().map(|h| h.to_string())
+    }
+}
+
+/// Host of an absolute-form target, which per RFC 7230 takes precedence over
+/// the `Host` header. Any userinfo is dropped.
+fn authority_host(uri: &Uri) -> Option<String> {
+    let host = uri.host()?;
+    Some(match uri.port_u16() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+/// The request's own uri with only scheme and authority replaced, the path
+/// and query keep their exact bytes instead of going through a re-parse.
+fn swap_authority(uri: &Uri, target: &Target) -> Result<Uri, String> {
+    let mut parts = uri.clone().into_parts();
+    parts.scheme = Some(target.protocol.parse().map_err(|e| format!("{}", e))?);
+    parts.authority = Some(target.authority().parse().map_err(|e| format!("{}", e))?);
+    if parts.path_and_query.is_none() {
+        parts.path_and_query = Some(PathAndQuery::from_static("/"));
+    }
+    Uri::from_parts(parts).map_err(|e| e.to_string())
+}
+
+/// The first regex route matching the path.
+fn select_regex_route(path: &str, cfg: &Host) -> Option<Target> {
+    cfg.regex_routes
+        .iter()
+        .flatten()
+        .find(|route| route.pattern.is_match(path))
+        .and_then(|route| route.target().ok())
+}
+
+/// The route for the most preferred media type in `Accept` that has one.
+/// Wildcards never select a route, they fall through to the default target.
+fn select_accept_route<B>(req: &Request<B>, cfg: &Host) -> Option<Target> {
+    let routes = cfg.accept_routes.as_ref()?;
+    let accept = req.headers().get(ACCEPT)?.to_str().ok()?;
+    preferred_media_types(accept).iter().find_map(|media_type| {
+        routes
+            .iter()
+            .find(|route| route.media_type.eq_ignore_ascii_case(media_type))
+            .and_then(|route| route.target().ok())
+    })
+}
+
+/// Answer for a host whose upstreams are all ejected by the health check.
+fn no_upstream_response(host_key: &str, cfg: &Host) -> Result<Response<Body>, ProxyError> {
+    match &cfg.no_upstream_response {
+        Some(custom) => {
+            let res = custom_response(custom, StatusCode::SERVICE_UNAVAILABLE);
+            track_status(host_key, cfg, res.status());
+            Ok(res)
+        }
+        None => Err(upstream_failure(
+            ProxyError::NoHealthyUpstream,
+            host_key,
+            cfg,
+        )),
+    }
+}
+
+/// Counts an answer towards the host's `error_alert` window.
+fn track_status(host_key: &str, cfg: &Host, status: StatusCode) {
+    if let Some(alert) = &cfg.error_alert {
+        record_status(host_key, alert, status);
+    }
+}
+
+/// An error the proxy answers for a request it could not get through to an
+/// upstream. It counts towards `error_alert`, and a 502, 503 or 504 gets
+/// the host's `error_retry_after_secs`.
+fn upstream_failure(e: ProxyError, host_key: &str, cfg: &Host) -> ProxyError {
+    track_status(host_key, cfg, e.status());
+    with_retry_after(e, cfg)
+}
+
+/// `e` with the host's `error_retry_after_secs` if it is a 502, 503 or 504.
+fn with_retry_after(e: ProxyError, cfg: &Host) -> ProxyError {
+    let unavailable = matches!(
+        e.status(),
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    );
+    match cfg.error_retry_after_secs {
+        Some(secs) if unavailable => ProxyError::RetryAfter(Box::new(e), secs),
+        _ => e,
+    }
+}
+
+fn custom_response(custom: &CustomResponse, default_status: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::from(custom.body.clone().unwrap_or_default()));
+    *res.status_mut() = custom
+        .status
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(default_status);
+    if let Some(content_type) = &custom.content_type {
+        if let Ok(value) = HeaderValue::from_str(content_type) {
+            res.headers_mut().insert(CONTENT_TYPE, value);
+        }
+    }
+    if let Some(secs) = custom.retry_after_secs {
+        res.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(secs));
+    }
+    res
+}
+
+/// A 400 after which the connection is closed, nothing else sent on it can
+/// be trusted.
+fn reject_and_close(version: Version, reason: &str) -> Response<Body> {
+    let mut res = Response::new(Body::from(reason.to_string()));
+    *res.status_mut() = StatusCode::BAD_REQUEST;
+    if version < Version::HTTP_2 {
+        res.headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+    res
+}
+
+/// Answer for a `require_https` host reached over plain http. Only GET and
+/// HEAD are redirected, other requests have already sent their body in the
+/// clear and a redirect would have the client send it again.
+fn require_https(
+    method: &Method,
+    host: &str,
+    path_query: &str,
+    https_port: u16,
+) -> Result<Response<Body>, ProxyError> {
+    if method != Method::GET && method != Method::HEAD {
+        return Err(ProxyError::HttpsRequired);
+    }
+    let (name, _) = split_host_port(host);
+    let location = match https_port {
+        443 => format!("https://{}{}", name, path_query),
+        port => format!("https://{}:{}{}", name, port, path_query),
+    };
+    let location = HeaderValue::from_str(&location).map_err(|_| ProxyError::HttpsRequired)?;
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = StatusCode::PERMANENT_REDIRECT;
+    res.headers_mut().insert(LOCATION, location);
+    Ok(res)
+}
+
+/// Answer for a host that matches no configured host.
+fn unknown_host_response(config: &Config) -> Result<Response<Body>, ProxyError> {
+    let custom = match &config.unknown_host_response {
+        Some(custom) => custom,
+        None => return Err(ProxyError::UnknownHost),
+    };
+    if custom.close.unwrap_or(false) {
+        let mut res = Response::new(Body::empty());
+        res.extensions_mut().insert(DropConnection);
+        return Ok(res);
+    }
+    let mut res = Response::new(Body::from(custom.body.clone().unwrap_or_default()));
+    *res.status_mut() = custom
+        .status
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::FAILED_DEPENDENCY);
+    if let Some(content_type) = &custom.content_type {
+        if let Ok(value) = HeaderValue::from_str(content_type) {
+            res.headers_mut().insert(CONTENT_TYPE, value);
+        }
+    }
+    Ok(res)
+}
+
+/// Removes the headers only a front proxy may set unless `client_ip` is one
+/// of `policy.trusted_proxies`.
+fn strip_untrusted_headers(
+    headers: &mut HeaderMap,
+    policy: &TrustedIncomingHeaders,
+    client_ip: Option<IpAddr>,
+) {
+    let trusted = client_ip
+        .map(|ip| matches_any(&policy.trusted_proxies, ip))
+        .unwrap_or(false);
+    if !trusted {
+        for name in policy.header_names() {
+            headers.remove(name);
+        }
+    }
+}
+
+/// Keeps `guard` alive until the whole body has been sent or the client
+/// went away.
+fn hold_until_sent<G: Send + 'static>(body: Body, guard: G) -> Body {
+    Body::wrap_stream(body.inspect(move |_| {
+        let _ = &guard;
+    }))
+}
+
+/// Ends `body` with an error once `deadline` passes, on which hyper closes
+/// the connection (http/1) or resets the stream (http/2), so the client can
+/// tell the response was cut short.
+fn cut_at_deadline(mut body: Body, deadline: Instant, label: String) -> Body {
+    let mut expired = Box::pin(tokio::time::sleep_until(deadline.into()));
+    Body::wrap_stream(stream::poll_fn(move |cx| {
+        if expired.poll_unpin(cx).is_ready() {
+            incr(&METRICS.responses_cut_at_deadline);
+            log_error(&format!("{} cut short by response_deadline_ms", label));
+            let e: Box<dyn Error + Send + Sync> =
+                io::Error::new(io::ErrorKind::TimedOut, "response deadline passed").into();
+            return Poll::Ready(Some(Err(e)));
+        }
+        body.poll_next_unpin(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map_err(Into::into)))
+    }))
+}
+
+/// The listener a request came in on.
+#[derive(Clone, Copy)]
+pub struct Listener {
+    pub port: u16,
+    pub tls: bool,
+}
+
+/// Entry point for every proxied request. A `debug_sample_rate` share of
+/// them is logged with all request and response headers.
+pub async fn handle_request(
+    req: Request<Body>,
+    client: HttpClient,
+    shared_config: SharedConfig,
+    listener: Listener,
+) -> Result<Response<Body>, ProxyError> {
+    let config = snapshot(&shared_config);
+    if !sampled(config.debug_sample_rate.unwrap_or(0.0)) {
+        return proxy_request(req, client, shared_config, listener).await;
+    }
+    let record = DebugRecord::new(&req, config.redact_headers.clone().unwrap_or_default());
+    let result = proxy_request(req, client, shared_config, listener).await;
+    record.log(&result);
+    result
+}
+
+async fn proxy_request(
+    mut req: Request<Body>,
+    client: HttpClient,
+    shared_config: SharedConfig,
+    listener: Listener,
+) -> Result<Response<Body>, ProxyError> {
+    let active = GaugeGuard::new(&METRICS.active_requests);
+    let received = Instant::now();
+    let config = snapshot(&shared_config);
+    let client_ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Some(policy) = &config.trusted_incoming_headers {
+        strip_untrusted_headers(req.headers_mut(), policy, client_ip);
+    }
+    if let (Some(limit), Some(ip)) = (&config.rate_limit, client_ip) {
+        if !check_rate_limit(ip, req.method().as_str(), limit) {
+            return Err(ProxyError::RateLimited);
+        }
+    }
+    if config.is_method_blocked(req.method().as_str()) {
+        return Err(ProxyError::MethodNotAllowed(req.method().clone()));
+    }
+    // Measured before any rewrite, on what the client sent.
+    let uri_length = req.uri().path_and_query().map(|v| v.as_str().len());
+    if uri_length.unwrap_or(0) > config.max_uri_length.unwrap_or(8192) {
+        return Err(ProxyError::UriTooLong);
+    }
+    if let Some(reason) = ambiguous_framing(req.headers()) {
+        incr(&METRICS.ambiguous_requests_rejected);
+        return Ok(reject_and_close(req.version(), reason));
+    }
+    let path = req.uri().path();
+    let path_query = req
+        .uri()
+        .path_and_query()
+        .map(|v| v.as_str())
+        .unwrap_or(path)
+        .to_string();
+
+    // hyper hands http/2 requests over with an absolute uri, only an
+    // absolute-form target on http/1.x comes from the client itself.
+    let absolute_form = req.version() < Version::HTTP_2 && req.uri().scheme().is_some();
+    let host = if absolute_form {
+        match config.absolute_form.unwrap_or_default() {
+            AbsoluteFormPolicy::Reject => return Err(ProxyError::AbsoluteFormRejected),
+            AbsoluteFormPolicy::Honor => authority_host(req.uri()),
+        }
+    } else {
+        extract_host(&req)
+    };
+    let host = match host {
+        Some(host) if host.trim().is_empty() => return Err(ProxyError::EmptyHost),
+        Some(host) => host,
+        None => return Err(ProxyError::MissingHost),
+    };
+    let (host_key, cfg) = match config.resolve_host(&host, listener.port) {
+        Some((key, cfg, _)) => (key, cfg),
+        None => return unknown_host_response(&config),
+    };
+    if !listener.tls && cfg.require_https.unwrap_or(false) {
+        return require_https(
+            req.method(),
+            &host,
+            &path_query,
+            config.ssl_port.unwrap_or(443),
+        );
+    }
+    let state_key = cfg.state_key(host_key);
+    if let Some(limit) = &cfg.aggregate_rate_limit {
+        if !check_host_rate_limit(&state_key, limit) {
+            return Err(ProxyError::RateLimited);
+        }
+    }
+
+    let client = client_for(&state_key, cfg, &config, &client);
+    let settings = config.effective_settings(cfg);
+
+    if let Some(maintenance) = &cfg.maintenance {
+        let allowed = match (&cfg.maintenance_allow_ips, client_ip) {
+            (Some(ranges), Some(ip)) => matches_any(ranges, ip),
+            _ => false,
+        };
+        if !allowed {
+            return Ok(custom_response(
+                maintenance,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ));
+        }
+    }
+
+    if let Some(inspection) = &cfg.body_inspection {
+        req = inspect_body(req, inspection, host_key).await?;
+    }
+
+    if cfg.is_echo() {
+        return Ok(match &cfg.echo_response {
+            Some(echo) => custom_response(echo, StatusCode::OK),
+            None => Response::new(Body::empty()),
+        });
+    }
+
+    let http10_client = req.version() == Version::HTTP_10;
+    if http10_client {
+        upgrade_from_http10(&mut req);
+    }
+    let client_close = req.version() == Version::HTTP_11 && asks_to_close(req.headers());
+
+    // Only HTTP/1.1 has `Upgrade`, http/2 clients use extended CONNECT.
+    let mut upgrade = (req.version() == Version::HTTP_11)
+        .then(|| upgrade_protocol(req.headers()))
+        .flatten();
+    let h2c = upgrade
+        .as_deref()
+        .map(|protocol| protocol.eq_ignore_ascii_case("h2c"))
+        .unwrap_or(false);
+    if h2c && cfg.h2c_upgrade.unwrap_or_default() == H2cUpgrade::Ignore {
+        drop_h2c_upgrade(req.headers_mut());
+        upgrade = None;
+    }
+    let ws_slot = match &upgrade {
+        Some(protocol) if protocol.eq_ignore_ascii_case("websocket") => {
+            match acquire_ws_slot(
+                &state_key,
+                config.max_ws_connections,
+                cfg.max_ws_connections,
+            ) {
+                Some(slot) => Some(slot),
+                None => {
+                    // The proxy's own limit, not an upstream failure, so it
+                    // stays out of `error_alert`.
+                    incr(&METRICS.websockets_rejected);
+                    return Err(with_retry_after(ProxyError::TooManyWebSockets, cfg));
+                }
+            }
+        }
+        _ => None,
+    };
+    let client_upgrade = upgrade.as_ref().map(|_| hyper::upgrade::on(&mut req));
+
+    let accept_encoding = req.headers().get(ACCEPT_ENCODING).cloned();
+    let is_head = req.method() == Method::HEAD;
+
+    // Only a plain body can be redacted, the client's encodings are still
+    // applied on the way back by `compression`.
+    if cfg.json_redaction.is_some() {
+        req.headers_mut().remove(ACCEPT_ENCODING);
+    }
+
+    let usable = |target: &Target| config.health.is_none() || is_healthy(&target.authority());
+    let route =
+        select_regex_route(req.uri().path(), cfg).or_else(|| select_accept_route(&req, cfg));
+    let hash_key = cfg
+        .hash_routing
+        .as_ref()
+        .and_then(|hash_routing| req.headers().get(hash_routing.header.as_str()))
+        .map(|value| value.as_bytes().to_vec());
+    let target = match (route, hash_key) {
+        (Some(target), _) => Some(target).filter(usable),
+        (None, Some(key)) => hashed_target(&key, &cfg.targets(), usable),
+        (None, None) => next_target(&state_key, settings.balance, &cfg.targets(), usable),
+    };
+    let target = match target {
+        Some(target) => target,
+        None => return no_upstream_response(host_key, cfg),
+    };
+    let upstream = target.authority();
+    let in_flight = track_in_flight(&upstream);
+    let ctx = StepContext {
+        host: &host,
+        cfg,
+        settings: &settings,
+        target: &target,
+    };
+    // Headers set here come before the pipeline so `header_limit` counts
+    // them, only the deadline header and request compression come later.
+    let request_id = settings
+        .request_id_header
+        .as_ref()
+        .and_then(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+        .map(|name| {
+            let id = ensure_request_id(req.headers_mut(), &name);
+            (name, id)
+        });
+    let version = match settings.upstream_version {
+        _ if upgrade.is_some() => Version::HTTP_11,
+        UpstreamVersion::Http1 => Version::HTTP_11,
+        UpstreamVersion::Http2 => Version::HTTP_2,
+    };
+    if client_close && !cfg.forward_connection_close.unwrap_or(false) {
+        drop_close_token(req.headers_mut());
+    }
+    if cfg.disable_keepalive.unwrap_or(false) && upgrade.is_none() && version == Version::HTTP_11 {
+        req.headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+    apply_request_pipeline(&mut req, &ctx)?;
+
+    let uri = if settings.raw_path_passthrough {
+        swap_authority(req.uri(), &target)
+    } else {
+        Uri::try_from(format!("{}://{}{}", target.protocol, upstream, path_query))
+            .map_err(|e| e.to_string())
+    };
+    *req.uri_mut() = match uri {
+        Ok(uri) => uri,
+        Err(e) => {
+            return Err(upstream_failure(
+                ProxyError::InvalidUpstreamUri(e),
+                host_key,
+                cfg,
+            ))
+        }
+    };
+    *req.version_mut() = version;
+    let redact_headers = config.redact_headers.as_deref().unwrap_or_default();
+    let capture = cfg
+        .capture
+        .as_ref()
+        .filter(|capture| {
+            capture
+                .path_prefix
+                .as_ref()
+                .map(|prefix| path_query.starts_with(prefix.as_str()))
+                .unwrap_or(true)
+        })
+        .map(|capture| {
+            let label = format!("{} {}{}", req.method(), host, path_query);
+            (
+                redacting_headers(capture, req.headers(), redact_headers),
+                label,
+            )
+        });
+    if let Some((capture, label)) = &capture {
+        let (parts, body) = req.into_parts();
+        let body = tee_body(
+            body,
+            format!("{} request", label),
+            capture,
+            settings.read_only,
+        );
+        req = Request::from_parts(parts, body);
+    }
+    let mut sent_gzip = false;
+    if let Some(gzip) = settings
+        .request_compression
+        .filter(|_| upstream_takes_gzip(&upstream))
+    {
+        let encoded = req.headers().contains_key(CONTENT_ENCODING);
+        req = compress_request(req, gzip.level, gzip.min_length);
+        sent_gzip = !encoded && req.headers().contains_key(CONTENT_ENCODING);
+    }
+
+    let policy = RetryPolicy::new(cfg, settings.retry_stale_connections);
+    let single_flight_key =
+        (settings.single_flight && upgrade.is_none() && (req.method() == Method::GET || is_head))
+            .then(|| flight_key(&req, &host, &path_query))
+            .flatten();
+    let response_deadline = cfg
+        .response_deadline_ms
+        .map(|ms| received + Duration::from_millis(ms));
+    if let Some(name) = &cfg.deadline_header {
+        let left =
+            response_deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let budget = [policy.attempt_timeout, policy.deadline, left]
+            .into_iter()
+            .flatten()
+            .min();
+        if let (Ok(name), Some(budget)) = (HeaderName::from_bytes(name.as_bytes()), budget) {
+            set_deadline_header(req.headers_mut(), &name, budget);
+        }
+    }
+    let sending = async {
+        match single_flight_key {
+            Some(key) => {
+                let client = client.clone();
+                single_flight(
+                    key,
+                    async move { send_upstream(&client, req, &policy).await },
+                )
+                .await
+            }
+            None => send_upstream(&client, req, &policy).await,
+        }
+    };
+    let sent = match response_deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), sending)
+            .await
+            .unwrap_or(Err(UpstreamError::Timeout)),
+        None => sending.await,
+    };
+    let mut res = match sent {
+        // The range was stripped, a partial answer cannot be what the
+        // client asked for.
+        Ok(res) if res.status() == StatusCode::PARTIAL_CONTENT && !settings.range_requests => {
+            log_error(&format!(
+                "{} upstream {} answered 206 with range requests disabled",
+                host, upstream
+            ));
+            return Err(upstream_failure(
+                ProxyError::UnexpectedPartialContent,
+                host_key,
+                cfg,
+            ));
+        }
+        Ok(res) => {
+            track_status(host_key, cfg, res.status());
+            if settings.request_compression.is_some() {
+                learn_request_gzip(&upstream, sent_gzip, &res);
+            }
+            if let Some(check) = &config.health {
+                if res.status().is_server_error() {
+                    mark_failure(&upstream, check);
+                } else {
+                    mark_success(&upstream);
+                }
+            }
+            watch_truncation(res, format!("{} response from {}", host, upstream))
+        }
+        Err(e) => {
+            if let Some(check) = &config.health {
+                mark_failure(&upstream, check);
+            }
+            log_error(&format!("{} upstream request failed: {}", host, e));
+            return Err(upstream_failure(e.into(), host_key, cfg));
+        }
+    };
+    if let Some((capture, label)) = &capture {
+        let (parts, body) = res.into_parts();
+        let label = format!("{} response {}", label, parts.status);
+        let capture = redacting_headers(capture, &parts.headers, redact_headers);
+        res = Response::from_parts(parts, tee_body(body, label, &capture, settings.read_only));
+    }
+    if let Some((name, id)) = request_id {
+        res.headers_mut().insert(name, id);
+    }
+    if let Some(via) = cfg.via.as_ref().filter(|via| via.response.unwrap_or(false)) {
+        let version = res.version();
+        append_via(res.headers_mut(), version, via.pseudonym());
+    }
+    if let (Some(protocol), Some(client_upgrade)) = (&upgrade, client_upgrade) {
+        if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+            let upstream_upgrade = hyper::upgrade::on(&mut res);
+            let label = format!("{} {} tunnel to {}", host, protocol, upstream);
+            let route = ws_slot.is_some().then(|| WsRoute {
+                host: host_key.clone(),
+                upstream: upstream.clone(),
+            });
+            spawn_tunnel(
+                client_upgrade,
+                upstream_upgrade,
+                label,
+                route,
+                (in_flight, ws_slot),
+            );
+            return Ok(res);
+        }
+    }
+    if let Some(charset) = &cfg.default_charset {
+        ensure_charset(res.headers_mut(), charset);
+    }
+    if !settings.range_requests {
+        res.headers_mut()
+            .insert(ACCEPT_RANGES, HeaderValue::from_static("none"));
+    }
+
+    if let Some(redaction) = cfg.json_redaction.as_ref().filter(|_| !is_head) {
+        res = redact_json(res, redaction).await;
+    }
+
+    let mut res = match settings.response_compression {
+        Some(gzip) if !is_head => {
+            maybe_compress(res, accept_encoding.as_ref(), gzip.level, gzip.min_length)
+        }
+        _ => res,
+    };
+    if let Some(bundle) = &cfg.security_headers {
+        apply_security_headers(res.headers_mut(), bundle, listener.tls);
+    }
+    if let Some(alt_svc) = settings.alt_svc.as_ref().filter(|_| listener.tls) {
+        if let Ok(value) = HeaderValue::from_str(alt_svc) {
+            res.headers_mut().insert(ALT_SVC, value);
+        }
+    }
+    if http10_client {
+        downgrade_to_http10(&mut res);
+    } else {
+        reset_connection_headers(res.headers_mut(), client_close);
+    }
+    let (parts, body) = res.into_parts();
+    // Whatever an upstream sends after the head of a HEAD response is not
+    // a body, only `Content-Length` is kept as it describes the GET.
+    let body = match response_deadline {
+        _ if is_head => Body::empty(),
+        Some(deadline) => cut_at_deadline(body, deadline, format!("{} response", host)),
+        None => body,
+    };
+    Ok(Response::from_parts(
+        parts,
+        hold_until_sent(body, (active, in_flight)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use axum::response::IntoResponse;
+    use hyper::header::{
+        ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE,
+        RETRY_AFTER, TRANSFER_ENCODING,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{config::Config, reload::new_shared_config, upstream::create_http_client};
+
+    /// Sends `req` through a proxy configured with `yaml` on plain port 80.
+    async fn proxy(yaml: &str, req: Request<Body>) -> Result<Response<Body>, ProxyError> {
+        let listener = Listener {
+            port: 80,
+            tls: false,
+        };
+        proxy_on(listener, yaml, req).await
+    }
+
+    async fn proxy_on(
+        listener: Listener,
+        yaml: &str,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, ProxyError> {
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        proxy_request(
+            req,
+            create_http_client(&Config::default()),
+            new_shared_config(config),
+            listener,
+        )
+        .await
+    }
+
+    /// An upstream answering every request with `respond`, returns its port.
+    fn upstream(respond: fn(Request<Body>) -> Response<Body>) -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let service = hyper::service::make_service_fn(move |_| async move {
+            Ok::<_, io::Error>(hyper::service::service_fn(move |req| async move {
+                Ok::<_, io::Error>(respond(req))
+            }))
+        });
+        tokio::spawn(hyper::Server::from_tcp(listener).unwrap().serve(service));
+        port
+    }
+
+    fn proxied_host(port: u16, extra: &str) -> String {
+        format!(
+            "hosts:\n  up.test:\n    ip: 127.0.0.1\n    port: {}\n    protocol: http\n{}",
+            port, extra
+        )
+    }
+
+    #[tokio::test]
+    async fn absolute_form_targets_are_rejected_unless_honored() {
+        let hosts = proxied_host(upstream(|_| Response::new(Body::empty())), "");
+        let req = || {
+            Request::get("http://up.test/")
+                .header(HOST, "other.test")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let e = proxy(&hosts, req()).await.unwrap_err();
+        assert!(matches!(e, ProxyError::AbsoluteFormRejected), "{:?}", e);
+        let honored = format!("absolute_form: honor\n{}", hosts);
+        assert_eq!(
+            proxy(&honored, req()).await.unwrap().status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn range_requests_pass_through_or_are_disabled() {
+        // Answers a range with 206 and anything else in full.
+        let port = upstream(|req| {
+            let range = req.headers().get(RANGE).is_some();
+            let res = Response::builder().header(CONTENT_TYPE, "text/plain");
+            let res = if range {
+                res.status(StatusCode::PARTIAL_CONTENT)
+                    .header(CONTENT_RANGE, "bytes 0-9/100")
+            } else {
+                res.status(StatusCode::OK)
+            };
+            res.body(Body::from(format!("range={}", range))).unwrap()
+        });
+        let req = || {
+            Request::get("/file.txt")
+                .header(HOST, "up.test")
+                .header(RANGE, "bytes=0-9")
+                .header(ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let compressed = format!("compression:\n  min_length: 0\n{}", proxied_host(port, ""));
+        let res = proxy(&compressed, req()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(!res.headers().contains_key(CONTENT_ENCODING));
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "range=true");
+
+        let disabled = proxied_host(port, "    range_requests: false\n");
+        let res = proxy(&disabled, req()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[ACCEPT_RANGES], "none");
+        assert!(!res.headers().contains_key(CONTENT_RANGE));
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "range=false");
+    }
+
+    #[tokio::test]
+    async fn partial_content_with_ranges_disabled_is_a_bad_gateway() {
+        // Ignores that the range was stripped.
+        let port = upstream(|_| {
+            Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, "bytes 0-9/100")
+                .body(Body::from("0123456789"))
+                .unwrap()
+        });
+        let req = Request::get("/file.txt")
+            .header(HOST, "up.test")
+            .header(RANGE, "bytes=0-9")
+            .body(Body::empty())
+            .unwrap();
+        let disabled = proxied_host(port, "    range_requests: false\n");
+        let e = proxy(&disabled, req).await.unwrap_err();
+        assert!(matches!(e, ProxyError::UnexpectedPartialContent), "{:?}", e);
+        assert_eq!(e.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    fn method_request(method: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("/")
+            .header(HOST, "up.test")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn blocked_methods_are_refused_before_routing() {
+        let hosts = proxied_host(upstream(|_| Response::new(Body::empty())), "");
+        let e = proxy(&hosts, method_request("TRACE")).await.unwrap_err();
+        assert_eq!(e.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let blocked = format!("blocked_methods: [delete]\n{}", hosts);
+        let e = proxy(&blocked, method_request("DELETE")).await.unwrap_err();
+        assert!(matches!(e, ProxyError::MethodNotAllowed(ref m) if m == Method::DELETE));
+        assert!(proxy(&blocked, method_request("TRACE")).await.is_ok());
+        let open = format!("blocked_methods: []\n{}", hosts);
+        assert!(proxy(&open, method_request("TRACE")).await.is_ok());
+    }
+
+    #[test]
+    fn accept_routes_follow_client_preference() {
+        let host: Host = serde_yaml::from_str(
+            "ip: 127.0.0.1\nport: 9000\nprotocol: http\naccept_routes:\n  - media_type: application/vnd.v2+json\n    upstream: http://127.0.0.2:9000\n  - media_type: text/html\n    upstream: http://127.0.0.3:9000\n",
+        )
+        .unwrap();
+        let route = |accept: &str| {
+            let req = Request::get("/").header(ACCEPT, accept).body(()).unwrap();
+            select_accept_route(&req, &host).map(|target| target.ip)
+        };
+        assert_eq!(route("application/vnd.v2+json"), Some("127.0.0.2".into()));
+        assert_eq!(
+            route("text/html;q=0.9, application/vnd.v2+json"),
+            Some("127.0.0.2".into())
+        );
+        assert_eq!(
+            route("application/json, text/html;q=0.5"),
+            Some("127.0.0.3".into())
+        );
+        assert_eq!(route("*/*"), None);
+        assert_eq!(route("text/html;q=0"), None);
+    }
+
+    #[test]
+    fn only_trusted_proxies_may_set_forwarding_headers() {
+        let policy: TrustedIncomingHeaders =
+            serde_yaml::from_str("trusted_proxies: [10.0.0.0/8]").unwrap();
+        let stripped = |client_ip: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", HeaderValue::from_static("192.0.2.1"));
+            headers.insert("x-real-ip", HeaderValue::from_static("192.0.2.1"));
+            headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
+            let client_ip = client_ip.map(|ip| ip.parse().unwrap());
+            strip_untrusted_headers(&mut headers, &policy, client_ip);
+            headers.len() == 1
+        };
+        assert!(!stripped(Some("10.1.2.3")));
+        assert!(stripped(Some("192.0.2.7")));
+        assert!(stripped(None));
+
+        let custom: TrustedIncomingHeaders =
+            serde_yaml::from_str("trusted_proxies: []\nheaders: [X-Real-IP]").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("192.0.2.1"));
+        headers.insert("x-real-ip", HeaderValue::from_static("192.0.2.1"));
+        strip_untrusted_headers(&mut headers, &custom, None);
+        assert!(headers.contains_key("x-forwarded-for"));
+        assert!(!headers.contains_key("x-real-ip"));
+    }
+
+    fn up_request(uri: &str) -> Request<Body> {
+        Request::get(uri)
+            .header(HOST, "up.test")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn body_of(res: Result<Response<Body>, ProxyError>) -> String {
+        let body = hyper::body::to_bytes(res.unwrap().into_body()).await;
+        String::from_utf8(body.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn upstream_version_is_set_per_host() {
+        let port = upstream(|req| Response::new(Body::from(format!("{:?}", req.version()))));
+        let http1 = proxied_host(port, "");
+        assert_eq!(
+            body_of(proxy(&http1, up_request("/")).await).await,
+            "HTTP/1.1"
+        );
+        let http2 = proxied_host(port, "    upstream_version: http2\n");
+        assert_eq!(
+            body_of(proxy(&http2, up_request("/")).await).await,
+            "HTTP/2.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn hosts_without_healthy_upstreams_answer_the_configured_response() {
+        let check: crate::config::HealthCheck = serde_yaml::from_str("max_failures: 1").unwrap();
+        mark_failure("127.0.0.97:9", &check);
+        let host = |extra: &str| {
+            format!(
+                "health:\n  max_failures: 1\nhosts:\n  up.test:\n    ip: 127.0.0.97\n    port: 9\n    protocol: http\n{}",
+                extra
+            )
+        };
+        let e = proxy(&host(""), up_request("/")).await.unwrap_err();
+        assert!(matches!(e, ProxyError::NoHealthyUpstream), "{:?}", e);
+
+        let custom = host("    no_upstream_response:\n      body: back soon\n");
+        let res = proxy(&custom, up_request("/")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_of(Ok(res)).await, "back soon");
+    }
+
+    #[test]
+    fn raw_paths_keep_their_bytes() {
+        let target = Target::parse("https://10.0.0.1:8443").unwrap();
+        let swapped = |uri: &str| swap_authority(&uri.parse().unwrap(), &target).unwrap();
+        assert_eq!(
+            swapped("/a%2fb//c/../d?q=a%20b&&x").to_string(),
+            "https://10.0.0.1:8443/a%2fb//c/../d?q=a%20b&&x"
+        );
+        assert_eq!(
+            swapped("http://user@example.com:80/p?q").to_string(),
+            "https://10.0.0.1:8443/p?q"
+        );
+        assert_eq!(
+            swapped("http://example.com").to_string(),
+            "https://10.0.0.1:8443/"
+        );
+    }
+
+    #[tokio::test]
+    async fn alt_svc_is_advertised_on_https_only() {
+        let port = upstream(|_| Response::new(Body::empty()));
+        let yaml = format!(
+            "ssl_port: 8443\nalt_svc:\n  protocols: [h2, http%2F1.1]\n  max_age_secs: 60\n{}",
+            proxied_host(port, "")
+        );
+        let https = Listener {
+            port: 8443,
+            tls: true,
+        };
+        let res = proxy_on(https, &yaml, up_request("/")).await.unwrap();
+        assert_eq!(
+            res.headers()[ALT_SVC],
+            "h2=\":8443\"; ma=60, http%2F1.1=\":8443\"; ma=60"
+        );
+        let res = proxy(&yaml, up_request("/")).await.unwrap();
+        assert!(!res.headers().contains_key(ALT_SVC));
+    }
+
+    #[tokio::test]
+    async fn unknown_hosts_get_the_configured_answer() {
+        let req = || {
+            Request::get("/")
+                .header(HOST, "nowhere.test")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let hosts = "hosts: {}\n";
+        let e = proxy(hosts, req()).await.unwrap_err();
+        assert_eq!(e.status(), StatusCode::FAILED_DEPENDENCY);
+
+        let custom = format!(
+            "unknown_host_response:\n  status: 404\n  body: no such site\n  content_type: text/plain\n{}",
+            hosts
+        );
+        let res = proxy(&custom, req()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(body_of(Ok(res)).await, "no such site");
+
+        let close = format!("unknown_host_response:\n  close: true\n{}", hosts);
+        let res = proxy(&close, req()).await.unwrap();
+        assert!(res.extensions().get::<DropConnection>().is_some());
+    }
+
+    #[tokio::test]
+    async fn empty_hosts_are_bad_requests() {
+        for value in ["", " \t"] {
+            let req = Request::get("/").header(HOST, value).body(Body::empty());
+            let e = proxy("hosts: {}\n", req.unwrap()).await.unwrap_err();
+            assert!(matches!(e, ProxyError::EmptyHost), "{:?}: {:?}", value, e);
+        }
+    }
+
+    #[tokio::test]
+    async fn aggregate_rate_limit_covers_every_client() {
+        // Slow enough that no token comes back during the test.
+        let yaml = &proxied_host(
+            upstream(|_| Response::new(Body::empty())),
+            "    aggregate_rate_limit:\n      requests_per_sec: 0.001\n      burst: 2\n",
+        );
+        let from = |ip: &str| {
+            let mut req = Request::get("/")
+                .header(HOST, "up.test")
+                .body(Body::empty())
+                .unwrap();
+            let addr = SocketAddr::new(ip.parse().unwrap(), 40000);
+            req.extensions_mut().insert(ConnectInfo(addr));
+            req
+        };
+        assert!(proxy(yaml, from("192.0.2.1")).await.is_ok());
+        assert!(proxy(yaml, from("192.0.2.2")).await.is_ok());
+        let e = proxy(yaml, from("192.0.2.3")).await.unwrap_err();
+        assert_eq!(e.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    /// Reads up to the blank line ending a message head.
+    async fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// Serves the proxy with `config` on a port of its own, for tests that
+    /// need a real client connection.
+    fn serve(config: Config) -> SocketAddr {
+        let preserve_header_case = config.preserve_header_case.unwrap_or(false);
+        let client = create_http_client(&config);
+        let shared = new_shared_config(config);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = hyper::service::make_service_fn(move |_| {
+            let (client, shared) = (client.clone(), shared.clone());
+            async move {
+                Ok::<_, io::Error>(hyper::service::service_fn(move |req| {
+                    let listener = Listener {
+                        port: 80,
+                        tls: false,
+                    };
+                    let res = proxy_request(req, client.clone(), shared.clone(), listener);
+                    async move { Ok::<_, io::Error>(res.await.unwrap()) }
+                }))
+            }
+        });
+        let server = hyper::Server::from_tcp(listener)
+            .unwrap()
+            .http1_preserve_header_case(preserve_header_case)
+            .serve(service);
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn other_upgrade_protocols_get_a_raw_tunnel() {
+        // Switches to `custom` and echoes every byte back.
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let head = read_head(&mut stream).await.to_ascii_lowercase();
+            assert!(head.contains("upgrade: custom"), "{}", head);
+            assert!(!head.contains("sec-websocket"), "{}", head);
+            stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nconnection: upgrade\r\nupgrade: custom\r\n\r\n")
+                .await
+                .unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+
+        let proxy_addr = serve(serde_yaml::from_str(&proxied_host(port, "")).unwrap());
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream
+            .write_all(b"GET /raw HTTP/1.1\r\nhost: up.test\r\nconnection: upgrade\r\nupgrade: custom\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        stream.write_all(b"raw bytes").await.unwrap();
+        let mut echoed = [0; 9];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"raw bytes");
+    }
+
+    /// Writes `raw` as the only request on a new connection and returns
+    /// everything read until the proxy closes it.
+    async fn exchange_until_closed(addr: SocketAddr, raw: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(raw).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            stream.read_to_end(&mut response),
+        )
+        .await
+        .expect("the connection was kept open")
+        .unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn ambiguous_framing_over_a_socket_gets_400_and_close() {
+        // Reports the framing headers it received.
+        let port = upstream(|req| {
+            let header = |name| {
+                req.headers()
+                    .get_all(name)
+                    .iter()
+                    .map(|v| v.to_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            let body = format!(
+                "cl=[{}] te=[{}]",
+                header(CONTENT_LENGTH),
+                header(TRANSFER_ENCODING)
+            );
+            Response::new(Body::from(body))
+        });
+        let addr = serve(serde_yaml::from_str(&proxied_host(port, "")).unwrap());
+        let rejected: [&[u8]; 3] = [
+            // Caught by the proxy.
+            b"POST / HTTP/1.1\r\nhost: up.test\r\ncontent-length: 5\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nhost: up.test\r\ntransfer-encoding: chunked\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n",
+            // Refused by hyper's parser.
+            b"POST / HTTP/1.1\r\nhost: up.test\r\ncontent-length: 1\r\ncontent-length: 2\r\n\r\nab",
+        ];
+        for raw in rejected {
+            let response = exchange_until_closed(addr, raw).await;
+            assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        }
+
+        // hyper drops a Content-Length that follows Transfer-Encoding, and
+        // merges repeats of the same length, before the proxy sees the
+        // request. Both reach the upstream with a single framing.
+        let forwarded: [(&[u8], &str); 2] = [
+            (
+                b"POST / HTTP/1.1\r\nhost: up.test\r\nconnection: close\r\ntransfer-encoding: chunked\r\ncontent-length: 5\r\n\r\n0\r\n\r\n",
+                "cl=[] te=[chunked]",
+            ),
+            (
+                b"POST / HTTP/1.1\r\nhost: up.test\r\nconnection: close\r\ncontent-length: 2\r\ncontent-length: 2\r\n\r\nab",
+                "cl=[2] te=[]",
+            ),
+        ];
+        for (raw, framing) in forwarded {
+            let response = exchange_until_closed(addr, raw).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert!(response.ends_with(framing), "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn repeated_content_length_over_http2_is_rejected() {
+        // h2 checks the body against the first Content-Length only.
+        let port = upstream(|_| Response::new(Body::empty()));
+        let addr = serve(serde_yaml::from_str(&proxied_host(port, "")).unwrap());
+        let client = hyper::Client::builder()
+            .http2_only(true)
+            .build_http::<Body>();
+        let mut req = Request::post(format!("http://{}/", addr))
+            .header(HOST, "up.test")
+            .body(Body::from("ab"))
+            .unwrap();
+        req.headers_mut()
+            .append(CONTENT_LENGTH, HeaderValue::from_static("2"));
+        req.headers_mut()
+            .append(CONTENT_LENGTH, HeaderValue::from_static("2"));
+        let res = client.request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn a_saturated_isolated_pool_does_not_block_other_hosts() {
+        // Takes every request and never answers, holding its connection.
+        let noisy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let noisy_port = noisy.local_addr().unwrap().port();
+        let held = Arc::new(AtomicUsize::new(0));
+        let holding = held.clone();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((mut stream, _)) = noisy.accept().await {
+                read_head(&mut stream).await;
+                holding.fetch_add(1, Ordering::SeqCst);
+                streams.push(stream);
+            }
+        });
+        let quiet_port = upstream(|_| Response::new(Body::from("quiet")));
+        let yaml = format!(
+            "hosts:\n  noisy.test:\n    ip: 127.0.0.1\n    port: {}\n    protocol: http\n    isolated_pool: true\n  quiet.test:\n    ip: 127.0.0.1\n    port: {}\n    protocol: http\n",
+            noisy_port, quiet_port
+        );
+        let proxy_addr = serve(serde_yaml::from_str(&yaml).unwrap());
+        let client = hyper::Client::new();
+        let get = move |host: &'static str| {
+            let req = Request::get(format!("http://{}/", proxy_addr))
+                .header(HOST, host)
+                .body(Body::empty())
+                .unwrap();
+            client.request(req)
+        };
+
+        let stuck = 32;
+        for _ in 0..stuck {
+            tokio::spawn(get("noisy.test"));
+        }
+        let saturated = async {
+            while held.load(Ordering::SeqCst) < stuck {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), saturated)
+            .await
+            .expect("the noisy upstream never got every request");
+
+        let res = tokio::time::timeout(std::time::Duration::from_secs(2), get("quiet.test"))
+            .await
+            .expect("the quiet host was blocked")
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "quiet");
+    }
+
+    #[tokio::test]
+    async fn head_responses_keep_the_length_but_no_body() {
+        let port = upstream(|_| {
+            Response::builder()
+                .header(CONTENT_TYPE, "text/plain")
+                .body(Body::from("hello world"))
+                .unwrap()
+        });
+        let req = Request::head("/")
+            .header(HOST, "up.test")
+            .body(Body::empty())
+            .unwrap();
+        let res = proxy(&proxied_host(port, ""), req).await.unwrap();
+        assert_eq!(res.headers()[CONTENT_LENGTH], "11");
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn header_case_reaches_the_upstream_when_preserved() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        let heads = tokio::spawn(async move {
+            let mut heads = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                heads.push(read_head(&mut stream).await);
+                stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+            heads
+        });
+        for preserve in [true, false] {
+            let yaml = format!(
+                "preserve_header_case: {}\n{}",
+                preserve,
+                proxied_host(port, "")
+            );
+            let mut stream = TcpStream::connect(serve(serde_yaml::from_str(&yaml).unwrap()))
+                .await
+                .unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: up.test\r\nX-Legacy-Token: 1\r\n\r\n")
+                .await
+                .unwrap();
+            let head = read_head(&mut stream).await;
+            assert!(head.starts_with("HTTP/1.1 204"), "{}", head);
+        }
+        let heads = heads.await.unwrap();
+        assert!(heads[0].contains("X-Legacy-Token: 1"), "{}", heads[0]);
+        assert!(heads[1].contains("x-legacy-token: 1"), "{}", heads[1]);
+    }
+
+    #[test]
+    fn regex_routes_are_tried_in_order() {
+        let host: Host = serde_yaml::from_str(
+            "ip: 127.0.0.1\nport: 9000\nprotocol: http\nregex_routes:\n  - pattern: ^/users/\\d+/posts\n    upstream: http://127.0.0.2:9000\n  - pattern: ^/users/\n    upstream: http://127.0.0.3:9000\n",
+        )
+        .unwrap();
+        let route = |path: &str| select_regex_route(path, &host).map(|target| target.ip);
+        assert_eq!(route("/users/42/posts/7"), Some("127.0.0.2".into()));
+        assert_eq!(route("/users/me/posts"), Some("127.0.0.3".into()));
+        assert_eq!(route("/groups/1"), None);
+
+        let invalid = |pattern: &str| {
+            serde_yaml::from_str::<Host>(&format!(
+                "ip: 127.0.0.1\nport: 9000\nprotocol: http\nregex_routes:\n  - pattern: '{}'\n    upstream: http://127.0.0.2:9000\n",
+                pattern
+            ))
+            .is_err()
+        };
+        assert!(invalid("^/users/(\\d+"));
+        assert!(invalid(&"a".repeat(2000)));
+        assert!(!invalid("^/users/\\d+$"));
+    }
+
+    /// An upstream writing `head` on every connection and then going quiet
+    /// with the connection open. Returns its port.
+    async fn stalling_upstream(head: &'static [u8]) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                read_head(&mut stream).await;
+                stream.write_all(head).await.unwrap();
+                held.push(stream);
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn response_deadline_answers_504_or_cuts_the_body() {
+        let deadline = "    response_deadline_ms: 200\n";
+        let silent = stalling_upstream(b"").await;
+        let e = proxy(&proxied_host(silent, deadline), up_request("/"))
+            .await
+            .unwrap_err();
+        assert_eq!(e.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let partial =
+            stalling_upstream(b"HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\nfirst bytes").await;
+        let cuts = || METRICS.responses_cut_at_deadline.load(Ordering::Relaxed);
+        let before = cuts();
+        let res = proxy(&proxied_host(partial, deadline), up_request("/"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(hyper::body::to_bytes(res.into_body()).await.is_err());
+        assert!(cuts() > before);
+    }
+
+    #[tokio::test]
+    async fn disabled_keepalive_opens_a_connection_per_request() {
+        // Keeps every connection alive and counts them.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                        let head = String::from_utf8_lossy(&buf[..n]);
+                        let close = head.contains("connection: close");
+                        let res = format!(
+                            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nx-close: {}\r\n\r\n",
+                            close
+                        );
+                        stream.write_all(res.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        for (extra, opened) in [("", 1), ("    disable_keepalive: true\n", 3)] {
+            connections.store(0, Ordering::SeqCst);
+            let config: Config = serde_yaml::from_str(&proxied_host(port, extra)).unwrap();
+            let client = create_http_client(&config);
+            let shared = new_shared_config(config);
+            for _ in 0..3 {
+                let listener = Listener {
+                    port: 80,
+                    tls: false,
+                };
+                let res = handle_request(up_request("/"), client.clone(), shared.clone(), listener)
+                    .await
+                    .unwrap();
+                assert_eq!(res.headers()["x-close"], (opened == 3).to_string());
+                hyper::body::to_bytes(res.into_body()).await.unwrap();
+            }
+            assert_eq!(connections.load(Ordering::SeqCst), opened);
+        }
+    }
+
+    #[tokio::test]
+    async fn header_limit_counts_the_connection_rewrite() {
+        let limited = |extra: &str| {
+            proxied_host(
+                9,
+                &format!("    upstream_header_limit:\n      max_bytes: 30\n{}", extra),
+            )
+        };
+        // The `host` header fits in 30 bytes, with `connection: close` it
+        // does not.
+        let closing = limited("    disable_keepalive: true\n");
+        let e = proxy(&closing, up_request("/")).await.unwrap_err();
+        assert!(matches!(e, ProxyError::HeadersTooLarge), "{:?}", e);
+        let e = proxy(&limited(""), up_request("/")).await.unwrap_err();
+        assert!(!matches!(e, ProxyError::HeadersTooLarge), "{:?}", e);
+    }
+
+    #[tokio::test]
+    async fn header_limit_counts_the_request_id() {
+        let limited = |max_bytes: usize| {
+            let limit = format!(
+                "    upstream_header_limit:\n      max_bytes: {}\n",
+                max_bytes
+            );
+            format!(
+                "request_id_header: x-request-id\n{}",
+                proxied_host(9, &limit)
+            )
+        };
+        // The `host` header fits in 40 bytes, the request id does not.
+        let e = proxy(&limited(40), up_request("/")).await.unwrap_err();
+        assert!(matches!(e, ProxyError::HeadersTooLarge), "{:?}", e);
+        let e = proxy(&limited(400), up_request("/")).await.unwrap_err();
+        assert!(!matches!(e, ProxyError::HeadersTooLarge), "{:?}", e);
+    }
+
+    #[tokio::test]
+    async fn bodies_cut_by_the_upstream_are_signaled() {
+        // Declares 100 bytes, sends 11 and hangs up.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                read_head(&mut stream).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\nfirst bytes")
+                    .await;
+            }
+        });
+        let truncated = || METRICS.upstream_truncated_bodies.load(Ordering::Relaxed);
+        let before = truncated();
+        let res = proxy(&proxied_host(port, ""), up_request("/"))
+            .await
+            .unwrap();
+        assert!(hyper::body::to_bytes(res.into_body()).await.is_err());
+        assert!(truncated() > before);
+    }
+
+    const ECHO: &str = "hosts:\n  echo.test:\n    protocol: echo\n";
+
+    fn echo_request(method: &str, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(HOST, "echo.test")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn long_uris_are_refused() {
+        let uri = format!("/{}", "a".repeat(8192));
+        let e = proxy(ECHO, echo_request("GET", &uri)).await.unwrap_err();
+        assert_eq!(e.status(), StatusCode::URI_TOO_LONG);
+        let limited = format!("max_uri_length: 10\n{}", ECHO);
+        let e = proxy(&limited, echo_request("GET", "/?q=1234567"))
+            .await
+            .unwrap_err();
+        assert_eq!(e.status(), StatusCode::URI_TOO_LONG);
+        assert!(proxy(&limited, echo_request("GET", "/?q=123456"))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn upstream_scoped_hosts_share_their_limits() {
+        let port = upstream(|_| Response::new(Body::empty()));
+        let hosts = |scope: &str, prefix: &str| {
+            let host = |name: &str| {
+                format!(
+                    "  {}{}:\n    ip: 127.0.0.1\n    port: {}\n    protocol: http\n    state_scope: {}\n    aggregate_rate_limit:\n      requests_per_sec: 0.001\n      burst: 1\n",
+                    prefix, name, port, scope
+                )
+            };
+            format!("hosts:\n{}{}", host("a.test"), host("b.test"))
+        };
+        let status = |yaml: String, host: String| async move {
+            let req = Request::get("/")
+                .header(HOST, host)
+                .body(Body::empty())
+                .unwrap();
+            match proxy(&yaml, req).await {
+                Ok(res) => res.status(),
+                Err(e) => e.status(),
+            }
+        };
+        let shared = hosts("upstream", "shared-");
+        assert_eq!(
+            status(shared.clone(), "shared-a.test".into()).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(shared, "shared-b.test".into()).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        let own = hosts("host", "own-");
+        assert_eq!(
+            status(own.clone(), "own-a.test".into()).await,
+            StatusCode::OK
+        );
+        assert_eq!(status(own, "own-b.test".into()).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn generated_unavailable_answers_carry_retry_after() {
+        let check: crate::config::HealthCheck = serde_yaml::from_str("max_failures: 1").unwrap();
+        mark_failure("127.0.0.163:9", &check);
+        let yaml = "health:\n  max_failures: 1\nhosts:\n  up.test:\n    ip: 127.0.0.163\n    port: 9\n    protocol: http\n    error_retry_after_secs: 30\n";
+        let res = proxy(yaml, up_request("/"))
+            .await
+            .unwrap_err()
+            .into_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[RETRY_AFTER], "30");
+    }
+
+    #[tokio::test]
+    async fn h2c_upgrades_are_ignored_by_default() {
+        let port = upstream(|req| {
+            let upgrade = req.headers().get(hyper::header::UPGRADE);
+            Response::new(Body::from(format!("{:?}", upgrade)))
+        });
+        let req = Request::get("/")
+            .header(HOST, "up.test")
+            .header(CONNECTION, "Upgrade, HTTP2-Settings")
+            .header(hyper::header::UPGRADE, "h2c")
+            .header("http2-settings", "AAMAAABkAAQAAP__")
+            .body(Body::empty())
+            .unwrap();
+        let res = proxy(&proxied_host(port, ""), req).await;
+        assert_eq!(body_of(res).await, "None");
+    }
+
+    #[tokio::test]
+    async fn request_ids_use_the_configured_header() {
+        let port = upstream(|req| {
+            let id = req.headers().get("x-correlation-id").cloned();
+            Response::new(Body::from(format!("{:?}", id)))
+        });
+        let yaml = format!(
+            "request_id_header: x-request-id\n{}",
+            proxied_host(port, "    request_id_header: x-correlation-id\n")
+        );
+        let sent = |id: Option<&str>| {
+            let mut req = Request::get("/").header(HOST, "up.test");
+            if let Some(id) = id {
+                req = req.header("x-correlation-id", id);
+            }
+            proxy(&yaml, req.body(Body::empty()).unwrap())
+        };
+        let res = sent(None).await.unwrap();
+        assert!(!res.headers().contains_key("x-request-id"));
+        let id = res.headers()["x-correlation-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(id.len(), 32);
+        assert_eq!(body_of(Ok(res)).await, format!("Some({:?})", id));
+
+        let res = sent(Some("abc-123")).await.unwrap();
+        assert_eq!(res.headers()["x-correlation-id"], "abc-123");
+        assert_eq!(body_of(Ok(res)).await, "Some(\"abc-123\")");
+
+        let res = sent(Some("not usable")).await.unwrap();
+        assert_ne!(res.headers()["x-correlation-id"], "not usable");
+    }
+
+    #[tokio::test]
+    async fn websocket_limit_is_not_an_upstream_error() {
+        let yaml = proxied_host(
+            9,
+            "    max_ws_connections: 0\n    error_retry_after_secs: 5\n    error_alert:\n      threshold: 0.0\n      min_requests: 1\n",
+        );
+        let req = Request::get("/chat")
+            .header(HOST, "up.test")
+            .header(CONNECTION, "upgrade")
+            .header(hyper::header::UPGRADE, "websocket")
+            .body(Body::empty())
+            .unwrap();
+        let alerts = METRICS.error_rate_alerts.load(Ordering::Relaxed);
+        let rejected = METRICS.websockets_rejected.load(Ordering::Relaxed);
+        let e = proxy(&yaml, req).await.unwrap_err();
+        assert_eq!(e.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(matches!(e, ProxyError::RetryAfter(_, 5)), "{:?}", e);
+        assert_eq!(
+            METRICS.websockets_rejected.load(Ordering::Relaxed),
+            rejected + 1
+        );
+        assert_eq!(METRICS.error_rate_alerts.load(Ordering::Relaxed), alerts);
+    }
+
+    #[tokio::test]
+    async fn client_close_ends_only_the_client_connection() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        let seen = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let head = read_head(&mut stream).await.to_ascii_lowercase();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nconnection: keep-alive\r\nkeep-alive: timeout=5\r\ncontent-length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+            head
+        });
+        let proxy_addr = serve(serde_yaml::from_str(&proxied_host(port, "")).unwrap());
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: up.test\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut stream).await.to_ascii_lowercase();
+        assert!(head.contains("connection: close"), "{}", head);
+        assert!(!head.contains("keep-alive"), "{}", head);
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut rest));
+        read.await.unwrap().unwrap();
+        assert_eq!(rest, b"ok");
+        let upstream_head = seen.await.unwrap();
+        assert!(!upstream_head.contains("close"), "{}", upstream_head);
+    }
+
+    #[tokio::test]
+    async fn require_https_hosts_stay_off_plain_http() {
+        let yaml = format!(
+            "ssl: true\nssl_port: 8443\n{}  secure.test:\n    protocol: echo\n    require_https: true\n",
+            ECHO
+        );
+        let req = |method: &str, host: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/a?b=1")
+                .header(HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = proxy(&yaml, req("GET", "secure.test:80")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers()[LOCATION], "https://secure.test:8443/a?b=1");
+        let e = proxy(&yaml, req("POST", "secure.test")).await.unwrap_err();
+        assert_eq!(e.status(), StatusCode::FORBIDDEN);
+        let res = proxy(&yaml, req("GET", "echo.test")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let https = Listener {
+            port: 8443,
+            tls: true,
+        };
+        let res = proxy_on(https, &yaml, req("POST", "secure.test"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+use crate::{
+    alert::prune_error_windows,
+    compress::prune_request_gzip,
+    health::prune_health,
+    ratelimit::prune_buckets,
+    reload::{snapshot, SharedConfig},
+    upstream::prune_isolated_clients,
+};
+
+/// Every `prune_interval_secs`, drops the per-client, per-upstream and
+/// per-host state nothing uses any more, so those maps stay bounded.
+pub fn spawn_prune_task(shared: SharedConfig) {
+    tokio::spawn(async move {
+        loop {
+            let interval =
+                Duration::from_secs(snapshot(&shared).prune_interval_secs.unwrap_or(60).max(1));
+            tokio::time::sleep(interval).await;
+            prune_buckets(interval);
+            prune_health(interval);
+            let config = snapshot(&shared);
+            prune_isolated_clients(&config);
+            prune_request_gzip(&config);
+            prune_error_windows(&config);
+        }
+    });
+}
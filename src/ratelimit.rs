@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::config::{HostRateLimit, RateLimit};
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    /// The rate and size it was last refilled with.
+    requests_per_sec: f64,
+    burst: f64,
+}
+
+impl Bucket {
+    fn full(requests_per_sec: f64, burst: f64) -> Self {
+        Bucket {
+            tokens: burst,
+            last: Instant::now(),
+            requests_per_sec,
+            burst,
+        }
+    }
+
+    /// Whether it would be full by now, so dropping it loses nothing.
+    fn refilled(&self) -> bool {
+        self.tokens + self.last.elapsed().as_secs_f64() * self.requests_per_sec >= self.burst
+    }
+}
+
+/// Client ip and, for methods with their own limit, the method.
+type BucketKey = (IpAddr, Option<String>);
+
+static BUCKETS: LazyLock<Mutex<HashMap<BucketKey, Bucket>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Keyed by the host's config key.
+static HOST_BUCKETS: LazyLock<Mutex<HashMap<String, Bucket>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Refills `bucket` for the time since it was last used and takes a token if
+/// one is left.
+fn take_token(bucket: &mut Bucket, requests_per_sec: f64, burst: f64) -> bool {
+    let now = Instant::now();
+    let elapsed = now.duration_since(bucket.last).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * requests_per_sec).min(burst);
+    bucket.last = now;
+    bucket.requests_per_sec = requests_per_sec;
+    bucket.burst = burst;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        true
+    } else {
+        false
+    }
+}
+
+/// Takes a token from the client's bucket for `method`, `false` means the
+/// client is over its limit.
+pub fn check_rate_limit(ip: IpAddr, method: &str, limit: &RateLimit) -> bool {
+    let method_limit = limit
+        .methods
+        .iter()
+        .flatten()
+        .find(|(m, _)| m.eq_ignore_ascii_case(method));
+    let (key, requests_per_sec, burst) = match method_limit {
+        Some((_, l)) => (
+            Some(method.to_ascii_uppercase()),
+            l.requests_per_sec,
+            l.burst,
+        ),
+        None => (None, limit.requests_per_sec, limit.burst),
+    };
+    let burst = burst.unwrap_or(requests_per_sec.ceil() as u32) as f64;
+    let mut buckets = BUCKETS.lock().unwrap();
+    let bucket = buckets
+        .entry((ip, key))
+        .or_insert_with(|| Bucket::full(requests_per_sec, burst));
+    take_token(bucket, requests_per_sec, burst)
+}
+
+/// Takes a token from the bucket all of `domain`'s requests share, `false`
+/// means the host is over its limit.
+pub fn check_host_rate_limit(domain: &str, limit: &HostRateLimit) -> bool {
+    let burst = limit.burst.unwrap_or(limit.requests_per_sec.ceil() as u32) as f64;
+    let mut buckets = HOST_BUCKETS.lock().unwrap();
+    let bucket = buckets
+        .entry(domain.to_string())
+        .or_insert_with(|| Bucket::full(limit.requests_per_sec, burst));
+    take_token(bucket, limit.requests_per_sec, burst)
+}
+
+/// Drops buckets untouched for `idle` that have refilled since, a returning
+/// client gets the same full bucket it would have had. Slow-refilling buckets
+/// stay until they are full, so pruning never resets a client's limit early.
+pub fn prune_buckets(idle: Duration) -> usize {
+    prune_idle(&mut BUCKETS.lock().unwrap(), idle)
+        + prune_idle(&mut HOST_BUCKETS.lock().unwrap(), idle)
+}
+
+fn prune_idle<K>(buckets: &mut HashMap<K, Bucket>, idle: Duration) -> usize {
+    let before = buckets.len();
+    buckets.retain(|_, bucket| bucket.last.elapsed() < idle || !bucket.refilled());
+    before - buckets.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(requests_per_sec: f64, burst: u32) -> RateLimit {
+        serde_yaml::from_str(&format!(
+            "requests_per_sec: {}\nburst: {}",
+            requests_per_sec, burst
+        ))
+        .unwrap()
+    }
+
+    fn bucket(ip: IpAddr) -> Option<f64> {
+        BUCKETS.lock().unwrap().get(&(ip, None)).map(|b| b.tokens)
+    }
+
+    #[test]
+    fn prune_keeps_buckets_still_refilling() {
+        // One token every 1000s, so the drained bucket stays empty for the test.
+        let limit = limit(0.001, 1);
+        let ip: IpAddr = "192.0.2.201".parse().unwrap();
+        assert!(check_rate_limit(ip, "GET", &limit));
+        assert!(!check_rate_limit(ip, "GET", &limit));
+        prune_buckets(Duration::ZERO);
+        assert!(bucket(ip).is_some());
+        assert!(!check_rate_limit(ip, "GET", &limit));
+    }
+
+    #[test]
+    fn prune_drops_refilled_buckets() {
+        let limit = limit(1000.0, 1);
+        let ip: IpAddr = "192.0.2.202".parse().unwrap();
+        assert!(check_rate_limit(ip, "GET", &limit));
+        std::thread::sleep(Duration::from_millis(10));
+        prune_buckets(Duration::ZERO);
+        assert!(bucket(ip).is_none());
+    }
+
+    #[test]
+    fn prune_shrinks_the_client_buckets_to_the_live_ones() {
+        let idle = Duration::from_secs(1);
+        let long_ago = Instant::now().checked_sub(2 * idle).unwrap();
+        let mut buckets: HashMap<IpAddr, Bucket> = HashMap::new();
+        for i in 0..1000u32 {
+            let ip = IpAddr::from((0x0a00_0000 + i).to_be_bytes());
+            let mut bucket = Bucket::full(10.0, 10.0);
+            bucket.tokens -= 1.0;
+            // Gone quiet long enough ago to have refilled.
+            bucket.last = long_ago;
+            buckets.insert(ip, bucket);
+        }
+        let live: Vec<IpAddr> = buckets.keys().take(10).cloned().collect();
+        for ip in &live {
+            let bucket = buckets.get_mut(ip).unwrap();
+            bucket.tokens -= 1.0;
+            bucket.last = Instant::now();
+        }
+        assert_eq!(prune_idle(&mut buckets, idle), 990);
+        let mut left: Vec<&IpAddr> = buckets.keys().collect();
+        let mut live: Vec<&IpAddr> = live.iter().collect();
+        left.sort();
+        live.sort();
+        assert_eq!(left, live);
+    }
+
+    #[test]
+    fn listed_methods_get_their_own_bucket() {
+        // Slow enough that no token comes back during the test.
+        let limit: RateLimit = serde_yaml::from_str(
+            "requests_per_sec: 0.001\nburst: 1\nmethods:\n  post:\n    requests_per_sec: 0.001\n    burst: 2\n",
+        )
+        .unwrap();
+        let ip: IpAddr = "192.0.2.102".parse().unwrap();
+        assert!(check_rate_limit(ip, "GET", &limit));
+        assert!(!check_rate_limit(ip, "GET", &limit));
+        assert!(!check_rate_limit(ip, "DELETE", &limit));
+        assert!(check_rate_limit(ip, "POST", &limit));
+        assert!(check_rate_limit(ip, "post", &limit));
+        assert!(!check_rate_limit(ip, "POST", &limit));
+        let other: IpAddr = "192.0.2.103".parse().unwrap();
+        assert!(check_rate_limit(other, "GET", &limit));
+    }
+}
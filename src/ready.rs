@@ -0,0 +1,183 @@
+use std::fs;
+
+use tokio::sync::mpsc;
+
+use crate::{
+    config::Config,
+    log::{log_error, log_info},
+};
+
+/// Held by a listener until its socket is bound, dropping it reports the
+/// listener as up.
+pub struct BindPending {
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl BindPending {
+    /// Reports that `listener` gave up, which keeps the proxy from being
+    /// signalled ready.
+    pub fn failed(self, listener: &str) {
+        let _ = self.tx.send(listener.to_string());
+    }
+}
+
+/// Waits for every `BindPending` handed out by `pending` to be dropped or
+/// to fail.
+pub struct Readiness {
+    tx: mpsc::UnboundedSender<String>,
+    rx: mpsc::UnboundedReceiver<String>,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self { tx, rx }
+    }
+}
+
+impl Readiness {
+    pub fn pending(&self) -> BindPending {
+        BindPending {
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// Signals readiness once every listener has settled, by writing the
+    /// pid to `ready_file` and telling systemd when it started the proxy
+    /// with `Type=notify`. A stale `ready_file` is removed right away. If
+    /// a listener failed the proxy runs degraded and is not signalled ready,
+    /// systemd only gets a status naming the failed listeners.
+    pub fn spawn_signal_task(self, config: &Config) {
+        let ready_file = config
+            .ready_file
+            .clone()
+            .filter(|_| !config.read_only.unwrap_or(false));
+        let notify = config.sd_notify.unwrap_or(true);
+        if let Some(path) = &ready_file {
+            let _ = fs::remove_file(path);
+        }
+        let Self { tx, mut rx } = self;
+        drop(tx);
+        tokio::spawn(async move {
+            let mut failed = Vec::new();
+            while let Some(listener) = rx.recv().await {
+                failed.push(listener);
+            }
+            if !failed.is_empty() {
+                let status = format!("degraded, failed listeners: {}", failed.join(", "));
+                log_error(&format!("not signalling readiness, {}", status));
+                if notify {
+                    if let Err(e) = sd_notify(&format!("STATUS={}", status)) {
+                        log_error(&format!("failed to notify systemd: {}", e));
+                    }
+                }
+                return;
+            }
+            if let Some(path) = &ready_file {
+                match fs::write(path, format!("{}\n", std::process::id())) {
+                    Ok(()) => log_info(&format!("listeners bound, wrote {}", path)),
+                    Err(e) => log_error(&format!("failed to write ready_file {}: {}", path, e)),
+                }
+            }
+            if notify {
+                if let Err(e) = sd_notify("READY=1") {
+                    log_error(&format!("failed to notify systemd: {}", e));
+                }
+            }
+        });
+    }
+}
+
+/// Undoes the readiness signal once shutdown starts.
+pub fn signal_stopping(config: &Config) {
+    if let Some(path) = config
+        .ready_file
+        .as_ref()
+        .filter(|_| !config.read_only.unwrap_or(false))
+    {
+        let _ = fs::remove_file(path);
+    }
+    if config.sd_notify.unwrap_or(true) {
+        let _ = sd_notify("STOPPING=1");
+    }
+}
+
+/// Sends `state` to the socket in `NOTIFY_SOCKET`, doing nothing when the
+/// variable is unset. A leading `@` names a linux abstract socket.
+#[cfg(unix)]
+fn sd_notify(state: &str) -> Result<(), String> {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let addr = SocketAddr::from_abstract_name(name).map_err(|e| e.to_string())?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err("abstract sockets need linux".to_string()),
+        None => socket.send_to(state.as_bytes(), &path),
+    }
+    .map(|_| ())
+    .map_err(|e| format!("{}: {}", path, e))
+}
+
+#[cfg(not(unix))]
+fn sd_notify(_state: &str) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::*;
+
+    fn config(name: &str) -> (Config, PathBuf) {
+        let path = std::env::temp_dir().join(format!("ready-{}-{}", std::process::id(), name));
+        let config = serde_yaml::from_str(&format!(
+            "ready_file: {}\nsd_notify: false\nhosts: {{}}",
+            path.display()
+        ))
+        .unwrap();
+        (config, path)
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn ready_once_every_listener_is_bound() {
+        let (config, path) = config("bound");
+        let readiness = Readiness::default();
+        let (http, https) = (readiness.pending(), readiness.pending());
+        readiness.spawn_signal_task(&config);
+        drop(http);
+        settle().await;
+        assert!(!path.exists());
+        drop(https);
+        settle().await;
+        let written = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(written, format!("{}\n", std::process::id()));
+    }
+
+    #[tokio::test]
+    async fn a_failed_listener_is_never_ready() {
+        let (config, path) = config("failed");
+        let readiness = Readiness::default();
+        let (http, https) = (readiness.pending(), readiness.pending());
+        readiness.spawn_signal_task(&config);
+        https.failed("https 0.0.0.0:443");
+        drop(http);
+        settle().await;
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,220 @@
+use futures_util::{stream, StreamExt};
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+use serde_json::Value;
+
+use crate::config::JsonRedaction;
+
+/// `application/json` and `+json` types.
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+/// Removes or masks what `path` points at, `*` standing for every key of an
+/// object or every element of an array.
+fn redact_path(value: &mut Value, path: &[&str], mask: Option<&str>) {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    if rest.is_empty() {
+        match (value, mask) {
+            (Value::Object(map), None) if *first == "*" => map.clear(),
+            (Value::Object(map), None) => {
+                map.remove(*first);
+            }
+            (Value::Object(map), Some(mask)) => {
+                for (key, field) in map.iter_mut() {
+                    if *first == "*" || key == first {
+                        *field = Value::String(mask.to_string());
+                    }
+                }
+            }
+            (Value::Array(items), _) if *first == "*" => match mask {
+                Some(mask) => items
+                    .iter_mut()
+                    .for_each(|item| *item = Value::String(mask.to_string())),
+                None => items.clear(),
+            },
+            _ => {}
+        }
+        return;
+    }
+    match value {
+        Value::Object(map) if *first == "*" => map
+            .values_mut()
+            .for_each(|field| redact_path(field, rest, mask)),
+        Value::Object(map) => {
+            if let Some(field) = map.get_mut(*first) {
+                redact_path(field, rest, mask);
+            }
+        }
+        Value::Array(items) if *first == "*" => items
+            .iter_mut()
+            .for_each(|item| redact_path(item, rest, mask)),
+        _ => {}
+    }
+}
+
+/// Reads `body` up to `max` bytes. `Err` hands back a body equal to the
+/// original when it turned out larger or failed midway.
+pub async fn collect_up_to(mut body: Body, max: u64) -> Result<Bytes, Body> {
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut total = 0;
+    while let Some(chunk) = body.next().await {
+        match chunk {
+            Ok(chunk) => {
+                total += chunk.len() as u64;
+                chunks.push(chunk);
+                if total > max {
+                    break;
+                }
+            }
+            Err(e) => {
+                let read = stream::iter(chunks.into_iter().map(Ok));
+                return Err(Body::wrap_stream(
+                    read.chain(stream::once(async { Err(e) })),
+                ));
+            }
+        }
+    }
+    if total > max {
+        let read = stream::iter(chunks.into_iter().map(Ok::<_, hyper::Error>));
+        return Err(Body::wrap_stream(read.chain(body)));
+    }
+    Ok(chunks.concat().into())
+}
+
+/// Takes the fields listed in `config` out of a json response, or masks
+/// them. Anything that is not uncompressed json under `max_bytes`, or does
+/// not parse, passes through untouched.
+pub async fn redact_json(res: Response<Body>, config: &JsonRedaction) -> Response<Body> {
+    let json = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(is_json)
+        .unwrap_or(false);
+    let max = config.max_bytes.unwrap_or(1024 * 1024);
+    let too_large = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(|len| len > max)
+        .unwrap_or(false);
+    if !json
+        || too_large
+        || res.status() == StatusCode::PARTIAL_CONTENT
+        || res.headers().contains_key(CONTENT_ENCODING)
+    {
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let bytes = match collect_up_to(body, max).await {
+        Ok(bytes) => bytes,
+        Err(body) => return Response::from_parts(parts, body),
+    };
+    let mut value: Value = match serde_json::from_slice(&bytes) {
+        Ok(value) => value,
+        Err(_) => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    for path in &config.paths {
+        let path: Vec<&str> = path.split('.').collect();
+        redact_path(&mut value, &path, config.mask.as_deref());
+    }
+    let bytes = match serde_json::to_vec(&value) {
+        Ok(redacted) => Bytes::from(redacted),
+        Err(_) => bytes,
+    };
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn redaction(yaml: &str) -> JsonRedaction {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    async fn redacted(res: Response<Body>, config: &JsonRedaction) -> Bytes {
+        hyper::body::to_bytes(redact_json(res, config).await.into_body())
+            .await
+            .unwrap()
+    }
+
+    fn json_response(body: &Value) -> Response<Body> {
+        Response::builder()
+            .header(CONTENT_TYPE, "application/json; charset=utf-8")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn removes_or_masks_the_listed_paths() {
+        let body =
+            json!({"user": {"email": "a@b.c", "name": "a"}, "items": [{"token": 1}, {"token": 2}]});
+        let removed = redacted(
+            json_response(&body),
+            &redaction("paths: [user.email, items.*.token]"),
+        )
+        .await;
+        let removed: Value = serde_json::from_slice(&removed).unwrap();
+        assert_eq!(removed, json!({"user": {"name": "a"}, "items": [{}, {}]}));
+
+        let masked = redacted(
+            json_response(&body),
+            &redaction("paths: [user.email]\nmask: '***'"),
+        )
+        .await;
+        let masked: Value = serde_json::from_slice(&masked).unwrap();
+        assert_eq!(masked["user"]["email"], "***");
+        assert_eq!(masked["user"]["name"], "a");
+    }
+
+    #[tokio::test]
+    async fn passes_other_responses_through() {
+        let config = redaction("paths: [secret]\nmax_bytes: 32");
+        let body = json!({"secret": 1}).to_string();
+        let text = Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from(body.clone()))
+            .unwrap();
+        assert_eq!(redacted(text, &config).await, body);
+        let large = json!({"secret": "x".repeat(64)});
+        assert_eq!(
+            redacted(json_response(&large), &config).await,
+            large.to_string()
+        );
+        let invalid = Response::builder()
+            .header(CONTENT_TYPE, "application/problem+json")
+            .body(Body::from("{not json"))
+            .unwrap();
+        assert_eq!(redacted(invalid, &config).await, "{not json");
+    }
+
+    #[tokio::test]
+    async fn collect_up_to_hands_back_larger_bodies_whole() {
+        let chunks = (0..4).map(|_| Ok::<_, std::io::Error>(Bytes::from_static(b"abcd")));
+        let body = Body::wrap_stream(stream::iter(chunks));
+        let body = collect_up_to(body, 6).await.unwrap_err();
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "abcd".repeat(4));
+        assert_eq!(collect_up_to(Body::from("abc"), 6).await.unwrap(), "abc");
+    }
+}
@@ -0,0 +1,420 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    config::{config_fragments, validate_config, Config, ConfigSource, STDIN_CONFIG},
+    log::{log_error, log_info},
+    tls::build_server_config,
+    tunnel::drain_stale_ws,
+};
+
+/// The live config. Handlers take a snapshot per request, the reload task
+/// swaps the inner `Arc` once a new config has passed validation.
+pub type SharedConfig = Arc<RwLock<Arc<Config>>>;
+
+pub fn new_shared_config(config: Config) -> SharedConfig {
+    Arc::new(RwLock::new(Arc::new(config)))
+}
+
+pub fn snapshot(shared: &SharedConfig) -> Arc<Config> {
+    shared.read().unwrap().clone()
+}
+
+fn modified_at(path: impl AsRef<Path>) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// What the hot reload task compares to notice a change: the config file's
+/// modification time, or every fragment with its own so that added and
+/// removed fragments count too.
+fn source_state(source: &ConfigSource) -> Option<Vec<(PathBuf, SystemTime)>> {
+    match source {
+        ConfigSource::File(path) => modified_at(path).map(|m| vec![(PathBuf::from(path), m)]),
+        ConfigSource::Dir(dir) => config_fragments(dir)
+            .ok()?
+            .into_iter()
+            .map(|path| modified_at(&path).map(|m| (path, m)))
+            .collect(),
+    }
+}
+
+/// Watches the config file, or the fragments of a config directory, and
+/// swaps the config into `shared` when it changes. A new config only becomes
+/// live if it passes `validate_config` as a whole, otherwise the current
+/// config stays active and the error is logged. A config read from stdin has
+/// no file to watch.
+pub fn spawn_hot_reload_task(source: ConfigSource, shared: SharedConfig) {
+    if matches!(&source, ConfigSource::File(path) if path == STDIN_CONFIG) {
+        log_info("config read from stdin, hot reload is unavailable");
+        return;
+    }
+    let interval = snapshot(&shared).reload_interval_secs.unwrap_or(3);
+    if interval == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut last_state = source_state(&source);
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let state = source_state(&source);
+            if state.is_none() || state == last_state {
+                continue;
+            }
+            last_state = state;
+            match try_reload(&source, &shared).await {
+                Ok(()) => log_info(&format!("config reloaded from {}", source)),
+                Err(e) => log_error(&format!(
+                    "config reload from {} rejected, keeping the previous config: {}",
+                    source, e
+                )),
+            }
+        }
+    });
+}
+
+pub async fn try_reload(source: &ConfigSource, shared: &SharedConfig) -> Result<(), String> {
+    let config = source.load()?;
+    validate_config(&config)?;
+    let current = snapshot(shared);
+    if config.port != current.port
+        || config.ssl != current.ssl
+        || config.ssl_port != current.ssl_port
+        || config.tls_handshake_timeout_secs != current.tls_handshake_timeout_secs
+        || config.max_concurrent_handshakes != current.max_concurrent_handshakes
+    {
+        log_error("listener settings changed, restart the proxy to apply them");
+    }
+    for conflict in config.read_only_conflicts() {
+        log_error(&conflict);
+    }
+    let config = Arc::new(config);
+    *shared.write().unwrap() = config.clone();
+    drain_stale_ws(&config);
+    Ok(())
+}
+
+/// Sent by the tls watch task when a cert, key or ocsp response the https
+/// listener uses has changed on disk, or the live config points at different
+/// files or holds different inline material.
+pub struct TlsArtifactChanged;
+
+/// Everything the https server's tls setup is built from. Only a change
+/// here reloads the https certs, routing changes reach requests through
+/// the shared config without touching any listener.
+#[derive(PartialEq)]
+struct TlsInputs {
+    files: Vec<(String, Option<SystemTime>)>,
+    inline: Vec<(String, Option<String>, Option<String>)>,
+    /// Names served by each host with its own cert.
+    cert_names: Vec<Vec<String>>,
+    /// Host names the `dev_mode` cert has to cover, when it is used.
+    dev_names: Option<Vec<String>>,
+}
+
+fn tls_inputs(config: &Config) -> TlsInputs {
+    let mut paths = vec![config.ssl_cert_path(), config.ssl_key_path()];
+    paths.extend(config.ssl_ocsp_file.clone());
+    let mut inline = vec![(
+        String::new(),
+        config.ssl_cert.clone(),
+        config.ssl_key.clone(),
+    )];
+    for (domain, host) in &config.hosts {
+        paths.extend(host.ssl_cert_file.clone());
+        paths.extend(host.ssl_key_file.clone());
+        paths.extend(host.ssl_ocsp_file.clone());
+        if host.ssl_cert.is_some() || host.ssl_key.is_some() {
+            inline.push((domain.clone(), host.ssl_cert.clone(), host.ssl_key.clone()));
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    inline.sort();
+    let mut cert_names: Vec<Vec<String>> = config
+        .hosts
+        .iter()
+        .filter(|(_, host)| host.ssl_sources().is_some())
+        .map(|(domain, host)| host.names(domain).map(String::from).collect())
+        .collect();
+    cert_names.sort();
+    let dev_names = config.needs_dev_cert().then(|| {
+        let mut names: Vec<String> = config
+            .hosts
+            .iter()
+            .flat_map(|(domain, host)| host.names(domain))
+            .map(String::from)
+            .collect();
+        names.sort();
+        names
+    });
+    TlsInputs {
+        files: paths
+            .into_iter()
+            .map(|path| {
+                let modified = modified_at(&path);
+                (path, modified)
+            })
+            .collect(),
+        inline,
+        cert_names,
+        dev_names,
+    }
+}
+
+/// How long changed tls inputs have to stay as they are before they count,
+/// so a rotation replacing the cert and the key one after the other is only
+/// picked up once both are in place.
+const TLS_SETTLE: Duration = Duration::from_millis(500);
+
+/// Polls the tls inputs at the hot reload interval and notifies `tx` once
+/// one of them has changed, stopped changing and the new material loads. A
+/// file seen missing or half written mid rotation is waited out instead of
+/// being reported.
+pub fn spawn_tls_watch_task(shared: SharedConfig, tx: mpsc::Sender<TlsArtifactChanged>) {
+    let interval = snapshot(&shared).reload_interval_secs.unwrap_or(3);
+    if interval == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut last = tls_inputs(&snapshot(&shared));
+        let mut failing: Option<String> = None;
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let current = tls_inputs(&snapshot(&shared));
+            if current == last {
+                continue;
+            }
+            tokio::time::sleep(TLS_SETTLE).await;
+            let config = snapshot(&shared);
+            if tls_inputs(&config) != current {
+                continue;
+            }
+            if let Err(e) = build_server_config(&config) {
+                if failing.as_ref() != Some(&e) {
+                    log_error(&format!(
+                        "changed tls material does not load, waiting for it to settle: {}",
+                        e
+                    ));
+                    failing = Some(e);
+                }
+                continue;
+            }
+            failing = None;
+            last = current;
+            if tx.send(TlsArtifactChanged).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::logged_errors;
+
+    /// A directory of its own for a test, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!(
+                "reverse-proxy-reload-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write_config(dir: &TempDir, name: &str, yaml: &str) -> ConfigSource {
+        let path = dir.0.join(format!("{}.yml", name));
+        fs::write(&path, yaml).unwrap();
+        ConfigSource::File(path.to_string_lossy().into_owned())
+    }
+
+    const GOOD: &str =
+        "port: 8080\nhosts:\n  a.com:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n";
+
+    const BAD: &str =
+        "port: 8080\nextra_ports: [8080]\nhosts:\n  a.com:\n    ip: 127.0.0.1\n    port: 9001\n    protocol: http\n";
+
+    #[tokio::test]
+    async fn bad_reload_keeps_the_current_config() {
+        let dir = TempDir::new("bad");
+        let source = write_config(&dir, "good", GOOD);
+        let shared = new_shared_config(source.load().unwrap());
+        let bad = write_config(&dir, "bad", BAD);
+        let e = try_reload(&bad, &shared).await.unwrap_err();
+        assert!(e.contains("8080"), "{}", e);
+        assert_eq!(snapshot(&shared).hosts["a.com"].port, Some(9000));
+    }
+
+    #[tokio::test]
+    async fn hot_reload_task_logs_a_rejected_config() {
+        let dir = TempDir::new("task");
+        let source = write_config(
+            &dir,
+            "config",
+            &format!("reload_interval_secs: 1\n{}", GOOD),
+        );
+        let shared = new_shared_config(source.load().unwrap());
+        spawn_hot_reload_task(source.clone(), shared.clone());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        write_config(&dir, "config", BAD);
+        let prefix = format!(
+            "config reload from {} rejected, keeping the previous config:",
+            source
+        );
+        let mut logged = None;
+        for _ in 0..50 {
+            logged = logged_errors()
+                .into_iter()
+                .find(|line| line.starts_with(&prefix));
+            if logged.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let logged = logged.expect("the rejected reload was not logged");
+        assert!(logged.contains("both 8080"), "{}", logged);
+        assert_eq!(snapshot(&shared).hosts["a.com"].port, Some(9000));
+    }
+
+    #[tokio::test]
+    async fn unparsable_reload_keeps_the_current_config() {
+        let dir = TempDir::new("parse");
+        let source = write_config(&dir, "good", GOOD);
+        let shared = new_shared_config(source.load().unwrap());
+        let bad = write_config(&dir, "bad", "hosts: [not, a, map]\n");
+        let e = try_reload(&bad, &shared).await.unwrap_err();
+        assert!(e.starts_with("failed to parse"), "{}", e);
+        assert_eq!(snapshot(&shared).port, Some(8080));
+    }
+
+    #[tokio::test]
+    async fn good_reload_swaps_the_config() {
+        let dir = TempDir::new("swap");
+        let source = write_config(&dir, "old", GOOD);
+        let shared = new_shared_config(source.load().unwrap());
+        let new = write_config(&dir, "new", &GOOD.replace("9000", "9002"));
+        try_reload(&new, &shared).await.unwrap();
+        assert_eq!(snapshot(&shared).hosts["a.com"].port, Some(9002));
+    }
+
+    /// A config directory with `base.yml` holding `GOOD`.
+    fn config_dir(name: &str) -> (ConfigSource, TempDir) {
+        let dir = TempDir::new(&format!("dir-{}", name));
+        fs::write(dir.0.join("base.yml"), GOOD).unwrap();
+        (ConfigSource::Dir(dir.0.to_string_lossy().into_owned()), dir)
+    }
+
+    const FRAGMENT: &str =
+        "hosts:\n  b.com:\n    ip: 127.0.0.1\n    port: 9001\n    protocol: http\n";
+
+    #[tokio::test]
+    async fn added_fragment_is_reloaded() {
+        let (source, dir) = config_dir("added");
+        let shared = new_shared_config(source.load().unwrap());
+        let before = source_state(&source);
+        fs::write(dir.0.join("b.yml"), FRAGMENT).unwrap();
+        let after = source_state(&source);
+        let reloaded = try_reload(&source, &shared).await;
+        assert!(before.is_some() && after != before);
+        reloaded.unwrap();
+        assert_eq!(snapshot(&shared).hosts["b.com"].port, Some(9001));
+        assert!(snapshot(&shared).hosts.contains_key("a.com"));
+    }
+
+    #[tokio::test]
+    async fn removed_fragment_is_reloaded() {
+        let (source, dir) = config_dir("removed");
+        fs::write(dir.0.join("b.yml"), FRAGMENT).unwrap();
+        let shared = new_shared_config(source.load().unwrap());
+        let before = source_state(&source);
+        fs::remove_file(dir.0.join("b.yml")).unwrap();
+        let after = source_state(&source);
+        let reloaded = try_reload(&source, &shared).await;
+        assert!(after.is_some() && after != before);
+        reloaded.unwrap();
+        assert!(!snapshot(&shared).hosts.contains_key("b.com"));
+        assert!(snapshot(&shared).hosts.contains_key("a.com"));
+    }
+
+    /// A config serving copies of the repo's test cert and key from a
+    /// directory of its own, polled every second.
+    fn tls_config(name: &str) -> (Config, TempDir) {
+        let temp = TempDir::new(&format!("tls-{}", name));
+        let dir = &temp.0;
+        fs::copy("./ssl/certificate.crt", dir.join("cert.pem")).unwrap();
+        fs::copy("./ssl/private.pem", dir.join("key.pem")).unwrap();
+        let config = serde_yaml::from_str(&format!(
+            "reload_interval_secs: 1\nssl_cert_file: {}\nssl_key_file: {}\nhosts: {{}}\n",
+            dir.join("cert.pem").display(),
+            dir.join("key.pem").display()
+        ))
+        .unwrap();
+        (config, temp)
+    }
+
+    #[tokio::test]
+    async fn changed_cert_files_are_reported() {
+        let (config, dir) = tls_config("changed");
+        let (tx, mut rx) = mpsc::channel(1);
+        spawn_tls_watch_task(new_shared_config(config), tx);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let cert = fs::read(dir.0.join("cert.pem")).unwrap();
+        fs::write(dir.0.join("cert.pem"), [cert.as_slice(), b"\n"].concat()).unwrap();
+        let changed = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await;
+        assert!(matches!(changed, Ok(Some(TlsArtifactChanged))));
+    }
+
+    #[test]
+    fn routing_changes_leave_the_tls_inputs_alone() {
+        let config = |yaml: &str| -> Config { serde_yaml::from_str(yaml).unwrap() };
+        let before = tls_inputs(&config(GOOD));
+        let routing =
+            GOOD.to_string() + "  b.com:\n    ip: 127.0.0.1\n    port: 9001\n    protocol: http\n";
+        assert!(tls_inputs(&config(&routing)) == before);
+        assert!(tls_inputs(&config(&GOOD.replace("9000", "9003"))) == before);
+        let own_cert = GOOD.to_string()
+            + "  c.com:\n    ip: 127.0.0.1\n    port: 9001\n    protocol: http\n    ssl_cert: inline\n    ssl_key: inline\n";
+        assert!(tls_inputs(&config(&own_cert)) != before);
+    }
+
+    #[tokio::test]
+    async fn rename_rotation_is_reported_once_it_completes() {
+        let (config, dir) = tls_config("rotation");
+        let (tx, mut rx) = mpsc::channel(1);
+        spawn_tls_watch_task(new_shared_config(config), tx);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let cert = fs::read(dir.0.join("cert.pem")).unwrap();
+        // Mid rotation the cert is half written, which must not count.
+        fs::write(dir.0.join("cert.pem"), &cert[..cert.len() / 2]).unwrap();
+        let early = tokio::time::timeout(Duration::from_millis(1600), rx.recv()).await;
+        fs::write(
+            dir.0.join("cert.pem.tmp"),
+            [cert.as_slice(), b"\n"].concat(),
+        )
+        .unwrap();
+        fs::rename(dir.0.join("cert.pem.tmp"), dir.0.join("cert.pem")).unwrap();
+        let changed = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await;
+        let again = tokio::time::timeout(Duration::from_secs(2), rx.recv()).await;
+        assert!(early.is_err());
+        assert!(matches!(changed, Ok(Some(TlsArtifactChanged))));
+        assert!(again.is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use std::env;
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::config::{Config, RuntimeFlavor};
+
+/// Overrides `worker_threads` from the config.
+const WORKER_THREADS_ENV: &str = "REVERSE_PROXY_WORKER_THREADS";
+
+fn worker_threads(config: &Config) -> Result<Option<usize>, String> {
+    match env::var(WORKER_THREADS_ENV) {
+        Ok(value) => match value.trim().parse::<usize>() {
+            Ok(threads) if threads > 0 => Ok(Some(threads)),
+            _ => Err(format!(
+                "{} must be a positive number, got `{}`",
+                WORKER_THREADS_ENV, value
+            )),
+        },
+        Err(_) => Ok(config.worker_threads),
+    }
+}
+
+/// Builds the tokio runtime from `runtime` and `worker_threads`, the thread
+/// count defaults to one per cpu core.
+pub fn build_runtime(config: &Config) -> Result<Runtime, String> {
+    let mut builder = match config.runtime.unwrap_or_default() {
+        RuntimeFlavor::MultiThread => {
+            let mut builder = Builder::new_multi_thread();
+            if let Some(threads) = worker_threads(config)? {
+                builder.worker_threads(threads);
+            }
+            builder
+        }
+        RuntimeFlavor::CurrentThread => Builder::new_current_thread(),
+    };
+    builder
+        .enable_all()
+        .build()
+        .map_err(|e| format!("failed to start the runtime: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(&format!("{}hosts: {{}}\n", yaml)).unwrap()
+    }
+
+    #[test]
+    fn runtime_follows_the_config() {
+        let runtime = build_runtime(&config("worker_threads: 3\n")).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 3);
+        let runtime = build_runtime(&config("runtime: current_thread\n")).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 1);
+        assert_eq!(runtime.block_on(async { 1 + 1 }), 2);
+    }
+}
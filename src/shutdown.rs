@@ -0,0 +1,124 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use axum_server::Handle;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    log::{log_error, log_info},
+    metrics::METRICS,
+};
+
+/// How often drain progress is logged.
+const DRAIN_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+#[cfg(unix)]
+async fn terminate() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            sigterm.recv().await;
+        }
+        Err(e) => {
+            log_error(&format!("failed to listen for SIGTERM: {}", e));
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+    std::future::pending::<()>().await;
+}
+
+/// Resolves on the first SIGINT (ctrl-c) or SIGTERM.
+pub async fn wait_for_signal() {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate() => {}
+    }
+}
+
+/// Stops `handle` from accepting once `shutdown` is cancelled and gives its
+/// open connections `timeout` to finish.
+pub fn drain_on_shutdown(shutdown: &CancellationToken, handle: Handle, timeout: Duration) {
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        shutdown.cancelled().await;
+        handle.graceful_shutdown(Some(timeout));
+    });
+}
+
+/// Waits until no request is in flight and every upgraded connection, e.g. a
+/// websocket tunnel, has closed, logging how many are left every second, or
+/// until `timeout` has passed.
+pub async fn drain(timeout: Duration) {
+    let active = &METRICS.active_requests;
+    let upgraded = &METRICS.upgraded_connections;
+    match drain_counters(active, upgraded, timeout, |progress| log_info(&progress)).await {
+        Ok(()) => log_info("all requests and upgraded connections drained, exiting"),
+        Err((active, upgraded)) => log_error(&format!(
+            "shutdown timeout of {:?} reached with {} requests in flight and {} upgraded connections still open, exiting",
+            timeout, active, upgraded
+        )),
+    }
+}
+
+/// Polls `active` and `upgraded` until both are zero, handing a progress
+/// line to `report` every second. Fails with the counts left at `timeout`.
+async fn drain_counters(
+    active: &AtomicU64,
+    upgraded: &AtomicU64,
+    timeout: Duration,
+    mut report: impl FnMut(String),
+) -> Result<(), (u64, u64)> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let active = active.load(Ordering::Relaxed);
+        let upgraded = upgraded.load(Ordering::Relaxed);
+        if active == 0 && upgraded == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err((active, upgraded));
+        }
+        report(format!(
+            "draining: {} requests in flight, {} upgraded connections open",
+            active, upgraded
+        ));
+        tokio::time::sleep(DRAIN_LOG_INTERVAL.min(deadline - now)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_waits_for_upgraded_connections() {
+        let active = AtomicU64::new(0);
+        let upgraded = AtomicU64::new(1);
+        let mut reported = Vec::new();
+        let started = Instant::now();
+        let left = drain_counters(&active, &upgraded, Duration::from_millis(200), |progress| {
+            reported.push(progress)
+        })
+        .await;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(left, Err((0, 1)));
+        assert_eq!(
+            reported,
+            ["draining: 0 requests in flight, 1 upgraded connections open"]
+        );
+
+        upgraded.store(0, Ordering::Relaxed);
+        let left = drain_counters(&active, &upgraded, Duration::from_millis(200), |progress| {
+            panic!("drained counters reported {}", progress)
+        })
+        .await;
+        assert_eq!(left, Ok(()));
+    }
+}
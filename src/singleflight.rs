@@ -0,0 +1,213 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use futures_util::{stream, StreamExt};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{
+        ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION,
+    },
+    Body, HeaderMap, Request, Response, StatusCode, Version,
+};
+use tokio::sync::watch;
+
+use crate::upstream::UpstreamError;
+
+/// Responses up to this size are buffered and shared, larger ones stream to
+/// the request that started the flight.
+const MAX_SHARED_BODY: usize = 1024 * 1024;
+
+/// A response may differ per user, so requests carrying these never share.
+const CREDENTIALS: [hyper::header::HeaderName; 3] = [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE];
+
+/// Request headers upstreams commonly vary on, part of the key.
+const VARY: [hyper::header::HeaderName; 3] = [ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE];
+
+/// The key requests share a flight under: method, host, path and query and
+/// the `VARY` headers. `None` for requests with credentials, which never
+/// share a response.
+pub fn flight_key<B>(req: &Request<B>, host: &str, path_query: &str) -> Option<String> {
+    if CREDENTIALS
+        .iter()
+        .any(|name| req.headers().contains_key(name))
+    {
+        return None;
+    }
+    let mut key = format!("{} {}{}", req.method(), host, path_query);
+    for name in VARY {
+        for value in req.headers().get_all(&name) {
+            key.push_str(&format!(
+                "\n{}: {}",
+                name,
+                String::from_utf8_lossy(value.as_bytes())
+            ));
+        }
+    }
+    Some(key)
+}
+
+/// A finished upstream response, buffered so every waiter gets a copy.
+struct BufferedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+enum Shared {
+    Buffered(BufferedResponse),
+    /// Over `MAX_SHARED_BODY`, the request that started the flight streams
+    /// it and the others send their own.
+    Streaming(Mutex<Option<Response<Body>>>),
+}
+
+type Outcome = Result<Arc<Shared>, Arc<UpstreamError>>;
+
+/// Upstream requests in flight, keyed by `flight_key`.
+static IN_FLIGHT: LazyLock<Mutex<HashMap<String, watch::Receiver<Option<Outcome>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+async fn buffer(res: Response<Body>) -> Outcome {
+    let (parts, mut body) = res.into_parts();
+    let mut buffered = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Arc::new(UpstreamError::Request(e)))?;
+        buffered.extend_from_slice(&chunk);
+        if buffered.len() > MAX_SHARED_BODY {
+            let read = stream::once(async move { Ok(Bytes::from(buffered)) });
+            let res = Response::from_parts(parts, Body::wrap_stream(read.chain(body)));
+            return Ok(Arc::new(Shared::Streaming(Mutex::new(Some(res)))));
+        }
+    }
+    Ok(Arc::new(Shared::Buffered(BufferedResponse {
+        status: parts.status,
+        version: parts.version,
+        headers: parts.headers,
+        body: Bytes::from(buffered),
+    })))
+}
+
+/// Runs `send` once for all concurrent callers with the same `key` and hands
+/// each of them a copy of the response. The request runs in its own task, so
+/// the caller that started it may go away without failing the others.
+pub async fn single_flight<F>(key: String, send: F) -> Result<Response<Body>, UpstreamError>
+where
+    F: std::future::Future<Output = Result<Response<Body>, UpstreamError>> + Send + 'static,
+{
+    let mut send = Some(send);
+    let mut rx = {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        match in_flight.get(&key) {
+            Some(rx) => rx.clone(),
+            None => {
+                let (tx, rx) = watch::channel(None);
+                in_flight.insert(key.clone(), rx.clone());
+                let key = key.clone();
+                let send = send.take().unwrap();
+                tokio::spawn(async move {
+                    let outcome = match send.await {
+                        Ok(res) => buffer(res).await,
+                        Err(e) => Err(Arc::new(e)),
+                    };
+                    let _ = tx.send(Some(outcome));
+                    IN_FLIGHT.lock().unwrap().remove(&key);
+                });
+                rx
+            }
+        }
+    };
+
+    let outcome = match rx.wait_for(|outcome| outcome.is_some()).await {
+        Ok(outcome) => outcome.clone().unwrap(),
+        Err(_) => {
+            IN_FLIGHT.lock().unwrap().remove(&key);
+            return Err(UpstreamError::Abandoned);
+        }
+    };
+    match outcome.as_deref() {
+        Ok(Shared::Buffered(buffered)) => {
+            let mut res = Response::new(Body::from(buffered.body.clone()));
+            *res.status_mut() = buffered.status;
+            *res.version_mut() = buffered.version;
+            *res.headers_mut() = buffered.headers.clone();
+            Ok(res)
+        }
+        Ok(Shared::Streaming(res)) => match send {
+            Some(send) => send.await,
+            None => res.lock().unwrap().take().ok_or(UpstreamError::Abandoned),
+        },
+        Err(e) => Err(UpstreamError::Shared(e.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<()> {
+        let mut req = Request::get("http://example.com/a?b=c");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap()
+    }
+
+    #[test]
+    fn credentialed_requests_do_not_share() {
+        for name in ["authorization", "proxy-authorization", "cookie"] {
+            let req = request(&[(name, "secret")]);
+            assert_eq!(flight_key(&req, "example.com", "/a?b=c"), None);
+        }
+    }
+
+    #[test]
+    fn key_varies_on_negotiation_headers() {
+        let en = request(&[("accept-language", "en")]);
+        let fr = request(&[("accept-language", "fr")]);
+        let key = |req| flight_key(req, "example.com", "/a?b=c").unwrap();
+        assert_ne!(key(&en), key(&fr));
+        assert_eq!(key(&en), key(&request(&[("accept-language", "en")])));
+    }
+
+    async fn run(key: &str, body: Bytes, sent: Arc<AtomicUsize>) -> Bytes {
+        let res = single_flight(key.to_string(), async move {
+            sent.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(Response::new(Body::from(body)))
+        })
+        .await
+        .unwrap();
+        hyper::body::to_bytes(res.into_body()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_upstream_request() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let body = Bytes::from_static(b"shared");
+        let (a, b) = tokio::join!(
+            run("small", body.clone(), sent.clone()),
+            run("small", body.clone(), sent.clone()),
+        );
+        assert_eq!((a, b), (body.clone(), body));
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_responses_are_not_shared() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let body = Bytes::from(vec![b'x'; MAX_SHARED_BODY + 1]);
+        let (a, b) = tokio::join!(
+            run("large", body.clone(), sent.clone()),
+            run("large", body.clone(), sent.clone()),
+        );
+        assert_eq!((a, b), (body.clone(), body));
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+    }
+}
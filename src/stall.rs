@@ -0,0 +1,174 @@
+use std::{
+    future::{ready, Future, Ready},
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum_server::accept::Accept;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+use crate::metrics::{incr, METRICS};
+
+/// Fails writes to a client that has not accepted any bytes for `timeout`,
+/// which tears the connection down and with it the upstream response.
+pub struct WriteTimeoutStream<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    stall: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> WriteTimeoutStream<S> {
+    pub fn new(inner: S, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            stall: None,
+        }
+    }
+
+    /// Called while a write is pending, ready once the client has stalled
+    /// for longer than the timeout.
+    fn poll_stalled(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+        let stall = self
+            .stall
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match stall.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.stall = None;
+                incr(&METRICS.client_write_timeouts);
+                Poll::Ready(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "client stopped reading the response",
+                ))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn track<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        match poll {
+            Poll::Pending => self.poll_stalled(cx).map(Err),
+            ready => {
+                self.stall = None;
+                ready
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WriteTimeoutStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WriteTimeoutStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.track(cx, poll)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        this.track(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        this.track(cx, poll)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_shutdown(cx);
+        this.track(cx, poll)
+    }
+}
+
+/// Wraps every accepted connection in a `WriteTimeoutStream`.
+#[derive(Clone, Copy)]
+pub struct WriteTimeoutAcceptor {
+    timeout: Option<Duration>,
+}
+
+impl WriteTimeoutAcceptor {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self { timeout }
+    }
+}
+
+impl<I, S> Accept<I, S> for WriteTimeoutAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    type Stream = WriteTimeoutStream<I>;
+    type Service = S;
+    type Future = Ready<io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        ready(Ok((WriteTimeoutStream::new(stream, self.timeout), service)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    #[tokio::test]
+    async fn fails_writes_to_a_stalled_client() {
+        let (server, _client) = duplex(16);
+        let mut stream = WriteTimeoutStream::new(server, Some(TIMEOUT));
+        let e = stream.write_all(&[0; 64]).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn slow_readers_are_not_stalled() {
+        let (server, mut client) = duplex(16);
+        let mut stream = WriteTimeoutStream::new(server, Some(TIMEOUT));
+        let reader = tokio::spawn(async move {
+            let mut read = Vec::new();
+            let mut buf = [0; 16];
+            while read.len() < 64 {
+                tokio::time::sleep(TIMEOUT / 10).await;
+                let n = client.read(&mut buf).await.unwrap();
+                read.extend_from_slice(&buf[..n]);
+            }
+            read
+        });
+        stream.write_all(&[7; 64]).await.unwrap();
+        assert_eq!(reader.await.unwrap(), vec![7; 64]);
+    }
+}
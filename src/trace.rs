@@ -0,0 +1,128 @@
+use hyper::{header::HeaderValue, HeaderMap};
+use rand::Rng;
+
+const TRACEPARENT: &str = "traceparent";
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    loop {
+        let id: String = (0..bytes)
+            .map(|_| format!("{:02x}", rng.gen::<u8>()))
+            .collect();
+        // All zero ids are invalid.
+        if id.bytes().any(|b| b != b'0') {
+            return id;
+        }
+    }
+}
+
+/// Trace id and sampled flag of a valid W3C `traceparent`.
+fn parse_traceparent(value: &str) -> Option<(String, bool)> {
+    let mut fields = value.trim().split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_id = fields.next()?;
+    let flags = fields.next()?;
+    if !is_hex(version, 2)
+        || version == "ff"
+        || !is_hex(trace_id, 32)
+        || trace_id.bytes().all(|b| b == b'0')
+        || !is_hex(parent_id, 16)
+        || parent_id.bytes().all(|b| b == b'0')
+        || !is_hex(flags, 2)
+    {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_string(), flags & 1 == 1))
+}
+
+/// Sets the `traceparent` sent upstream with the proxy as the parent span.
+/// A valid incoming trace keeps its id and sampling decision, otherwise a
+/// new trace is started and sampled with probability `sample_rate`. Returns
+/// whether the request is sampled.
+pub fn propagate_trace(headers: &mut HeaderMap, sample_rate: f64) -> bool {
+    let incoming = headers
+        .get(TRACEPARENT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_traceparent);
+    let (trace_id, sampled) = match incoming {
+        Some(trace) => trace,
+        None => (
+            random_hex(16),
+            rand::thread_rng().gen::<f64>() < sample_rate,
+        ),
+    };
+    let value = format!(
+        "00-{}-{}-{}",
+        trace_id,
+        random_hex(8),
+        if sampled { "01" } else { "00" }
+    );
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(TRACEPARENT, value);
+    }
+    sampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INCOMING: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn traced(traceparent: Option<&str>, sample_rate: f64) -> (bool, String) {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = traceparent {
+            headers.insert(TRACEPARENT, HeaderValue::from_str(value).unwrap());
+        }
+        let sampled = propagate_trace(&mut headers, sample_rate);
+        let sent = headers[TRACEPARENT].to_str().unwrap().to_string();
+        assert!(parse_traceparent(&sent).is_some());
+        (sampled, sent)
+    }
+
+    #[test]
+    fn incoming_trace_keeps_its_id_and_decision() {
+        let (sampled, sent) = traced(Some(INCOMING), 0.0);
+        assert!(sampled);
+        assert!(sent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(sent.ends_with("-01"));
+        assert_ne!(sent, INCOMING);
+
+        let unsampled = INCOMING.replace("-01", "-00");
+        assert!(!traced(Some(&unsampled), 1.0).0);
+    }
+
+    #[test]
+    fn new_traces_follow_the_sample_rate() {
+        assert!(traced(None, 1.0).0);
+        assert!(!traced(None, 0.0).0);
+        let (_, sent) = traced(
+            Some("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            0.0,
+        );
+        assert!(!sent.contains("-00000000000000000000000000000000-"));
+        assert!(sent.ends_with("-00"));
+    }
+
+    #[test]
+    fn rejects_malformed_traceparents() {
+        for value in [
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(parse_traceparent(value), None, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use hyper::{
+    header::{HeaderValue, HOST, IF_RANGE, RANGE, USER_AGENT},
+    Body, Request,
+};
+
+use crate::{
+    config::{EffectiveSettings, Host, RequestStep, Target},
+    error::ProxyError,
+    headers::{append_via, mark_behind_https, trim_headers},
+    log::log_error,
+    metrics::{incr, METRICS},
+    trace::propagate_trace,
+};
+
+/// What the steps need to know about the request besides the request.
+pub struct StepContext<'a> {
+    pub host: &'a str,
+    pub cfg: &'a Host,
+    pub settings: &'a EffectiveSettings,
+    pub target: &'a Target,
+}
+
+fn apply_step(
+    step: RequestStep,
+    req: &mut Request<Body>,
+    ctx: &StepContext,
+) -> Result<(), ProxyError> {
+    let cfg = ctx.cfg;
+    match step {
+        RequestStep::StripRange => {
+            if !ctx.settings.range_requests {
+                req.headers_mut().remove(RANGE);
+                req.headers_mut().remove(IF_RANGE);
+            }
+        }
+        RequestStep::Via => {
+            if let Some(via) = &cfg.via {
+                let version = req.version();
+                append_via(req.headers_mut(), version, via.pseudonym());
+            }
+        }
+        RequestStep::UserAgent => {
+            if let Some(user_agent) = &cfg.user_agent {
+                if user_agent.always.unwrap_or(false) || !req.headers().contains_key(USER_AGENT) {
+                    if let Ok(value) = HeaderValue::from_str(&user_agent.value) {
+                        req.headers_mut().insert(USER_AGENT, value);
+                    }
+                }
+            }
+        }
+        RequestStep::Trace => {
+            if let Some(sample_rate) = cfg.trace_sample_rate {
+                if propagate_trace(req.headers_mut(), sample_rate) {
+                    incr(&METRICS.traces_sampled);
+                } else {
+                    incr(&METRICS.traces_unsampled);
+                }
+            }
+        }
+        RequestStep::Forwarded => {
+            if ctx.settings.behind_https {
+                mark_behind_https(req.headers_mut(), ctx.host, ctx.settings.https_port);
+            }
+        }
+        RequestStep::HostHeader => {
+            if let Some(host_header) = cfg.host_header_for(ctx.target) {
+                match HeaderValue::from_str(&host_header) {
+                    Ok(value) => {
+                        req.headers_mut().insert(HOST, value);
+                    }
+                    Err(_) => log_error(&format!(
+                        "{} has an invalid host header `{}`",
+                        ctx.host, host_header
+                    )),
+                }
+            }
+        }
+        RequestStep::HeaderLimit => {
+            if let Some(limit) = &cfg.upstream_header_limit {
+                let strip = limit.strip.as_deref().unwrap_or_default();
+                if !trim_headers(req.headers_mut(), limit.max_bytes, strip) {
+                    return Err(ProxyError::HeadersTooLarge);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs the host's request pipeline on the request about to be sent
+/// upstream, step by step in the configured order.
+pub fn apply_request_pipeline(
+    req: &mut Request<Body>,
+    ctx: &StepContext,
+) -> Result<(), ProxyError> {
+    for step in &ctx.settings.request_pipeline {
+        apply_step(*step, req, ctx)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// `req` as it leaves the pipeline of `t.test` configured with `extra`.
+    fn piped(extra: &str, mut req: Request<Body>) -> Request<Body> {
+        let config: Config = serde_yaml::from_str(&format!(
+            "hosts:\n  t.test:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n{}",
+            extra
+        ))
+        .unwrap();
+        let cfg = &config.hosts["t.test"];
+        let ctx = StepContext {
+            host: "t.test",
+            cfg,
+            settings: &config.effective_settings(cfg),
+            target: &cfg.targets()[0],
+        };
+        apply_request_pipeline(&mut req, &ctx).unwrap();
+        req
+    }
+
+    fn user_agent(extra: &str, sent: Option<&str>) -> String {
+        let mut req = Request::get("/");
+        if let Some(sent) = sent {
+            req = req.header(USER_AGENT, sent);
+        }
+        let req = piped(extra, req.body(Body::empty()).unwrap());
+        req.headers()[USER_AGENT].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn user_agent_is_a_default_unless_always() {
+        let default = "    user_agent:\n      value: proxy/1.0\n";
+        assert_eq!(user_agent(default, None), "proxy/1.0");
+        assert_eq!(user_agent(default, Some("curl/8")), "curl/8");
+        let always = "    user_agent:\n      value: proxy/1.0\n      always: true\n";
+        assert_eq!(user_agent(always, Some("curl/8")), "proxy/1.0");
+    }
+}
@@ -0,0 +1,344 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use tokio::{
+    io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt},
+    sync::oneshot,
+    time::Instant,
+};
+
+use crate::{
+    config::Config,
+    log::{log_error, log_info},
+    metrics::{GaugeGuard, METRICS},
+};
+
+/// Websocket slots taken, in total and per host.
+static WS_SLOTS: LazyLock<Mutex<(usize, HashMap<String, usize>)>> =
+    LazyLock::new(|| Mutex::new((0, HashMap::new())));
+
+/// A websocket slot of a host, given back when dropped.
+pub struct WsSlot(String);
+
+impl Drop for WsSlot {
+    fn drop(&mut self) {
+        let mut slots = WS_SLOTS.lock().unwrap();
+        slots.0 -= 1;
+        if let Some(count) = slots.1.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                slots.1.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// Takes a websocket slot for `domain`, `None` when `global` or `per_host`
+/// slots are all taken already. Limits are passed in on every call, so a
+/// reload applies to the next upgrade; tunnels already open are kept.
+pub fn acquire_ws_slot(domain: &str, global: Option<u32>, per_host: Option<u32>) -> Option<WsSlot> {
+    let mut slots = WS_SLOTS.lock().unwrap();
+    let (total, per_domain) = &mut *slots;
+    let taken = per_domain.get(domain).copied().unwrap_or(0);
+    if global.map(|max| *total >= max as usize).unwrap_or(false)
+        || per_host.map(|max| taken >= max as usize).unwrap_or(false)
+    {
+        return None;
+    }
+    *total += 1;
+    per_domain.insert(domain.to_string(), taken + 1);
+    Some(WsSlot(domain.to_string()))
+}
+
+/// Where an open websocket tunnel goes, to tell after a reload whether the
+/// config still sends its host there.
+pub struct WsRoute {
+    /// Key of the host in `hosts`.
+    pub host: String,
+    pub upstream: String,
+}
+
+/// Close code and grace period handed to a tunnel being drained.
+type DrainSignal = (u16, Duration);
+
+struct OpenTunnel {
+    route: WsRoute,
+    drain: Option<oneshot::Sender<DrainSignal>>,
+}
+
+static NEXT_TUNNEL: AtomicU64 = AtomicU64::new(0);
+
+/// Open websocket tunnels, so a reload can drain the stale ones.
+static WS_TUNNELS: LazyLock<Mutex<HashMap<u64, OpenTunnel>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Takes a tunnel out of `WS_TUNNELS` when it ends.
+struct Registered(u64);
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        WS_TUNNELS.lock().unwrap().remove(&self.0);
+    }
+}
+
+fn register(route: WsRoute) -> (Registered, oneshot::Receiver<DrainSignal>) {
+    let (tx, rx) = oneshot::channel();
+    let id = NEXT_TUNNEL.fetch_add(1, Ordering::Relaxed);
+    WS_TUNNELS.lock().unwrap().insert(
+        id,
+        OpenTunnel {
+            route,
+            drain: Some(tx),
+        },
+    );
+    (Registered(id), rx)
+}
+
+/// Closes the websockets whose host `config` removed or no longer routes to
+/// their upstream, when `ws_drain` is set. Called with each config that
+/// goes live.
+pub fn drain_stale_ws(config: &Config) {
+    let drain = match &config.ws_drain {
+        Some(drain) => drain,
+        None => return,
+    };
+    let signal = (
+        drain.close_code.unwrap_or(1012),
+        Duration::from_millis(drain.grace_ms.unwrap_or(1000)),
+    );
+    let mut drained = 0;
+    for tunnel in WS_TUNNELS.lock().unwrap().values_mut() {
+        let live = config
+            .hosts
+            .get(&tunnel.route.host)
+            .map(|host| host.routes_to(&tunnel.route.upstream))
+            .unwrap_or(false);
+        if live {
+            continue;
+        }
+        if let Some(tx) = tunnel.drain.take() {
+            drained += usize::from(tx.send(signal).is_ok());
+        }
+    }
+    if drained > 0 {
+        log_info(&format!(
+            "closing {} websockets of removed or changed hosts with code {}",
+            drained, signal.0
+        ));
+    }
+}
+
+/// Follows the frames an upstream sends, so a close frame of the proxy's
+/// own is only ever written between two of them.
+#[derive(Default)]
+struct FrameBoundary {
+    header: Vec<u8>,
+    payload_left: u64,
+}
+
+impl FrameBoundary {
+    fn advance(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.payload_left > 0 {
+                let n = self.payload_left.min(bytes.len() as u64);
+                self.payload_left -= n;
+                bytes = &bytes[n as usize..];
+                continue;
+            }
+            self.header.push(bytes[0]);
+            bytes = &bytes[1..];
+            if let Some(payload) = self.header_done() {
+                self.payload_left = payload;
+                self.header.clear();
+            }
+        }
+    }
+
+    /// The payload length once `header` holds a whole frame header.
+    fn header_done(&self) -> Option<u64> {
+        let header = &self.header;
+        let second = *header.get(1)?;
+        let extended = match second & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask = if second & 0x80 != 0 { 4 } else { 0 };
+        if header.len() < 2 + extended + mask {
+            return None;
+        }
+        Some(match extended {
+            0 => u64::from(second & 0x7f),
+            _ => header[2..2 + extended]
+                .iter()
+                .fold(0, |len, byte| (len << 8) | u64::from(*byte)),
+        })
+    }
+
+    fn at_boundary(&self) -> bool {
+        self.header.is_empty() && self.payload_left == 0
+    }
+}
+
+/// Relays a websocket like `copy_bidirectional` until both sides are done
+/// or a drain signal comes in. Draining writes a close frame to the client
+/// at the next frame boundary and gives the client the grace period to
+/// answer before both sides are dropped.
+async fn relay_ws(
+    client: Upgraded,
+    upstream: Upgraded,
+    mut drain: oneshot::Receiver<DrainSignal>,
+    label: &str,
+) -> io::Result<()> {
+    let (mut client_rd, mut client_wr) = tokio::io::split(client);
+    let (mut upstream_rd, mut upstream_wr) = tokio::io::split(upstream);
+    let to_upstream = async {
+        tokio::io::copy(&mut client_rd, &mut upstream_wr).await?;
+        upstream_wr.shutdown().await
+    };
+    tokio::pin!(to_upstream);
+    let mut client_done = false;
+    let mut frames = FrameBoundary::default();
+    let mut buf = vec![0; 16 * 1024];
+    let mut draining: Option<(u16, Instant)> = None;
+    loop {
+        if let Some((code, deadline)) = draining.filter(|_| frames.at_boundary()) {
+            let [high, low] = code.to_be_bytes();
+            client_wr.write_all(&[0x88, 2, high, low]).await?;
+            if !client_done {
+                let _ = tokio::time::timeout_at(deadline, &mut to_upstream).await;
+            }
+            log_info(&format!("{} drained with code {}", label, code));
+            return Ok(());
+        }
+        let deadline = draining.map(|(_, deadline)| deadline);
+        tokio::select! {
+            read = upstream_rd.read(&mut buf) => {
+                let n = read?;
+                if n == 0 {
+                    client_wr.shutdown().await?;
+                    if !client_done {
+                        to_upstream.await?;
+                    }
+                    return Ok(());
+                }
+                client_wr.write_all(&buf[..n]).await?;
+                frames.advance(&buf[..n]);
+            }
+            copied = &mut to_upstream, if !client_done => {
+                copied?;
+                client_done = true;
+            }
+            Ok((code, grace)) = &mut drain, if draining.is_none() => {
+                draining = Some((code, Instant::now() + grace));
+            }
+            // An upstream stalled mid-frame never reaches a boundary.
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                if deadline.is_some() => {
+                log_info(&format!("{} dropped, no frame boundary within the grace period", label));
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Once both sides have switched protocols after a 101, copies bytes between
+/// the client and the upstream until either closes. The proxy never looks
+/// at the bytes, so websockets and any other `Upgrade` protocol pass through
+/// the same way; only a websocket given a `route` follows frame boundaries
+/// so `drain_stale_ws` can close it. `guard` lives as long as the tunnel.
+pub fn spawn_tunnel<G: Send + 'static>(
+    client: OnUpgrade,
+    upstream: OnUpgrade,
+    label: String,
+    route: Option<WsRoute>,
+    guard: G,
+) {
+    tokio::spawn(async move {
+        let _open = GaugeGuard::new(&METRICS.upgraded_connections);
+        let _guard = guard;
+        let (mut client, mut upstream) = match tokio::try_join!(client, upstream) {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                log_error(&format!("{}: upgrade failed: {}", label, e));
+                return;
+            }
+        };
+        if let Some(route) = route {
+            let (_registered, drain) = register(route);
+            if let Err(e) = relay_ws(client, upstream, drain, &label).await {
+                log_info(&format!("{} closed: {}", label, e));
+            }
+        } else if let Err(e) = copy_bidirectional(&mut client, &mut upstream).await {
+            log_info(&format!("{} closed: {}", label, e));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ws_slots_are_limited_per_host_and_given_back() {
+        let first = acquire_ws_slot("slots.test", None, Some(2)).unwrap();
+        let second = acquire_ws_slot("slots.test", None, Some(2)).unwrap();
+        assert!(acquire_ws_slot("slots.test", None, Some(2)).is_none());
+        assert!(acquire_ws_slot("other.slots.test", None, Some(2)).is_some());
+        drop(first);
+        let third = acquire_ws_slot("slots.test", None, Some(2));
+        assert!(third.is_some());
+        drop((second, third));
+        assert!(!WS_SLOTS.lock().unwrap().1.contains_key("slots.test"));
+    }
+
+    #[test]
+    fn frame_boundaries_across_reads() {
+        let mut frames = FrameBoundary::default();
+        assert!(frames.at_boundary());
+        // Unmasked text frame with 3 bytes, split inside header and payload.
+        frames.advance(&[0x81]);
+        assert!(!frames.at_boundary());
+        frames.advance(&[3, b'a']);
+        assert!(!frames.at_boundary());
+        frames.advance(b"bc");
+        assert!(frames.at_boundary());
+
+        // 16 bit length, then a masked 64 bit length frame in one read.
+        let mut bytes = vec![0x82, 126, 0x01, 0x00];
+        bytes.extend_from_slice(&[0; 256]);
+        bytes.extend_from_slice(&[0x82, 0x80 | 127, 0, 0, 0, 0, 0, 0, 0, 2, 1, 2, 3, 4]);
+        frames.advance(&bytes);
+        assert!(!frames.at_boundary());
+        frames.advance(&[9, 9]);
+        assert!(frames.at_boundary());
+    }
+
+    #[test]
+    fn drains_only_tunnels_of_stale_hosts() {
+        let route = |host: &str, upstream: &str| WsRoute {
+            host: host.to_string(),
+            upstream: upstream.to_string(),
+        };
+        let (_kept, mut kept_rx) = register(route("drain.test", "127.0.0.1:9000"));
+        let (_moved, mut moved_rx) = register(route("drain.test", "127.0.0.1:9001"));
+        let (_removed, mut removed_rx) = register(route("gone.drain.test", "127.0.0.1:9000"));
+        let config: Config = serde_yaml::from_str(
+            "ws_drain:\n  close_code: 4000\n  grace_ms: 10\nhosts:\n  drain.test:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n",
+        )
+        .unwrap();
+        drain_stale_ws(&config);
+        let signal = (4000, Duration::from_millis(10));
+        assert_eq!(moved_rx.try_recv(), Ok(signal));
+        assert_eq!(removed_rx.try_recv(), Ok(signal));
+        assert!(kept_rx.try_recv().is_err());
+    }
+}
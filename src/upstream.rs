@@ -0,0 +1,949 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::{stream, StreamExt};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{CONTENT_LENGTH, TRANSFER_ENCODING},
+    Body, Client, Request, Response, StatusCode, Version,
+};
+use hyper_tls::HttpsConnector;
+use rand::Rng;
+use tokio_native_tls::TlsConnector;
+
+use crate::{
+    config::{Config, Host, InvalidResponsePolicy, RetryMode},
+    connect::{PhaseConnector, TimedConnector},
+    log::log_error,
+    metrics::{incr, METRICS},
+};
+
+/// The https connector also handles plain `http://` targets.
+pub type UpstreamClient = Client<TimedConnector, Body>;
+
+/// `pooled` serves every request. `fresh` never keeps idle connections, it is
+/// used to replay a request whose pooled connection turned out to be dead so
+/// the replay cannot pick another stale one. `h2` serves requests sent as
+/// HTTP/2, with prior knowledge on `http://` and ALPN on `https://` targets.
+#[derive(Clone)]
+pub struct HttpClient {
+    pub pooled: UpstreamClient,
+    pub fresh: UpstreamClient,
+    pub h2: UpstreamClient,
+}
+
+fn connector(config: &Config) -> TimedConnector {
+    let http = PhaseConnector::new(
+        config.upstream_source_address,
+        config.upstream_tcp_keepalive(),
+    );
+    TimedConnector::new(
+        HttpsConnector::new_with_connector(http),
+        config.slow_connect_log(),
+    )
+}
+
+fn h2_connector(config: &Config) -> TimedConnector {
+    let tls = native_tls::TlsConnector::builder()
+        .request_alpns(&["h2"])
+        .build()
+        .unwrap_or_else(|e| panic!("failed to create the http/2 tls connector: {}", e));
+    let http = PhaseConnector::new(
+        config.upstream_source_address,
+        config.upstream_tcp_keepalive(),
+    );
+    TimedConnector::new(
+        HttpsConnector::from((http, TlsConnector::from(tls))),
+        config.slow_connect_log(),
+    )
+}
+
+/// Connector settings are read once here, changing them needs a restart.
+pub fn create_http_client(config: &Config) -> HttpClient {
+    let http1 = || {
+        let mut builder = Client::builder();
+        builder.pool_idle_timeout(config.upstream_idle_timeout());
+        builder.http1_preserve_header_case(config.preserve_header_case.unwrap_or(false));
+        if let Some(max) = config.max_response_header_bytes {
+            builder.http1_max_buf_size(max);
+        }
+        builder
+    };
+    HttpClient {
+        pooled: http1().build::<_, Body>(connector(config)),
+        fresh: http1()
+            .pool_max_idle_per_host(0)
+            .build::<_, Body>(connector(config)),
+        h2: Client::builder()
+            .pool_idle_timeout(config.upstream_idle_timeout())
+            .http2_only(true)
+            .build::<_, Body>(h2_connector(config)),
+    }
+}
+
+/// Clients of hosts with `isolated_pool`, keyed by `Host::state_key`.
+static ISOLATED: LazyLock<Mutex<HashMap<String, HttpClient>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The client requests to the host with state key `key` go through: its own
+/// when `isolated_pool` is set, so its connections never mix with other
+/// hosts' (unless they share its upstreams under `state_scope: upstream`), otherwise
+/// `shared`. With `disable_keepalive` http/1 requests only ever use the
+/// `fresh` client.
+pub fn client_for(key: &str, host: &Host, config: &Config, shared: &HttpClient) -> HttpClient {
+    let client = if host.isolated_pool.unwrap_or(false) {
+        ISOLATED
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert_with(|| create_http_client(config))
+            .clone()
+    } else {
+        shared.clone()
+    };
+    if host.disable_keepalive.unwrap_or(false) {
+        return HttpClient {
+            pooled: client.fresh.clone(),
+            ..client
+        };
+    }
+    client
+}
+
+/// Drops the clients of hosts that no longer ask for an isolated pool.
+pub fn prune_isolated_clients(config: &Config) {
+    let live: Vec<String> = config
+        .hosts
+        .iter()
+        .filter(|(_, host)| host.isolated_pool.unwrap_or(false))
+        .map(|(domain, host)| host.state_key(domain))
+        .collect();
+    ISOLATED.lock().unwrap().retain(|key, _| live.contains(key));
+}
+
+/// Counts and logs an upstream body that breaks off before its end, short
+/// of its `Content-Length` or its final chunk. The error itself still reaches
+/// the client connection, which hyper then aborts instead of ending the
+/// response normally, so the truncation is visible there too.
+pub fn watch_truncation(res: Response<Body>, label: String) -> Response<Body> {
+    let declared = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let (parts, body) = res.into_parts();
+    let mut received: u64 = 0;
+    let body = body.map(move |chunk| {
+        match &chunk {
+            Ok(chunk) => received += chunk.len() as u64,
+            Err(e) => {
+                incr(&METRICS.upstream_truncated_bodies);
+                log_error(&format!(
+                    "{} body ended early after {} of {} bytes: {}",
+                    label,
+                    received,
+                    declared
+                        .map(|len| len.to_string())
+                        .unwrap_or_else(|| "unknown".to_string()),
+                    e
+                ));
+            }
+        }
+        chunk
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// Bodies up to this size are buffered so the request can be retried.
+const MAX_REPLAY_BODY: u64 = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    Request(hyper::Error),
+    /// The upstream answered with something that is not valid HTTP.
+    InvalidResponse(hyper::Error),
+    /// The response head went over `max_response_header_bytes` or had too
+    /// many headers.
+    HeadersTooLarge(hyper::Error),
+    Timeout,
+    /// The failure of a single-flight request this one waited on.
+    Shared(Arc<UpstreamError>),
+    /// The single-flight request this one waited on never finished.
+    Abandoned,
+}
+
+impl UpstreamError {
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            UpstreamError::Timeout => true,
+            UpstreamError::Shared(e) => e.is_timeout(),
+            _ => false,
+        }
+    }
+
+    pub fn is_invalid_response(&self) -> bool {
+        match self {
+            UpstreamError::InvalidResponse(_) => true,
+            UpstreamError::Shared(e) => e.is_invalid_response(),
+            _ => false,
+        }
+    }
+
+    pub fn is_headers_too_large(&self) -> bool {
+        match self {
+            UpstreamError::HeadersTooLarge(_) => true,
+            UpstreamError::Shared(e) => e.is_headers_too_large(),
+            _ => false,
+        }
+    }
+
+    fn from_hyper(e: hyper::Error) -> Self {
+        if e.is_parse_too_large() {
+            incr(&METRICS.upstream_oversized_headers);
+            UpstreamError::HeadersTooLarge(e)
+        } else if e.is_parse() {
+            incr(&METRICS.upstream_invalid_responses);
+            UpstreamError::InvalidResponse(e)
+        } else {
+            UpstreamError::Request(e)
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Request(e) => write!(f, "{}", e),
+            UpstreamError::InvalidResponse(e) => write!(f, "invalid response: {}", e),
+            UpstreamError::HeadersTooLarge(e) => write!(f, "response head too large: {}", e),
+            UpstreamError::Timeout => write!(f, "timed out waiting for the upstream"),
+            UpstreamError::Shared(e) => write!(f, "{}", e),
+            UpstreamError::Abandoned => write!(f, "the shared upstream request was abandoned"),
+        }
+    }
+}
+
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration,
+    pub backoff_max: Duration,
+    pub attempt_timeout: Option<Duration>,
+    /// Bounds all attempts and backoffs together.
+    pub deadline: Option<Duration>,
+    pub retry_stale: bool,
+    pub retry_invalid: bool,
+    pub mode: RetryMode,
+}
+
+impl RetryPolicy {
+    pub fn new(cfg: &Host, retry_stale: bool) -> Self {
+        let retry_invalid =
+            cfg.invalid_response.unwrap_or_default() == InvalidResponsePolicy::Retry;
+        RetryPolicy {
+            retries: cfg.retries.unwrap_or(0),
+            backoff: Duration::from_millis(cfg.retry_backoff_ms.unwrap_or(100)),
+            backoff_max: Duration::from_millis(cfg.retry_backoff_max_ms.unwrap_or(2000)),
+            attempt_timeout: cfg.timeout_ms.map(Duration::from_millis),
+            deadline: cfg.deadline_ms.map(Duration::from_millis),
+            retry_stale,
+            retry_invalid,
+            mode: cfg.retry_mode.unwrap_or(if retry_invalid {
+                RetryMode::Idempotent
+            } else {
+                RetryMode::NeverSent
+            }),
+        }
+    }
+}
+
+/// Exponential backoff with equal jitter: half of the delay is fixed, the
+/// other half random, so retries from many requests don't line up.
+fn backoff_delay(attempt: u32, base: Duration, max: Duration) -> Duration {
+    let delay = base.saturating_mul(2u32.saturating_pow(attempt)).min(max);
+    let half = delay / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+/// Errors that mean the connection died before the upstream answered, which
+/// is what a pooled connection closed by the upstream or a NAT looks like.
+fn is_stale_connection(e: &hyper::Error) -> bool {
+    e.is_incomplete_message() || e.is_closed() || e.is_canceled()
+}
+
+/// Whether the stale connection replay may resend after `e`. Under
+/// `never_sent` a dead pooled connection is not enough, it may have taken
+/// the request before it died, only proof the request was never written is.
+fn may_replay(e: &hyper::Error, mode: RetryMode) -> bool {
+    match mode {
+        RetryMode::NeverSent => never_sent(e),
+        RetryMode::Idempotent => is_stale_connection(e),
+    }
+}
+
+fn copy_with_body(req: &Request<Body>, body: Body) -> Request<Body> {
+    let mut copy = Request::new(body);
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    copy
+}
+
+async fn send_attempt(
+    client: &HttpClient,
+    req: Request<Body>,
+    buffered: Option<&Bytes>,
+    policy: &RetryPolicy,
+) -> Result<Response<Body>, UpstreamError> {
+    // The first attempt consumes the body, so only an idempotent request
+    // whose body is buffered or known to be empty can be replayed on a
+    // fresh connection. Headers alone do not tell: an http/2 request may
+    // carry a body without `Content-Length`.
+    let replay_body = match buffered {
+        Some(body) => Some(Body::from(body.clone())),
+        None if req.body().is_end_stream() => Some(Body::empty()),
+        None => None,
+    };
+    let replay = replay_body
+        .filter(|_| policy.retry_stale && req.method().is_idempotent())
+        .map(|body| copy_with_body(&req, body));
+    let (pooled, fresh) = if req.version() == Version::HTTP_2 {
+        (&client.h2, &client.h2)
+    } else {
+        (&client.pooled, &client.fresh)
+    };
+    let send = async {
+        match pooled.request(req).await {
+            Err(e) if replay.is_some() && may_replay(&e, policy.mode) => {
+                fresh.request(replay.unwrap()).await
+            }
+            res => res,
+        }
+    };
+    match policy.attempt_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, send).await {
+            Ok(res) => res.map_err(UpstreamError::from_hyper),
+            Err(_) => Err(UpstreamError::Timeout),
+        },
+        None => send.await.map_err(UpstreamError::from_hyper),
+    }
+}
+
+/// The request was never written to the upstream: no connection could be
+/// opened, or hyper dropped the request before sending any of it.
+fn never_sent(e: &hyper::Error) -> bool {
+    e.is_connect() || e.is_canceled()
+}
+
+fn should_retry(result: &Result<Response<Body>, UpstreamError>, policy: &RetryPolicy) -> bool {
+    if policy.mode == RetryMode::NeverSent {
+        return matches!(result, Err(UpstreamError::Request(e)) if never_sent(e));
+    }
+    match result {
+        Err(e) if e.is_invalid_response() => policy.retry_invalid,
+        // The same upstream sends the same head again.
+        Err(e) if e.is_headers_too_large() => false,
+        Err(_) => true,
+        Ok(res) => matches!(
+            res.status(),
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+        ),
+    }
+}
+
+/// Reads `body` into memory while it stays within `MAX_REPLAY_BODY`. A body
+/// without a declared length may be larger, once it goes over the limit it
+/// is handed back as the bytes read so far followed by the rest, to be sent
+/// once without retries.
+async fn buffer_for_replay(mut body: Body) -> Result<Result<Bytes, Body>, hyper::Error> {
+    let mut buffered = Vec::new();
+    while let Some(chunk) = body.data().await {
+        buffered.extend_from_slice(&chunk?);
+        if buffered.len() as u64 > MAX_REPLAY_BODY {
+            let read = stream::once(async move { Ok(Bytes::from(buffered)) });
+            return Ok(Err(Body::wrap_stream(read.chain(body))));
+        }
+    }
+    Ok(Ok(Bytes::from(buffered)))
+}
+
+/// Sends the request, retrying idempotent ones on the failures `retry_mode`
+/// covers with a jittered backoff between attempts. The whole exchange never takes
+/// longer than `deadline_ms`, or `timeout_ms * (retries + 1)` when that is
+/// shorter; a backoff that would overrun it ends the retries. Hitting the
+/// deadline itself is a timeout whatever retries are left.
+pub async fn send_upstream(
+    client: &HttpClient,
+    req: Request<Body>,
+    policy: &RetryPolicy,
+) -> Result<Response<Body>, UpstreamError> {
+    match policy.deadline {
+        Some(deadline) => tokio::time::timeout(deadline, send_with_retries(client, req, policy))
+            .await
+            .unwrap_or(Err(UpstreamError::Timeout)),
+        None => send_with_retries(client, req, policy).await,
+    }
+}
+
+async fn send_with_retries(
+    client: &HttpClient,
+    req: Request<Body>,
+    policy: &RetryPolicy,
+) -> Result<Response<Body>, UpstreamError> {
+    let body_len = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let replayable = req.method().is_idempotent()
+        && !req.headers().contains_key(TRANSFER_ENCODING)
+        && body_len.map(|len| len <= MAX_REPLAY_BODY).unwrap_or(true);
+    if policy.retries == 0 || !replayable {
+        return send_attempt(client, req, None, policy).await;
+    }
+
+    let (parts, body) = req.into_parts();
+    let body = match buffer_for_replay(body)
+        .await
+        .map_err(UpstreamError::Request)?
+    {
+        Ok(body) => body,
+        Err(body) => {
+            let req = Request::from_parts(parts, body);
+            return send_attempt(client, req, None, policy).await;
+        }
+    };
+    let head = Request::from_parts(parts, Body::empty());
+
+    let started = Instant::now();
+    let budget = policy
+        .attempt_timeout
+        .map(|timeout| timeout * (policy.retries + 1))
+        .into_iter()
+        .chain(policy.deadline)
+        .min();
+    let mut attempt = 0;
+    loop {
+        let req = copy_with_body(&head, Body::from(body.clone()));
+        let result = send_attempt(client, req, Some(&body), policy).await;
+        if attempt >= policy.retries || !should_retry(&result, policy) {
+            return result;
+        }
+        let delay = backoff_delay(attempt, policy.backoff, policy.backoff_max);
+        if let Some(budget) = budget {
+            if started.elapsed() + delay >= budget {
+                return result;
+            }
+        }
+        attempt += 1;
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use futures_util::stream;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// An upstream that reads each request head and hangs up without an
+    /// answer, like a pooled connection the upstream already closed. Counts
+    /// the requests it saw.
+    async fn hanging_up_upstream() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = seen.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        (url, seen)
+    }
+
+    /// An upstream whose first connection answers one request and then
+    /// hangs up on the next, like a pooled connection that died while idle.
+    /// Every later connection answers all its requests.
+    async fn failing_once_upstream() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut first = true;
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let answers = first.then_some(1);
+                first = false;
+                tokio::spawn(async move {
+                    let mut count = 0;
+                    let mut buf = [0; 1024];
+                    loop {
+                        let mut head = Vec::new();
+                        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                            match stream.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => head.extend_from_slice(&buf[..n]),
+                            }
+                        }
+                        if answers == Some(count) {
+                            return;
+                        }
+                        count += 1;
+                        let ok = b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n";
+                        if stream.write_all(ok).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    fn policy(mode: RetryMode) -> RetryPolicy {
+        RetryPolicy {
+            retries: 0,
+            backoff: Duration::from_millis(1),
+            backoff_max: Duration::from_millis(1),
+            attempt_timeout: None,
+            deadline: None,
+            retry_stale: true,
+            retry_invalid: false,
+            mode,
+        }
+    }
+
+    fn request(method: &str, url: &str, body: Body) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(url)
+            .body(body)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn empty_request_is_replayed_on_a_fresh_connection() {
+        let (url, seen) = hanging_up_upstream().await;
+        let req = request("GET", &url, Body::empty());
+        assert!(send_upstream(
+            &create_http_client(&Config::default()),
+            req,
+            &policy(RetryMode::Idempotent)
+        )
+        .await
+        .is_err());
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn body_of_unknown_length_is_not_replayed() {
+        let (url, seen) = hanging_up_upstream().await;
+        let chunks = stream::iter([Ok::<_, std::io::Error>(Bytes::from("payload"))]);
+        let req = request("PUT", &url, Body::wrap_stream(chunks));
+        assert!(send_upstream(
+            &create_http_client(&Config::default()),
+            req,
+            &policy(RetryMode::Idempotent)
+        )
+        .await
+        .is_err());
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn one_dead_pooled_connection_does_not_cascade() {
+        let url = failing_once_upstream().await;
+        let client = create_http_client(&Config::default());
+        for _ in 0..5 {
+            let req = request("GET", &url, Body::empty());
+            let res = send_upstream(&client, req, &policy(RetryMode::Idempotent))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            hyper::body::to_bytes(res.into_body()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn never_sent_mode_fails_only_the_request_on_the_dead_connection() {
+        let url = failing_once_upstream().await;
+        let client = create_http_client(&Config::default());
+        let mut ok = Vec::new();
+        for _ in 0..5 {
+            let req = request("GET", &url, Body::empty());
+            match send_upstream(&client, req, &policy(RetryMode::NeverSent)).await {
+                Ok(res) => {
+                    assert_eq!(res.status(), StatusCode::OK);
+                    hyper::body::to_bytes(res.into_body()).await.unwrap();
+                    ok.push(true);
+                }
+                Err(_) => ok.push(false),
+            }
+        }
+        // The request written to the dead connection is not resent.
+        assert_eq!(ok, [true, false, true, true, true]);
+    }
+
+    #[tokio::test]
+    async fn small_body_is_buffered_for_replay() {
+        let chunks = stream::iter([
+            Ok::<_, std::io::Error>(Bytes::from("a")),
+            Ok(Bytes::from("b")),
+        ]);
+        let buffered = buffer_for_replay(Body::wrap_stream(chunks)).await.unwrap();
+        assert_eq!(buffered.unwrap(), Bytes::from("ab"));
+    }
+
+    #[tokio::test]
+    async fn body_over_the_replay_limit_streams_through_whole() {
+        let chunk = Bytes::from(vec![b'x'; 64 * 1024]);
+        let count = (MAX_REPLAY_BODY as usize / chunk.len()) + 4;
+        let chunks = stream::iter((0..count).map(move |_| Ok::<_, std::io::Error>(chunk.clone())));
+        let body = match buffer_for_replay(Body::wrap_stream(chunks)).await.unwrap() {
+            Ok(_) => panic!("a body over the limit was buffered"),
+            Err(body) => body,
+        };
+        let sent = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(sent.len(), 64 * 1024 * count);
+    }
+
+    #[tokio::test]
+    async fn idempotent_requests_are_retried_until_retries_run_out() {
+        let (url, seen) = hanging_up_upstream().await;
+        let policy = RetryPolicy {
+            retries: 2,
+            ..policy(RetryMode::Idempotent)
+        };
+        let req = request("GET", &url, Body::empty());
+        assert!(
+            send_upstream(&create_http_client(&Config::default()), req, &policy)
+                .await
+                .is_err()
+        );
+        // Each attempt is replayed once on a fresh connection.
+        assert_eq!(seen.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn never_sent_mode_does_not_retry_after_the_request_was_written() {
+        let (url, seen) = hanging_up_upstream().await;
+        let client = create_http_client(&Config::default());
+        let policy = RetryPolicy {
+            retries: 2,
+            retry_stale: false,
+            ..policy(RetryMode::NeverSent)
+        };
+        let req = request("GET", &url, Body::empty());
+        assert!(send_upstream(&client, req, &policy).await.is_err());
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn never_sent_mode_does_not_replay_a_written_request() {
+        let (url, seen) = hanging_up_upstream().await;
+        let client = create_http_client(&Config::default());
+        let policy = RetryPolicy {
+            retries: 2,
+            ..policy(RetryMode::NeverSent)
+        };
+        let req = request("GET", &url, Body::empty());
+        assert!(send_upstream(&client, req, &policy).await.is_err());
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn never_sent_mode_retries_a_refused_connect() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        // The port refuses the first attempt and starts accepting before the
+        // backoff, at least 100ms, lets the second one go.
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let seen = accepted.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            let listener = TcpListener::bind(addr).await.unwrap();
+            let (mut conn, _) = listener.accept().await.unwrap();
+            seen.fetch_add(1, Ordering::SeqCst);
+            let mut buf = [0; 1024];
+            let _ = conn.read(&mut buf).await;
+            let _ = conn
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await;
+        });
+        let client = create_http_client(&Config::default());
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(200),
+            backoff_max: Duration::from_millis(200),
+            ..policy(RetryMode::NeverSent)
+        };
+        let req = request("GET", &format!("http://{}/", addr), Body::empty());
+        let res = send_upstream(&client, req, &policy).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn never_sent_mode_sends_non_idempotent_requests_once() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let client = create_http_client(&Config::default());
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_secs(5),
+            backoff_max: Duration::from_secs(5),
+            ..policy(RetryMode::NeverSent)
+        };
+        let req = request("POST", &url, Body::from("payload"));
+        let started = Instant::now();
+        assert!(send_upstream(&client, req, &policy).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn backoff_is_jittered_within_half_and_capped() {
+        let base = Duration::from_millis(100);
+        let max = Duration::from_millis(300);
+        for attempt in 0..5 {
+            let full = (base * 2u32.pow(attempt)).min(max);
+            let delay = backoff_delay(attempt, base, max);
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_are_spaced_by_the_jittered_backoff() {
+        // Answers every request with 503 and records when it arrived.
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let recorded = arrivals.clone();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let service = hyper::service::make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, std::io::Error>(hyper::service::service_fn(move |_| {
+                    recorded.lock().unwrap().push(Instant::now());
+                    async {
+                        let mut res = Response::new(Body::empty());
+                        *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                        Ok::<_, std::io::Error>(res)
+                    }
+                }))
+            }
+        });
+        tokio::spawn(hyper::Server::from_tcp(listener).unwrap().serve(service));
+
+        let policy = RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            backoff_max: Duration::from_millis(250),
+            ..policy(RetryMode::Idempotent)
+        };
+        let client = create_http_client(&Config::default());
+        let res = send_upstream(&client, request("GET", &url, Body::empty()), &policy)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), 4);
+        // Room for the request itself on top of the sleep.
+        let slack = Duration::from_millis(50);
+        for (attempt, pair) in arrivals.windows(2).enumerate() {
+            let full = (policy.backoff * 2u32.pow(attempt as u32)).min(policy.backoff_max);
+            let gap = pair[1] - pair[0];
+            assert!(
+                gap >= full / 2 && gap <= full + slack,
+                "attempt {}: {:?} outside {:?}..{:?}",
+                attempt,
+                gap,
+                full / 2,
+                full
+            );
+            assert!(gap <= policy.backoff_max + slack, "{:?}", gap);
+        }
+    }
+
+    #[test]
+    fn isolated_pools_are_kept_per_host_and_pruned() {
+        let config = |isolated: bool| -> Config {
+            serde_yaml::from_str(&format!(
+                "hosts:\n  pool.test:\n    ip: 127.0.0.1\n    port: 9000\n    protocol: http\n    isolated_pool: {}\n",
+                isolated
+            ))
+            .unwrap()
+        };
+        let isolated = config(true);
+        let host = &isolated.hosts["pool.test"];
+        let shared = create_http_client(&isolated);
+        client_for("pool.test", host, &isolated, &shared);
+        assert!(ISOLATED.lock().unwrap().contains_key("pool.test"));
+
+        prune_isolated_clients(&isolated);
+        assert!(ISOLATED.lock().unwrap().contains_key("pool.test"));
+        prune_isolated_clients(&config(false));
+        assert!(!ISOLATED.lock().unwrap().contains_key("pool.test"));
+    }
+
+    #[tokio::test]
+    async fn deadline_covers_every_attempt() {
+        // Accepts connections and never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        let client = create_http_client(&Config::default());
+        let policy = RetryPolicy {
+            retries: 5,
+            attempt_timeout: Some(Duration::from_millis(150)),
+            deadline: Some(Duration::from_millis(200)),
+            ..policy(RetryMode::Idempotent)
+        };
+        let started = Instant::now();
+        let result = send_upstream(&client, request("GET", &url, Body::empty()), &policy).await;
+        assert!(matches!(result, Err(UpstreamError::Timeout)));
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn connections_leave_from_the_source_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let peer = tokio::spawn(async move {
+            let (mut stream, peer) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+            peer.ip()
+        });
+        let config: Config =
+            serde_yaml::from_str("upstream_source_address: 127.0.0.2\nhosts: {}\n").unwrap();
+        let client = create_http_client(&config);
+        let res = client.pooled.get(url.parse().unwrap()).await.unwrap();
+        assert_eq!(res.status(), 204);
+        assert_eq!(peer.await.unwrap(), "127.0.0.2".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn invalid_responses_fail_or_retry_as_configured() {
+        // Answers every connection with an ssh banner.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = seen.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").await;
+            }
+        });
+        let client = create_http_client(&Config::default());
+        for (retry_invalid, attempts) in [(false, 1), (true, 3)] {
+            seen.store(0, Ordering::SeqCst);
+            let policy = RetryPolicy {
+                retries: 2,
+                retry_invalid,
+                ..policy(RetryMode::Idempotent)
+            };
+            let result = send_upstream(&client, request("GET", &url, Body::empty()), &policy).await;
+            let e = result.unwrap_err();
+            assert!(e.is_invalid_response(), "{}", e);
+            assert_eq!(seen.load(Ordering::SeqCst), attempts);
+            let status = crate::error::ProxyError::from(e).status();
+            assert_eq!(status, hyper::StatusCode::BAD_GATEWAY);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_response_heads_are_their_own_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nx-big: {}\r\ncontent-length: 0\r\n\r\n",
+                    "a".repeat(20_000)
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+            }
+        });
+        let config: Config =
+            serde_yaml::from_str("max_response_header_bytes: 8192\nhosts: {}\n").unwrap();
+        let client = create_http_client(&config);
+        let oversized = || METRICS.upstream_oversized_headers.load(Ordering::Relaxed);
+        let before = oversized();
+        let policy = RetryPolicy {
+            retries: 2,
+            ..policy(RetryMode::Idempotent)
+        };
+        let result = send_upstream(&client, request("GET", &url, Body::empty()), &policy).await;
+        let e = crate::error::ProxyError::from(result.unwrap_err());
+        assert_eq!(e.status(), hyper::StatusCode::BAD_GATEWAY);
+        assert_eq!(e.to_string(), "Upstream response headers are too large");
+        assert_eq!(oversized(), before + 1);
+    }
+
+    #[tokio::test]
+    async fn idle_pooled_connections_expire() {
+        // Keeps every connection alive and counts them.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let opened = Arc::new(AtomicUsize::new(0));
+        let counter = opened.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    while let Ok(1..) = stream.read(&mut buf).await {
+                        let res = b"HTTP/1.1 204 No Content\r\n\r\n";
+                        if stream.write_all(res).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        let config: Config =
+            serde_yaml::from_str("upstream_idle_timeout_secs: 1\nhosts: {}\n").unwrap();
+        let client = create_http_client(&config);
+        let get = || client.pooled.get(url.parse().unwrap());
+        get().await.unwrap();
+        get().await.unwrap();
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        get().await.unwrap();
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,181 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use futures_util::future::join_all;
+use hyper::{header::HOST, Body, Request};
+
+use crate::{
+    config::{Config, Host, Target, Warmup},
+    log::{log_error, log_info},
+    reload::{snapshot, SharedConfig},
+    upstream::{client_for, HttpClient},
+};
+
+/// GETs `warmup.path` from `target` through the host's own client, so the
+/// connection it opens stays in the pool for the first real request.
+async fn warm_up(
+    client: &HttpClient,
+    target: &Target,
+    host_header: &str,
+    warmup: &Warmup,
+) -> Result<u16, String> {
+    let uri = format!(
+        "{}://{}{}",
+        target.protocol,
+        target.authority(),
+        warmup.path
+    );
+    let req = Request::get(uri)
+        .header(HOST, host_header)
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+    let exchange = async {
+        let res = client
+            .pooled
+            .request(req)
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = res.status();
+        // Read to the end, an unfinished body keeps the connection out of
+        // the pool.
+        hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok::<_, String>(status.as_u16())
+    };
+    let timeout = Duration::from_millis(warmup.timeout_ms.unwrap_or(10000));
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| "timed out".to_string())?
+}
+
+async fn warm_up_host(domain: String, host: Host, config: Arc<Config>, shared_client: HttpClient) {
+    let warmup = match &host.warmup {
+        Some(warmup) => warmup,
+        None => return,
+    };
+    let client = client_for(&host.state_key(&domain), &host, &config, &shared_client);
+    let requests = host.targets().into_iter().map(|target| {
+        let host_header = host
+            .host_header_for(&target)
+            .unwrap_or_else(|| domain.clone());
+        let client = &client;
+        async move {
+            let result = warm_up(client, &target, &host_header, warmup).await;
+            (target.authority(), result)
+        }
+    });
+    for (upstream, result) in join_all(requests).await {
+        match result {
+            Ok(status) => log_info(&format!(
+                "warmup of {} for host {} answered {}",
+                upstream, domain, status
+            )),
+            Err(e) => log_error(&format!(
+                "warmup of {} for host {} failed: {}",
+                upstream, domain, e
+            )),
+        }
+    }
+}
+
+/// Warms up each host with a `warmup` in the background, all of them at
+/// startup and later any a reload adds or gives a different `warmup`.
+/// Failures are only logged, the host serves requests either way.
+pub fn spawn_warmup_task(shared: SharedConfig, client: HttpClient) {
+    tokio::spawn(async move {
+        let mut warmed: HashMap<String, Warmup> = HashMap::new();
+        let mut last: Option<Arc<Config>> = None;
+        loop {
+            let config = snapshot(&shared);
+            if last
+                .as_ref()
+                .map(|last| !Arc::ptr_eq(last, &config))
+                .unwrap_or(true)
+            {
+                warmed.retain(|domain, _| config.hosts.contains_key(domain));
+                for (domain, host) in &config.hosts {
+                    let warmup = match &host.warmup {
+                        Some(warmup) => warmup,
+                        None => {
+                            warmed.remove(domain);
+                            continue;
+                        }
+                    };
+                    if warmed.get(domain) == Some(warmup) {
+                        continue;
+                    }
+                    warmed.insert(domain.clone(), warmup.clone());
+                    tokio::spawn(warm_up_host(
+                        domain.clone(),
+                        host.clone(),
+                        config.clone(),
+                        client.clone(),
+                    ));
+                }
+                last = Some(config);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::{reload::new_shared_config, upstream::create_http_client};
+
+    /// An upstream reporting the request line of everything it gets.
+    async fn upstream() -> (u16, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                        let head = String::from_utf8_lossy(&buf[..n]).into_owned();
+                        let _ = tx.send(head.lines().next().unwrap_or_default().to_string());
+                        let res = b"HTTP/1.1 204 No Content\r\n\r\n";
+                        if stream.write_all(res).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (port, rx)
+    }
+
+    fn host(domain: &str, port: u16, path: &str) -> String {
+        format!(
+            "  {}:\n    ip: 127.0.0.1\n    port: {}\n    protocol: http\n    warmup:\n      path: {}\n",
+            domain, port, path
+        )
+    }
+
+    #[tokio::test]
+    async fn hosts_are_warmed_up_at_startup_and_when_added() {
+        let (port, mut seen) = upstream().await;
+        let yaml = format!("hosts:\n{}", host("warm.test", port, "/warm"));
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        let client = create_http_client(&config);
+        let shared = new_shared_config(config);
+        spawn_warmup_task(shared.clone(), client);
+        let wait = Duration::from_secs(3);
+        let first = tokio::time::timeout(wait, seen.recv()).await.unwrap();
+        assert_eq!(first.unwrap(), "GET /warm HTTP/1.1");
+
+        let yaml = format!("{}{}", yaml, host("added.test", port, "/added"));
+        *shared.write().unwrap() = Arc::new(serde_yaml::from_str(&yaml).unwrap());
+        let added = tokio::time::timeout(wait, seen.recv()).await.unwrap();
+        assert_eq!(added.unwrap(), "GET /added HTTP/1.1");
+    }
+}